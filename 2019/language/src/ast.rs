@@ -1,19 +1,24 @@
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
 use std::cmp::{Eq, PartialEq};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
 /**
- * A reference back to the original source that produces this AST node.
+ * A reference back to the original source that produces this AST node. The
+ * file is the id the VM gave the source file when it was loaded.
  */
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct SourceRef {
+  pub file: usize,
   pub left: u64,
   pub right: u64,
 }
 
 impl SourceRef {
-  pub fn new(l: usize, r: usize) -> SourceRef {
+  pub fn new(file: usize, l: usize, r: usize) -> SourceRef {
     SourceRef {
+      file,
       left: l as u64,
       right: r as u64,
     }
@@ -75,6 +80,7 @@ impl Hash for Identifier {
  * with the exception that the input parameters are dynamically scoped. In other
  * words:
  * > fn (arg) { foo + arg + 42 }
+ *
  * produces a block with the free variables one and two. These free variables
  * do not need to be lexicographically closed over though.
  *
@@ -102,28 +108,128 @@ impl Block {
  */
 #[derive(Clone, Debug)]
 pub enum Statement {
-  // A mutable let statement.
-  // Ex: let foo = 42;
-  LetStmt(Identifier, Expression),
+  // A mutable let statement, with an optional type annotation.
+  // Ex: let foo = 42; let bar: str = "baz";
+  LetStmt(Identifier, Option<TypeAnnotation>, Expression),
 
-  // An assignment to an *already bound* variable.
-  // Ex: foo = 24;
+  // An assignment to an *already bound* variable. Compound assignment is sugar
+  // for an assignment of the binary expression.
+  // Ex: foo = 24; foo += 1;
   AssignmentStmt(Identifier, Expression),
 
+  // An assignment to an element of a list bound to a variable, with the
+  // operator of a compound assignment (if any). The indices are evaluated only
+  // once, even for compound assignment.
+  // Ex: xs[i] = 24; grid[y][x] += 1;
+  IndexAssignmentStmt(Identifier, Vec<Expression>, Option<BinOp>, Expression, SourceRef),
+
   // An unused expression evaluation. This is for transitive effects of the
   // expression and does not directly mutate the current scope.
   // Ex: { 42 + 24 }; returns_something_that_we_are_ignoring();
   UnusedExprEvalStmt(Expression),
 
   // A function declaration statement. This is a special case, it is the only
-  // statement that doesn't need to in a ";".
-  // Ex: fn name (arg1, arg2) { }  fn name { }
-  FunctionDeclarationStmt(Identifier, Vec<Identifier>, Box<Block>),
+  // statement that doesn't need to in a ";". Parameters and the return type
+  // can optionally be annotated.
+  // Ex: fn name (arg1, arg2) { }  fn name { }  fn add(a: int, b: int) -> int { }
+  FunctionDeclarationStmt(Identifier, Vec<Param>, Option<TypeAnnotation>, Box<Block>),
+
+  // An enum declaration. Like functions, it doesn't need to end in a ";".
+  // Ex: enum Shape { Circle(radius), Rect(width, height), Empty }
+  EnumDeclarationStmt(Identifier, Vec<EnumVariant>),
+
+  // Imports another file as a module, bound to the alias. The path is
+  // relative to the importing file.
+  // Ex: import "util.at" as util;
+  ImportStmt(String, Identifier, SourceRef),
+}
+
+impl Statement {
+  /**
+   * Builds an assignment (compound when op is set) to the target expression.
+   * Returns None if the target is not assignable. Targets are either a
+   * variable, or a variable indexed one or more times.
+   */
+  pub fn assignment(
+    target: Expression,
+    op: Option<BinOp>,
+    value: Expression,
+    source_ref: SourceRef,
+  ) -> Option<Statement> {
+    let mut indices = vec![];
+    let mut target = target;
+    loop {
+      match target {
+        Expression::IdentifierDerefExpr(identifier) if indices.is_empty() => {
+          let value = match op {
+            Some(op) => Expression::BinExpr(
+              Box::new(Expression::IdentifierDerefExpr(identifier.clone())),
+              op,
+              Box::new(value),
+              source_ref,
+            ),
+            None => value,
+          };
+          return Some(Statement::AssignmentStmt(identifier, value));
+        }
+        Expression::IdentifierDerefExpr(identifier) => {
+          indices.reverse();
+          return Some(Statement::IndexAssignmentStmt(
+            identifier, indices, op, value, source_ref,
+          ));
+        }
+        Expression::IndexExpr(list, index, _) => {
+          indices.push(*index);
+          target = *list;
+        }
+        _ => return None,
+      }
+    }
+  }
+}
+
+/**
+ * A function parameter, with an optional type annotation.
+ */
+#[derive(Clone, Debug)]
+pub struct Param {
+  pub name: Identifier,
+  pub annotation: Option<TypeAnnotation>,
+}
+
+/**
+ * A type written in the source. Annotations are only used by the type checker,
+ * the VM ignores them.
+ */
+#[derive(Clone, Debug)]
+pub enum TypeAnnotation {
+  // A builtin type (int, float, str, bool, unit or any) or a declared enum.
+  // Ex: int, Shape
+  Named(Identifier),
+
+  // A list with elements of the type.
+  // Ex: [str]
+  List(Box<TypeAnnotation>),
+
+  // A function from the parameter types to the return type.
+  // Ex: fn(int, int) -> bool
+  Function(Vec<TypeAnnotation>, Box<TypeAnnotation>),
+}
+
+/**
+ * A single variant of an enum declaration, with the names of its (positional)
+ * payload fields. Unit variants have no fields.
+ */
+#[derive(Clone, Debug)]
+pub struct EnumVariant {
+  pub name: Identifier,
+  pub fields: Vec<Identifier>,
 }
 
 /**
  * An expression is always ultimately owned by a Block, but can be nested down
- * in a long expression tree.
+ * in a long expression tree. Expressions that can fail at runtime carry the
+ * SourceRef they were parsed from as their last field.
  */
 #[derive(Clone, Debug)]
 pub enum Expression {
@@ -133,7 +239,11 @@ pub enum Expression {
 
   // A recursive binary operation.
   // Ex: 1 + 2, true && false, 42 > 24
-  BinExpr(Box<Expression>, BinOp, Box<Expression>),
+  BinExpr(Box<Expression>, BinOp, Box<Expression>, SourceRef),
+
+  // A prefix operation.
+  // Ex: -foo, !done
+  UnaryExpr(UnOp, Box<Expression>, SourceRef),
 
   // A child block used as an expression. Note that is directly owned as this
   // forms part os a tree-structure.
@@ -144,32 +254,129 @@ pub enum Expression {
   // Ex: foo + 42
   IdentifierDerefExpr(Identifier),
 
-  // The results of evaluating a Block with dynamically scoped params.
-  // Ex: returns_42(arg1, arg2)
-  FunctionInvokeExpr(Identifier, Vec<Expression>),
+  // The results of evaluating a Block with dynamically scoped params. The
+  // callee can be any expression that evaluates to a function.
+  // Ex: returns_42(arg1, arg2), outer()()
+  FunctionInvokeExpr(Box<Expression>, Vec<Expression>, SourceRef),
+
+  // A list literal.
+  // Ex: [1, 2, 3]
+  ListExpr(Vec<Expression>),
+
+  // Indexes into a list.
+  // Ex: foo[0]
+  IndexExpr(Box<Expression>, Box<Expression>, SourceRef),
+
+  // An anonymous function, closing over the scope it's evaluated in.
+  // Ex: let add = fn (a, b) { a + b }; fn (x: float) -> str { to_string(x) }
+  FunctionExpr(Vec<Param>, Option<TypeAnnotation>, Box<Block>),
 
   // An if+else (else is require) expression.
   // Es: let foo = if bar { 1 } else { 2 };
-  IfElseExpr(Box<Expression>, Box<Block>, Option<Box<Block>>),
+  IfElseExpr(Box<Expression>, Box<Block>, Option<Box<Block>>, SourceRef),
+
+  // A name inside of an enum or an imported module. Unit enum variants
+  // evaluate to the variant itself, variants with a payload evaluate to a
+  // constructor function.
+  // Ex: Shape::Empty, Shape::Circle(42), util::parse_grid(input)
+  PathExpr(Identifier, Identifier),
+
+  // Evaluates the first arm who's pattern matches the subject.
+  // Ex: match shape { Shape::Circle(r) => r * r, _ => 0 }
+  MatchExpr(Box<Expression>, Vec<MatchArm>, SourceRef),
 }
 
 impl Expression {
-  fn get_free_variables(&self) -> Vec<&Identifier> {
+  /**
+   * The location of the expression, for those that record one.
+   */
+  pub fn source_ref(&self) -> Option<&SourceRef> {
     match self {
-      // LetStmt(_, ref expression) =>
-      _ => vec![],
+      Expression::LiteralExpr(_) | Expression::ListExpr(_) => None,
+      Expression::BinExpr(_, _, _, ref source_ref)
+      | Expression::UnaryExpr(_, _, ref source_ref)
+      | Expression::FunctionInvokeExpr(_, _, ref source_ref)
+      | Expression::IndexExpr(_, _, ref source_ref)
+      | Expression::IfElseExpr(_, _, _, ref source_ref)
+      | Expression::MatchExpr(_, _, ref source_ref) => Some(source_ref),
+      Expression::BlockExpr(ref block) | Expression::FunctionExpr(_, _, ref block) => {
+        Some(&block.source_ref)
+      }
+      Expression::IdentifierDerefExpr(ref identifier) | Expression::PathExpr(ref identifier, _) => {
+        Some(&identifier.source_ref)
+      }
     }
   }
 }
 
+/**
+ * A single arm of a match expression. Bindings made by the pattern are scoped
+ * to the arm's expression.
+ */
+#[derive(Clone, Debug)]
+pub struct MatchArm {
+  pub pattern: Pattern,
+  pub expression: Expression,
+}
+
+#[derive(Clone, Debug)]
+pub enum Pattern {
+  // Matches anything without binding it.
+  // Ex: _
+  Wildcard,
+
+  // Matches a value equal to the literal.
+  // Ex: 42, "hello", true
+  Literal(LiteralValue),
+
+  // Matches anything, binding it to the ident.
+  // Ex: foo
+  Binding(Identifier),
+
+  // Matches an enum variant, recursively matching its payload.
+  // Ex: Shape::Rect(w, _), Shape::Empty
+  EnumVariant(Identifier, Identifier, Vec<Pattern>),
+}
+
+impl Pattern {
+  /**
+   * True if the pattern matches any value at all.
+   */
+  pub fn is_irrefutable(&self) -> bool {
+    matches!(self, Pattern::Wildcard | Pattern::Binding(_))
+  }
+}
+
 #[derive(Clone, Debug)]
 pub enum LiteralValue {
   Unit,
   Int64(i64),
+  // Only for ints too large for an Int64.
+  BigInt(BigInt),
+  Float64(f64),
   Str(String),
   Bool(bool),
 }
 
+impl LiteralValue {
+  /**
+   * The literal for an int, which is an Int64 whenever it fits.
+   */
+  pub fn int(i: BigInt) -> LiteralValue {
+    match i.to_i64() {
+      Some(i) => LiteralValue::Int64(i),
+      None => LiteralValue::BigInt(i),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub enum UnOp {
+  Neg,
+  Not,
+  BitNot,
+}
+
 #[derive(Clone, Debug)]
 pub enum BinOp {
   Ge,
@@ -187,4 +394,13 @@ pub enum BinOp {
   Slash,
   Star,
   Mod,
+
+  BitAnd,
+  BitOr,
+  BitXor,
+  Shl,
+  Shr,
+
+  // Passes the left value as the first argument of the call on the right.
+  Pipe,
 }
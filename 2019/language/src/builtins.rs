@@ -0,0 +1,398 @@
+use super::error::RuntimeError;
+use super::numeric::{normalize, to_big_int};
+use super::scope::*;
+use super::vm::VM;
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::cmp::Ordering;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::rc::Rc;
+
+/**
+ * Registers the functions that reach outside of the VM. Each checks the VM's
+ * configuration for the capability it needs first.
+ */
+pub fn add_system_builtins(vm: &mut VM) {
+  // read_file_to_str(path) -> str
+  vm.add_builtin_function("read_file_to_str", |vm, args| {
+    expect_arg_count("read_file_to_str", &args, 1)?;
+    let path = expect_str("read_file_to_str", &args[0])?;
+    let path = vm.config().check_read(Path::new(path))?;
+    fs::read_to_string(&path)
+      .map(Value::Str)
+      .map_err(|e| RuntimeError::new(format!("Cannot read {}: {}", path.display(), e)))
+  });
+  // write_file(path, str) -> (), replacing the file if it exists.
+  vm.add_builtin_function("write_file", |vm, args| {
+    let (path, contents) = two_strings("write_file", args)?;
+    let path = vm.config().check_write(Path::new(&path))?;
+    fs::write(&path, contents)
+      .map(|_| Value::Unit)
+      .map_err(|e| RuntimeError::new(format!("Cannot write {}: {}", path.display(), e)))
+  });
+  // get_env(name) -> str, or () when it isn't set.
+  vm.add_builtin_function("get_env", |vm, args| {
+    expect_arg_count("get_env", &args, 1)?;
+    let name = expect_str("get_env", &args[0])?;
+    vm.config().check_env(name)?;
+    Ok(env::var(name).map(Value::Str).unwrap_or(Value::Unit))
+  });
+  // run_process(program, list of str args) -> str of what it wrote to stdout.
+  vm.add_builtin_function("run_process", |vm, args| {
+    expect_arg_count("run_process", &args, 2)?;
+    let mut args = args.into_iter();
+    let program = args.next().unwrap();
+    let program = expect_str("run_process", &program)?;
+    vm.config().check_process(program)?;
+    let arguments = expect_list("run_process", args.next().unwrap())?;
+    let arguments = arguments
+      .iter()
+      .map(|arg| expect_str("run_process", arg))
+      .collect::<Result<Vec<_>, _>>()?;
+    let output = Command::new(program)
+      .args(arguments)
+      .output()
+      .map_err(|e| RuntimeError::new(format!("Cannot run {}: {}", program, e)))?;
+    if !output.status.success() {
+      return Err(RuntimeError::new(format!(
+        "{} failed with {}: {}",
+        program,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+    Ok(Value::Str(String::from_utf8_lossy(&output.stdout).into_owned()))
+  });
+}
+
+/**
+ * Registers the higher-order list functions. These call back into user
+ * closures through the VM they are invoked from.
+ */
+pub fn add_functional_builtins(vm: &mut VM) {
+  // map(list, fn (item) { ... }) -> list
+  vm.add_builtin_function("map", |vm, args| {
+    let (items, function) = list_and_function("map", args)?;
+    Ok(Value::List(Rc::new(
+      items
+        .iter()
+        .map(|item| vm.call_function(function.clone(), vec![item.clone()]))
+        .collect::<Result<_, _>>()?,
+    )))
+  });
+  // filter(list, fn (item) { bool }) -> list
+  vm.add_builtin_function("filter", |vm, args| {
+    let (items, function) = list_and_function("filter", args)?;
+    let mut kept = vec![];
+    for item in items.iter() {
+      if call_predicate("filter", vm, &function, item)? {
+        kept.push(item.clone());
+      }
+    }
+    Ok(Value::List(Rc::new(kept)))
+  });
+  // fold(list, initial, fn (accumulator, item) { ... }) -> accumulator
+  vm.add_builtin_function("fold", |vm, args| {
+    expect_arg_count("fold", &args, 3)?;
+    let mut args = args.into_iter();
+    let items = expect_list("fold", args.next().unwrap())?;
+    let initial = args.next().unwrap();
+    let function = expect_function("fold", args.next().unwrap())?;
+    items.iter().try_fold(initial, |accumulator, item| {
+      vm.call_function(function.clone(), vec![accumulator, item.clone()])
+    })
+  });
+  // sort_by(list, fn (item) { key }) -> list, stable and ascending by key.
+  vm.add_builtin_function("sort_by", |vm, args| {
+    let (items, function) = list_and_function("sort_by", args)?;
+    let keyed: Vec<(Value, Value)> = items
+      .iter()
+      .map(|item| Ok((vm.call_function(function.clone(), vec![item.clone()])?, item.clone())))
+      .collect::<Result<_, RuntimeError>>()?;
+    // The sort can't fail, so the first keys that can't be compared are
+    // remembered and reported once it's done.
+    let mut error = None;
+    let mut keyed = keyed;
+    keyed.sort_by(|(l, _), (r, _)| {
+      compare_keys(l, r).unwrap_or_else(|e| {
+        error.get_or_insert(e);
+        Ordering::Equal
+      })
+    });
+    if let Some(error) = error {
+      return Err(error);
+    }
+    Ok(Value::List(Rc::new(keyed.into_iter().map(|(_, item)| item).collect())))
+  });
+  // any(list, fn (item) { bool }) -> bool, stopping at the first true.
+  vm.add_builtin_function("any", |vm, args| {
+    let (items, function) = list_and_function("any", args)?;
+    for item in items.iter() {
+      if call_predicate("any", vm, &function, item)? {
+        return Ok(Value::Bool(true));
+      }
+    }
+    Ok(Value::Bool(false))
+  });
+  // all(list, fn (item) { bool }) -> bool, stopping at the first false.
+  vm.add_builtin_function("all", |vm, args| {
+    let (items, function) = list_and_function("all", args)?;
+    for item in items.iter() {
+      if !call_predicate("all", vm, &function, item)? {
+        return Ok(Value::Bool(false));
+      }
+    }
+    Ok(Value::Bool(true))
+  });
+  // window(list, size) -> list of every contiguous sub-list of length size.
+  vm.add_builtin_function("window", |_, args| {
+    expect_arg_count("window", &args, 2)?;
+    let mut args = args.into_iter();
+    let items = expect_list("window", args.next().unwrap())?;
+    match args.next().unwrap() {
+      Value::Int64(size) if size > 0 => Ok(Value::List(Rc::new(
+        items
+          .windows(size as usize)
+          .map(|window| Value::List(Rc::new(window.to_vec())))
+          .collect(),
+      ))),
+      v => Err(RuntimeError::new(format!(
+        "window expected a positive size, found {:?}",
+        v
+      ))),
+    }
+  });
+}
+
+/**
+ * Registers the string functions used for text processing.
+ */
+pub fn add_string_builtins(vm: &mut VM) {
+  // split(str, separator) -> list of str
+  vm.add_builtin_function("split", |_, args| {
+    let (s, separator) = two_strings("split", args)?;
+    Ok(str_list(s.split(separator.as_str())))
+  });
+  // lines(str) -> list of str
+  vm.add_builtin_function("lines", |_, args| {
+    expect_arg_count("lines", &args, 1)?;
+    Ok(str_list(expect_str("lines", &args[0])?.lines()))
+  });
+  // trim(str) -> str without leading or trailing whitespace.
+  vm.add_builtin_function("trim", |_, args| {
+    expect_arg_count("trim", &args, 1)?;
+    Ok(Value::Str(expect_str("trim", &args[0])?.trim().to_owned()))
+  });
+  // chars(str) -> list of single character strs.
+  vm.add_builtin_function("chars", |_, args| {
+    expect_arg_count("chars", &args, 1)?;
+    Ok(Value::List(Rc::new(
+      expect_str("chars", &args[0])?
+        .chars()
+        .map(|c| Value::Str(c.to_string()))
+        .collect(),
+    )))
+  });
+  // len(str | list) -> int, counting characters for strs.
+  vm.add_builtin_function("len", |_, args| {
+    expect_arg_count("len", &args, 1)?;
+    match args[0] {
+      Value::Str(ref s) => Ok(Value::Int64(s.chars().count() as i64)),
+      Value::List(ref items) => Ok(Value::Int64(items.len() as i64)),
+      ref v => Err(RuntimeError::new(format!(
+        "len expected a str or list, found {:?}",
+        v
+      ))),
+    }
+  });
+  // contains(str, sub_str) -> bool
+  vm.add_builtin_function("contains", |_, args| {
+    let (s, sub) = two_strings("contains", args)?;
+    Ok(Value::Bool(s.contains(sub.as_str())))
+  });
+  // starts_with(str, prefix) -> bool
+  vm.add_builtin_function("starts_with", |_, args| {
+    let (s, prefix) = two_strings("starts_with", args)?;
+    Ok(Value::Bool(s.starts_with(prefix.as_str())))
+  });
+  // replace(str, from, to) -> str with every occurrence of from replaced.
+  vm.add_builtin_function("replace", |_, args| {
+    expect_arg_count("replace", &args, 3)?;
+    let s = expect_str("replace", &args[0])?;
+    let from = expect_str("replace", &args[1])?;
+    let to = expect_str("replace", &args[2])?;
+    Ok(Value::Str(s.replace(from, to)))
+  });
+  // parse_int(str) -> int, ignoring surrounding whitespace. Ints too large for
+  // an i64 parse as a BigInt.
+  vm.add_builtin_function("parse_int", |_, args| {
+    expect_arg_count("parse_int", &args, 1)?;
+    let s = expect_str("parse_int", &args[0])?;
+    match s.trim().parse::<BigInt>() {
+      Ok(i) => Ok(normalize(i)),
+      Err(_) => Err(RuntimeError::new(format!(
+        "parse_int failed to parse \"{}\" as an int",
+        s
+      ))),
+    }
+  });
+  // to_string(value) -> str, formatted as println would.
+  vm.add_builtin_function("to_string", |_, args| {
+    expect_arg_count("to_string", &args, 1)?;
+    Ok(Value::Str(args[0].to_string()))
+  });
+}
+
+/**
+ * Registers the explicit conversions between ints and floats.
+ */
+pub fn add_numeric_builtins(vm: &mut VM) {
+  // to_float(int | float) -> float
+  vm.add_builtin_function("to_float", |_, args| {
+    expect_arg_count("to_float", &args, 1)?;
+    match args[0] {
+      Value::Int64(i) => Ok(Value::Float64(i as f64)),
+      Value::BigInt(ref i) => Ok(Value::Float64(i.to_f64().unwrap_or(f64::NAN))),
+      Value::Float64(f) => Ok(Value::Float64(f)),
+      ref v => Err(RuntimeError::new(format!(
+        "to_float expected a number, found {:?}",
+        v
+      ))),
+    }
+  });
+  // to_int(int | float) -> int, truncating towards zero.
+  vm.add_builtin_function("to_int", |_, args| {
+    expect_arg_count("to_int", &args, 1)?;
+    match args[0] {
+      Value::Int64(_) | Value::BigInt(_) => Ok(args[0].clone()),
+      Value::Float64(f) if f.is_finite() => Ok(normalize(BigInt::from_f64(f.trunc()).unwrap())),
+      ref v => Err(RuntimeError::new(format!(
+        "to_int cannot represent {:?} as an int",
+        v
+      ))),
+    }
+  });
+}
+
+pub fn expect_arg_count(name: &str, args: &[Value], count: usize) -> Result<(), RuntimeError> {
+  if args.len() != count {
+    return Err(RuntimeError::new(format!(
+      "{} takes {} arguments but {} were given",
+      name,
+      count,
+      args.len()
+    )));
+  }
+  Ok(())
+}
+
+pub fn expect_list(name: &str, value: Value) -> Result<Rc<Vec<Value>>, RuntimeError> {
+  match value {
+    Value::List(items) => Ok(items),
+    v => Err(RuntimeError::new(format!(
+      "{} expected a list, found {:?}",
+      name, v
+    ))),
+  }
+}
+
+pub fn expect_function(name: &str, value: Value) -> Result<Value, RuntimeError> {
+  match value {
+    Value::Function(..) | Value::BuiltInFunction(_) | Value::EnumConstructor(..) => Ok(value),
+    v => Err(RuntimeError::new(format!(
+      "{} expected a function, found {:?}",
+      name, v
+    ))),
+  }
+}
+
+pub fn expect_str<'a>(name: &str, value: &'a Value) -> Result<&'a str, RuntimeError> {
+  match value {
+    Value::Str(ref s) => Ok(s),
+    v => Err(RuntimeError::new(format!(
+      "{} expected a str, found {:?}",
+      name, v
+    ))),
+  }
+}
+
+fn two_strings(name: &str, args: Vec<Value>) -> Result<(String, String), RuntimeError> {
+  expect_arg_count(name, &args, 2)?;
+  Ok((
+    expect_str(name, &args[0])?.to_owned(),
+    expect_str(name, &args[1])?.to_owned(),
+  ))
+}
+
+fn str_list<'a>(strs: impl Iterator<Item = &'a str>) -> Value {
+  Value::List(Rc::new(strs.map(|s| Value::Str(s.to_owned())).collect()))
+}
+
+fn list_and_function(
+  name: &str,
+  args: Vec<Value>,
+) -> Result<(Rc<Vec<Value>>, Value), RuntimeError> {
+  expect_arg_count(name, &args, 2)?;
+  let mut args = args.into_iter();
+  let items = expect_list(name, args.next().unwrap())?;
+  Ok((items, expect_function(name, args.next().unwrap())?))
+}
+
+fn call_predicate(
+  name: &str,
+  vm: &VM,
+  function: &Value,
+  item: &Value,
+) -> Result<bool, RuntimeError> {
+  match vm.call_function(function.clone(), vec![item.clone()])? {
+    Value::Bool(b) => Ok(b),
+    v => Err(RuntimeError::new(format!(
+      "{} predicate must return a bool, found {:?}",
+      name, v
+    ))),
+  }
+}
+
+fn compare_keys(l: &Value, r: &Value) -> Result<Ordering, RuntimeError> {
+  Ok(match (l, r) {
+    (Value::Int64(l), Value::Int64(r)) => l.cmp(r),
+    (Value::Int64(_) | Value::BigInt(_), Value::Int64(_) | Value::BigInt(_)) => {
+      to_big_int(l).cmp(&to_big_int(r))
+    }
+    (Value::Float64(l), Value::Float64(r)) => l.partial_cmp(r).unwrap_or(Ordering::Equal),
+    // Ints are promoted when mixed with floats, as they are by `<`.
+    (Value::Float64(_), Value::Int64(_) | Value::BigInt(_))
+    | (Value::Int64(_) | Value::BigInt(_), Value::Float64(_)) => {
+      float_key(l).partial_cmp(&float_key(r)).unwrap_or(Ordering::Equal)
+    }
+    (Value::Str(l), Value::Str(r)) => l.cmp(r),
+    (Value::Bool(l), Value::Bool(r)) => l.cmp(r),
+    (Value::List(l), Value::List(r)) => {
+      for (l, r) in l.iter().zip(r.iter()) {
+        let ordering = compare_keys(l, r)?;
+        if ordering != Ordering::Equal {
+          return Ok(ordering);
+        }
+      }
+      l.len().cmp(&r.len())
+    }
+    _ => {
+      return Err(RuntimeError::new(format!(
+        "Cannot compare sort keys {:?} and {:?}",
+        l, r
+      )))
+    }
+  })
+}
+
+fn float_key(value: &Value) -> f64 {
+  match value {
+    Value::Int64(i) => *i as f64,
+    Value::BigInt(i) => i.to_f64().unwrap_or(f64::NAN),
+    Value::Float64(f) => *f,
+    _ => f64::NAN,
+  }
+}
@@ -0,0 +1,248 @@
+use super::error::RuntimeError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/**
+ * Configures a VM, including the capabilities granted to the scripts it runs.
+ * Builtins that reach outside of the VM (and imports) check these, a violation
+ * is a runtime error. The default grants no capabilities at all, so it is safe
+ * for running untrusted scripts.
+ */
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+  // Binds the prelude's helpers.
+  pub prelude: bool,
+  // Directories that files can be read (or imported) from, recursively.
+  pub read_roots: Vec<PathBuf>,
+  // Directories that files can be written to, recursively.
+  pub write_roots: Vec<PathBuf>,
+  // Whether environment variables can be read.
+  pub env: bool,
+  // Whether other processes can be spawned.
+  pub process: bool,
+  // Limits over the lifetime of the VM, unlimited when None. Each expression
+  // evaluated costs one step of fuel. The allocation limit is a budget for the
+  // total (approximate) bytes of every str and list the program creates, so
+  // what's freed still counts against it.
+  pub max_steps: Option<u64>,
+  pub max_call_depth: Option<usize>,
+  pub max_alloc_bytes: Option<usize>,
+  // Optimizes programs before running them. See optimizer::optimize_program.
+  pub optimize: bool,
+}
+
+impl Default for VmConfig {
+  fn default() -> VmConfig {
+    VmConfig {
+      prelude: true,
+      read_roots: vec![],
+      write_roots: vec![],
+      env: false,
+      process: false,
+      max_steps: None,
+      max_call_depth: None,
+      max_alloc_bytes: None,
+      optimize: false,
+    }
+  }
+}
+
+impl VmConfig {
+  /**
+   * Grants every capability, for scripts that are trusted as much as the host.
+   */
+  pub fn trusted() -> VmConfig {
+    VmConfig {
+      prelude: true,
+      read_roots: vec![PathBuf::from("/")],
+      write_roots: vec![PathBuf::from("/")],
+      env: true,
+      process: true,
+      max_steps: None,
+      max_call_depth: None,
+      max_alloc_bytes: None,
+      optimize: false,
+    }
+  }
+
+  /**
+   * Checks that the file can be read, returning its canonical path.
+   */
+  pub fn check_read(&self, path: &Path) -> Result<PathBuf, RuntimeError> {
+    let canonical_path = fs::canonicalize(path)
+      .map_err(|e| RuntimeError::new(format!("Cannot read {}: {}", path.display(), e)))?;
+    check_roots("read", path, &canonical_path, &self.read_roots)?;
+    Ok(canonical_path)
+  }
+
+  /**
+   * Checks that the file can be written, returning its canonical path. The
+   * file itself doesn't need to exist yet, but its directory does. Writing to
+   * a symlink writes the file it points to, so that file must be in a root.
+   */
+  pub fn check_write(&self, path: &Path) -> Result<PathBuf, RuntimeError> {
+    let cannot_write = |e| RuntimeError::new(format!("Cannot write {}: {}", path.display(), e));
+    let file_name = path
+      .file_name()
+      .ok_or_else(|| cannot_write("not a file".to_owned()))?;
+    let directory = match path.parent() {
+      Some(directory) if !directory.as_os_str().is_empty() => directory,
+      _ => Path::new("."),
+    };
+    let mut canonical_path = fs::canonicalize(directory)
+      .map_err(|e| cannot_write(e.to_string()))?
+      .join(file_name);
+    if fs::symlink_metadata(&canonical_path).is_ok() {
+      // Fails for broken symlinks, which writing would create the target of.
+      canonical_path = fs::canonicalize(&canonical_path).map_err(|e| cannot_write(e.to_string()))?;
+    }
+    check_roots("write", path, &canonical_path, &self.write_roots)?;
+    Ok(canonical_path)
+  }
+
+  pub fn check_env(&self, name: &str) -> Result<(), RuntimeError> {
+    if !self.env {
+      return Err(denied(format!("reading environment variable {}", name)));
+    }
+    Ok(())
+  }
+
+  pub fn check_process(&self, program: &str) -> Result<(), RuntimeError> {
+    if !self.process {
+      return Err(denied(format!("running {}", program)));
+    }
+    Ok(())
+  }
+}
+
+fn check_roots(
+  action: &str,
+  path: &Path,
+  canonical_path: &Path,
+  roots: &[PathBuf],
+) -> Result<(), RuntimeError> {
+  // Roots are canonicalized too, so that symlinks can't be used to escape them.
+  let allowed = roots
+    .iter()
+    .filter_map(|root| fs::canonicalize(root).ok())
+    .any(|root| canonical_path.starts_with(root));
+  if !allowed {
+    return Err(denied(format!("{} {}", action, path.display())));
+  }
+  Ok(())
+}
+
+fn denied(action: String) -> RuntimeError {
+  RuntimeError::new(format!(
+    "Permission denied: {} is not allowed by the VM's configuration",
+    action
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vm::VM;
+
+  /**
+   * A temporary directory for a test, deleted once the test is done with it.
+   */
+  struct SandboxDir(PathBuf);
+
+  impl std::ops::Deref for SandboxDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+      &self.0
+    }
+  }
+
+  impl Drop for SandboxDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn sandbox_dir(test: &str) -> SandboxDir {
+    let directory = std::env::temp_dir().join(format!("atc_{}_{}", test, std::process::id()));
+    fs::create_dir_all(directory.join("inside")).unwrap();
+    fs::write(directory.join("inside/input.txt"), "42").unwrap();
+    fs::write(directory.join("secret.txt"), "hunter2").unwrap();
+    SandboxDir(directory)
+  }
+
+  fn error(vm: &mut VM, source: &str) -> String {
+    vm.exec(source).unwrap_err().message().to_owned()
+  }
+
+  #[test]
+  fn sandbox_grants_nothing_by_default() {
+    let directory = sandbox_dir("sandbox_default");
+    let mut vm = VM::with_config(VmConfig::default());
+    let read = format!("read_file_to_str(\"{}\")", directory.join("secret.txt").display());
+    assert!(error(&mut vm, &read).starts_with("Permission denied: read"));
+    let write = format!("write_file(\"{}\", \"x\")", directory.join("out.txt").display());
+    assert!(error(&mut vm, &write).starts_with("Permission denied: write"));
+    let import = format!("import \"{}\" as secret;", directory.join("secret.txt").display());
+    assert!(error(&mut vm, &import).starts_with("Permission denied: read"));
+    assert_eq!(
+      error(&mut vm, "get_env(\"HOME\")"),
+      "Permission denied: reading environment variable HOME is not allowed by the VM's \
+       configuration"
+    );
+    assert!(error(&mut vm, "run_process(\"true\", [])").starts_with("Permission denied: running"));
+    assert!(!directory.join("out.txt").exists());
+  }
+
+  #[test]
+  fn sandbox_roots_are_enforced() {
+    let directory = sandbox_dir("sandbox_roots");
+    let inside = directory.join("inside");
+    let mut vm = VM::with_config(VmConfig {
+      read_roots: vec![inside.clone()],
+      write_roots: vec![inside.clone()],
+      ..VmConfig::default()
+    });
+    let read = format!("read_file_to_str(\"{}\")", inside.join("input.txt").display());
+    assert_eq!(format!("{:?}", vm.exec(&read).unwrap()), "\"42\"");
+    // Escaping the root through ".." is still caught.
+    let escape = format!("read_file_to_str(\"{}/../secret.txt\")", inside.display());
+    assert!(error(&mut vm, &escape).starts_with("Permission denied: read"));
+    let write = format!("write_file(\"{}\", \"out\")", inside.join("out.txt").display());
+    vm.exec(&write).unwrap();
+    assert_eq!(fs::read_to_string(inside.join("out.txt")).unwrap(), "out");
+    let write = format!("write_file(\"{}\", \"out\")", directory.join("out.txt").display());
+    assert!(error(&mut vm, &write).starts_with("Permission denied: write"));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks_cannot_escape_write_roots() {
+    let directory = sandbox_dir("sandbox_symlinks");
+    let inside = directory.join("inside");
+    std::os::unix::fs::symlink(directory.join("secret.txt"), inside.join("link.txt")).unwrap();
+    std::os::unix::fs::symlink(directory.join("missing.txt"), inside.join("broken.txt")).unwrap();
+    std::os::unix::fs::symlink(inside.join("input.txt"), inside.join("local.txt")).unwrap();
+    let mut vm = VM::with_config(VmConfig {
+      write_roots: vec![inside.clone()],
+      ..VmConfig::default()
+    });
+    let write = format!("write_file(\"{}\", \"pwned\")", inside.join("link.txt").display());
+    assert!(error(&mut vm, &write).starts_with("Permission denied: write"));
+    assert_eq!(fs::read_to_string(directory.join("secret.txt")).unwrap(), "hunter2");
+    let write = format!("write_file(\"{}\", \"pwned\")", inside.join("broken.txt").display());
+    assert!(error(&mut vm, &write).starts_with("Cannot write"));
+    assert!(!directory.join("missing.txt").exists());
+    // Symlinks to files inside the root can be written through.
+    let write = format!("write_file(\"{}\", \"43\")", inside.join("local.txt").display());
+    vm.exec(&write).unwrap();
+    assert_eq!(fs::read_to_string(inside.join("input.txt")).unwrap(), "43");
+  }
+
+  #[test]
+  fn trusted_grants_everything() {
+    let mut vm = VM::new();
+    assert_eq!(format!("{:?}", vm.exec("run_process(\"echo\", [\"hi\"])").unwrap()), "\"hi\n\"");
+    assert_eq!(format!("{:?}", vm.exec("get_env(\"ATC_UNSET_VARIABLE\")").unwrap()), "()");
+  }
+}
@@ -0,0 +1,191 @@
+use super::error::RuntimeError;
+use super::scope::Value;
+use std::rc::Rc;
+
+/**
+ * Converts a Rust value into a VM value. Vecs and tuples both become lists.
+ */
+pub trait IntoValue {
+  fn into_value(self) -> Value;
+}
+
+/**
+ * Converts a VM value back into a Rust value, failing if it has the wrong
+ * type. Tuples are converted from lists of exactly their length.
+ */
+pub trait FromValue: Sized {
+  fn from_value(value: Value) -> Result<Self, RuntimeError>;
+}
+
+fn type_error<T>(expected: &str, value: &Value) -> Result<T, RuntimeError> {
+  Err(RuntimeError::new(format!(
+    "Expected {}, found {:?}",
+    expected, value
+  )))
+}
+
+impl IntoValue for Value {
+  fn into_value(self) -> Value {
+    self
+  }
+}
+
+impl FromValue for Value {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    Ok(value)
+  }
+}
+
+impl IntoValue for () {
+  fn into_value(self) -> Value {
+    Value::Unit
+  }
+}
+
+impl FromValue for () {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    match value {
+      Value::Unit => Ok(()),
+      v => type_error("unit", &v),
+    }
+  }
+}
+
+impl IntoValue for i64 {
+  fn into_value(self) -> Value {
+    Value::Int64(self)
+  }
+}
+
+impl FromValue for i64 {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    match value {
+      Value::Int64(i) => Ok(i),
+      v => type_error("an int that fits in an i64", &v),
+    }
+  }
+}
+
+impl IntoValue for f64 {
+  fn into_value(self) -> Value {
+    Value::Float64(self)
+  }
+}
+
+impl FromValue for f64 {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    match value {
+      Value::Float64(f) => Ok(f),
+      v => type_error("a float", &v),
+    }
+  }
+}
+
+impl IntoValue for bool {
+  fn into_value(self) -> Value {
+    Value::Bool(self)
+  }
+}
+
+impl FromValue for bool {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    match value {
+      Value::Bool(b) => Ok(b),
+      v => type_error("a bool", &v),
+    }
+  }
+}
+
+impl IntoValue for String {
+  fn into_value(self) -> Value {
+    Value::Str(self)
+  }
+}
+
+impl IntoValue for &str {
+  fn into_value(self) -> Value {
+    Value::Str(self.to_owned())
+  }
+}
+
+impl FromValue for String {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    match value {
+      Value::Str(s) => Ok(s),
+      v => type_error("a str", &v),
+    }
+  }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+  fn into_value(self) -> Value {
+    Value::List(Rc::new(self.into_iter().map(IntoValue::into_value).collect()))
+  }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+  fn from_value(value: Value) -> Result<Self, RuntimeError> {
+    match value {
+      Value::List(items) => items.iter().cloned().map(T::from_value).collect(),
+      v => type_error("a list", &v),
+    }
+  }
+}
+
+macro_rules! tuple_conversions {
+  ($length:expr; $($name:ident),+) => {
+    impl<$($name: IntoValue),+> IntoValue for ($($name,)+) {
+      #[allow(non_snake_case)]
+      fn into_value(self) -> Value {
+        let ($($name,)+) = self;
+        Value::List(Rc::new(vec![$($name.into_value()),+]))
+      }
+    }
+
+    impl<$($name: FromValue),+> FromValue for ($($name,)+) {
+      fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        match value {
+          Value::List(ref items) if items.len() == $length => {
+            let mut items = items.iter().cloned();
+            Ok(($($name::from_value(items.next().unwrap())?,)+))
+          }
+          v => type_error(concat!("a list of length ", $length), &v),
+        }
+      }
+    }
+  };
+}
+
+tuple_conversions!(1; A);
+tuple_conversions!(2; A, B);
+tuple_conversions!(3; A, B, C);
+tuple_conversions!(4; A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip<T: IntoValue + FromValue>(value: T) -> T {
+    T::from_value(value.into_value()).unwrap()
+  }
+
+  #[test]
+  fn conversions_round_trip() {
+    assert_eq!(round_trip(-7i64), -7);
+    assert_eq!(round_trip(2.5f64), 2.5);
+    assert!(round_trip(true));
+    assert_eq!(round_trip("abc".to_owned()), "abc");
+    assert_eq!(round_trip(vec![vec![1i64], vec![]]), vec![vec![1], vec![]]);
+    assert_eq!(round_trip((1i64, "a".to_owned(), false)), (1, "a".to_owned(), false));
+    assert_eq!(format!("{:?}", (1i64, vec!["x"]).into_value()), "[i1, [\"x\"]]");
+  }
+
+  #[test]
+  fn conversions_check_types() {
+    let error = i64::from_value(Value::Str("1".to_owned())).unwrap_err();
+    assert_eq!(error.message(), "Expected an int that fits in an i64, found \"1\"");
+    let error = <(i64, i64)>::from_value(vec![1i64].into_value()).unwrap_err();
+    assert_eq!(error.message(), "Expected a list of length 2, found [i1]");
+    assert!(Vec::<bool>::from_value(vec![1i64].into_value()).is_err());
+  }
+}
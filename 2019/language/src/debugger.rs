@@ -0,0 +1,344 @@
+use super::ast::SourceRef;
+use super::error::RuntimeError;
+use super::scope::{Scope, Value};
+use super::vm::VM;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+const HELP: &str = "Commands:
+  s, step          Runs to the next line, stepping into calls
+  n, next          Runs to the next line, stepping over calls
+  c, continue      Runs to the next breakpoint
+  b, break [LINE]  Sets a breakpoint on the line, or lists them
+  d, delete LINE   Deletes the breakpoint on the line
+  q, quit          Stops the program
+An empty line repeats the last command.";
+
+/**
+ * Hooks into the VM at statement boundaries, see VM::set_debugger.
+ */
+pub trait Debugger {
+  /**
+   * Called before each statement runs, and before the return expression of
+   * each block (the body of `fn add(a, b) { a + b }` has no statements). The
+   * scope is the innermost one the statement runs in, and the depth is the
+   * number of function calls being made. Returning an error stops the program
+   * with it.
+   */
+  fn before_statement(
+    &mut self,
+    vm: &VM,
+    source_ref: &SourceRef,
+    scope: &Rc<RefCell<Scope>>,
+    depth: usize,
+  ) -> Result<(), RuntimeError>;
+}
+
+/**
+ * Where a statement runs: the file, its (one based) line, and the call depth.
+ */
+#[derive(Clone, Copy, PartialEq)]
+struct Location {
+  file: usize,
+  line: usize,
+  depth: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+  // Stops at the next statement.
+  Pause,
+  // Stops at the next statement on another line, or in another call.
+  StepIn(Location),
+  // Like StepIn, but runs through the calls made from the line.
+  StepOver(Location),
+  // Stops at breakpoints only.
+  Continue,
+}
+
+struct LoadedFile {
+  path: PathBuf,
+  source: String,
+  line_starts: Vec<usize>,
+}
+
+/**
+ * The debugger of `atc debug`. It stops at line breakpoints (set in the file
+ * being debugged) and after stepping, printing the line along with the locals
+ * of every scope up the scope chain, then reads commands until one resumes the
+ * program.
+ */
+pub struct StepDebugger<R, W> {
+  input: R,
+  output: W,
+  // The file the breakpoints are in.
+  path: PathBuf,
+  breakpoints: BTreeSet<usize>,
+  mode: Mode,
+  // Where the previous statement ran, so that a breakpoint stops when its line
+  // is reached rather than at every statement on it.
+  last: Option<Location>,
+  // Repeated by an empty line.
+  last_command: String,
+  files: HashMap<usize, LoadedFile>,
+}
+
+impl<R: BufRead, W: Write> StepDebugger<R, W> {
+  /**
+   * Debugs the file at the path, reading commands from the input. Without any
+   * breakpoints it stops at the first statement, otherwise at the first
+   * breakpoint.
+   */
+  pub fn new(path: &str, breakpoints: &[usize], input: R, output: W) -> StepDebugger<R, W> {
+    StepDebugger {
+      input,
+      output,
+      path: PathBuf::from(path),
+      breakpoints: breakpoints.iter().copied().collect(),
+      mode: if breakpoints.is_empty() {
+        Mode::Pause
+      } else {
+        Mode::Continue
+      },
+      last: None,
+      last_command: String::new(),
+      files: HashMap::new(),
+    }
+  }
+
+  fn location(&mut self, vm: &VM, source_ref: &SourceRef, depth: usize) -> Location {
+    let file = self.files.entry(source_ref.file).or_insert_with(|| {
+      // SourceRefs only point into files that are loaded.
+      let file = vm.source_file(source_ref.file).unwrap();
+      LoadedFile {
+        path: file.path.clone(),
+        source: file.source.clone(),
+        line_starts: std::iter::once(0)
+          .chain(file.source.match_indices('\n').map(|(offset, _)| offset + 1))
+          .collect(),
+      }
+    });
+    Location {
+      file: source_ref.file,
+      line: file
+        .line_starts
+        .partition_point(|&start| start as u64 <= source_ref.left),
+      depth,
+    }
+  }
+
+  fn at_breakpoint(&self, location: Location) -> bool {
+    self.breakpoints.contains(&location.line)
+      && self.files[&location.file].path == self.path
+      && self.last != Some(location)
+  }
+
+  /**
+   * Prints where the program stopped and the locals of each scope, innermost
+   * first. The builtins' scope (the root of the chain) is left out.
+   */
+  fn print_stop(&mut self, location: Location, scope: &Rc<RefCell<Scope>>) -> io::Result<()> {
+    let file = &self.files[&location.file];
+    let start = file.line_starts[location.line - 1];
+    let text = file.source[start..].lines().next().unwrap_or_default();
+    writeln!(self.output, "{}:{}", file.path.display(), location.line)?;
+    writeln!(self.output, "{:>5} | {}", location.line, text)?;
+    let mut scope = Rc::clone(scope);
+    let mut index = 0;
+    loop {
+      let parent = match (*scope).borrow().parent {
+        Some(ref parent) => Rc::clone(parent),
+        None => break,
+      };
+      let mut locals: Vec<String> = (*scope)
+        .borrow()
+        .locals
+        .iter()
+        .map(|(identifier, value)| format!("{} = {}", identifier.name, describe(value)))
+        .collect();
+      locals.sort();
+      if locals.is_empty() {
+        locals.push("(empty)".to_owned());
+      }
+      writeln!(self.output, "  [{}] {}", index, locals.join(", "))?;
+      scope = parent;
+      index += 1;
+    }
+    Ok(())
+  }
+
+  /**
+   * Reads commands until one resumes the program. Returns false when the
+   * program should stop instead, which closing the input does too.
+   */
+  fn prompt(&mut self, location: Location) -> io::Result<bool> {
+    loop {
+      write!(self.output, "(atc) ")?;
+      self.output.flush()?;
+      let mut line = String::new();
+      if self.input.read_line(&mut line)? == 0 {
+        return Ok(false);
+      }
+      let command = match line.trim() {
+        "" => self.last_command.clone(),
+        command => command.to_owned(),
+      };
+      self.last_command = command.clone();
+      let mut words = command.split_whitespace();
+      match (words.next(), words.next().map(|line| line.parse::<usize>())) {
+        (Some("s" | "step"), None) => self.mode = Mode::StepIn(location),
+        (Some("n" | "next"), None) => self.mode = Mode::StepOver(location),
+        (Some("c" | "continue"), None) => self.mode = Mode::Continue,
+        (Some("q" | "quit"), None) => return Ok(false),
+        (Some("b" | "break"), None) => {
+          let lines: Vec<String> = self.breakpoints.iter().map(|l| l.to_string()).collect();
+          writeln!(self.output, "Breakpoints on lines: {}", lines.join(", "))?;
+          continue;
+        }
+        (Some("b" | "break"), Some(Ok(line))) if line > 0 => {
+          self.breakpoints.insert(line);
+          writeln!(self.output, "Breakpoint on line {}", line)?;
+          continue;
+        }
+        (Some("d" | "delete"), Some(Ok(line))) => {
+          if !self.breakpoints.remove(&line) {
+            writeln!(self.output, "No breakpoint on line {}", line)?;
+          }
+          continue;
+        }
+        _ => {
+          writeln!(self.output, "{}", HELP)?;
+          continue;
+        }
+      }
+      return Ok(true);
+    }
+  }
+}
+
+impl<R: BufRead, W: Write> Debugger for StepDebugger<R, W> {
+  fn before_statement(
+    &mut self,
+    vm: &VM,
+    source_ref: &SourceRef,
+    scope: &Rc<RefCell<Scope>>,
+    depth: usize,
+  ) -> Result<(), RuntimeError> {
+    let location = self.location(vm, source_ref, depth);
+    let same_line = |from: Location| (from.file, from.line) == (location.file, location.line);
+    let stop = match self.mode {
+      Mode::Pause => true,
+      Mode::StepIn(from) => from != location,
+      Mode::StepOver(from) => depth < from.depth || (depth == from.depth && !same_line(from)),
+      Mode::Continue => false,
+    } || self.at_breakpoint(location);
+    self.last = Some(location);
+    if !stop {
+      return Ok(());
+    }
+    let resume = self
+      .print_stop(location, scope)
+      .and_then(|_| self.prompt(location))
+      .map_err(|e| RuntimeError::new(format!("Debugger failed: {}", e)))?;
+    if resume {
+      Ok(())
+    } else {
+      Err(RuntimeError::new("Stopped by the debugger"))
+    }
+  }
+}
+
+fn describe(value: &Value) -> String {
+  match value {
+    Value::Str(ref s) => format!("{:?}", s),
+    Value::Function(_, ref params, _) => {
+      let params: Vec<&str> = params.iter().map(|param| param.name.as_str()).collect();
+      format!("fn({})", params.join(", "))
+    }
+    Value::BuiltInFunction(_) => "builtin".to_owned(),
+    _ => value.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  // Output the test can read once the VM owns the debugger.
+  #[derive(Clone, Default)]
+  struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /**
+   * Runs the source under the debugger with the commands as input, returning
+   * the result and what the debugger printed.
+   */
+  fn debug(source: &str, breakpoints: &[usize], commands: &str) -> (String, String) {
+    let output = SharedOutput::default();
+    let input = Cursor::new(commands.to_owned());
+    let mut vm = VM::new();
+    vm.set_debugger(StepDebugger::new("<source>", breakpoints, input, output.clone()));
+    let result = match vm.exec(source) {
+      Ok(value) => value.to_string(),
+      Err(error) => error.message().to_owned(),
+    };
+    let output = String::from_utf8(output.0.borrow().clone()).unwrap();
+    (result, output)
+  }
+
+  fn stops(output: &str) -> Vec<&str> {
+    output
+      .lines()
+      .map(|line| line.trim_start_matches("(atc) "))
+      .filter(|line| line.starts_with("<source>:"))
+      .collect()
+  }
+
+  #[test]
+  fn steps_in_and_over() {
+    let source = "fn add(a, b) {\n  let sum = a + b;\n  sum\n}\nlet x = add(1, 2);\n\
+                  let y = x * 2;\ny\n";
+    let (result, output) = debug(source, &[], "s\nn\n\nc\n");
+    assert_eq!(result, "6");
+    assert_eq!(
+      stops(&output),
+      vec!["<source>:5", "<source>:2", "<source>:3", "<source>:6"]
+    );
+    assert!(output.contains("    3 |   sum\n  [0] a = 1, b = 2, sum = 3\n  [1] add = fn(a, b)\n"));
+    assert!(output.contains("  [0] add = fn(a, b), x = 3\n"));
+  }
+
+  #[test]
+  fn stops_at_breakpoints() {
+    let source = "fn fact(n) {\n  if n == 0 { 1 } else { n * fact(n - 1) }\n}\n\
+                  let a = fact(3);\nlet b = a + 1;\nb\n";
+    // Each call stops once on the line, even though two statements run on it.
+    let (result, output) = debug(source, &[2], "c\nd 2\nb 5\nc\nc\n");
+    assert_eq!(result, "7");
+    assert_eq!(stops(&output), vec!["<source>:2", "<source>:2", "<source>:5"]);
+    assert!(output.contains("  [0] n = 2\n  [1] fact = fn(n)\n"));
+    assert!(output.contains("Breakpoint on line 5\n"));
+  }
+
+  #[test]
+  fn quits() {
+    let (result, output) = debug("let a = 1;\nlet b = 2;\n", &[], "x\nq\n");
+    assert_eq!(result, "Stopped by the debugger");
+    assert!(output.contains(HELP));
+    let (result, _) = debug("let a = 1;\nlet b = 2;\n", &[], "");
+    assert_eq!(result, "Stopped by the debugger");
+  }
+}
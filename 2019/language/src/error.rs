@@ -0,0 +1,132 @@
+use super::ast::SourceRef;
+use colored::*;
+use std::fmt;
+
+/**
+ * An error raised while parsing or executing a program. Errors raised by
+ * builtins don't know where they were called from, so the location is filled
+ * in by the VM as the error propagates out through the call expression.
+ */
+#[derive(Clone, Debug)]
+pub enum RuntimeError {
+  // The source failed to parse.
+  SyntaxError(String, Option<SourceRef>),
+
+  // Evaluation failed.
+  // Ex: 1 / 0, 1 + true, calling an undeclared function.
+  EvalError(String, Option<SourceRef>),
+
+  // The type checker found the program to be ill-typed, before running it.
+  // Ex: 1 + true, let x: int = "one";
+  TypeError(String, Option<SourceRef>),
+
+  // One of the VM's configured limits was exceeded.
+  // Ex: running out of fuel, recursing too deep.
+  ResourceExhausted(String, Option<SourceRef>),
+}
+
+impl RuntimeError {
+  pub fn new<S: Into<String>>(message: S) -> RuntimeError {
+    RuntimeError::EvalError(message.into(), None)
+  }
+
+  pub fn new_at<S: Into<String>>(message: S, source_ref: &SourceRef) -> RuntimeError {
+    RuntimeError::EvalError(message.into(), Some(source_ref.clone()))
+  }
+
+  /**
+   * Sets the location of the error, if it doesn't already have one.
+   */
+  pub fn at(self, source_ref: &SourceRef) -> RuntimeError {
+    match self {
+      RuntimeError::SyntaxError(m, None) => RuntimeError::SyntaxError(m, Some(source_ref.clone())),
+      RuntimeError::EvalError(m, None) => RuntimeError::EvalError(m, Some(source_ref.clone())),
+      RuntimeError::TypeError(m, None) => RuntimeError::TypeError(m, Some(source_ref.clone())),
+      RuntimeError::ResourceExhausted(m, None) => {
+        RuntimeError::ResourceExhausted(m, Some(source_ref.clone()))
+      }
+      e => e,
+    }
+  }
+
+  pub fn message(&self) -> &str {
+    match self {
+      RuntimeError::SyntaxError(m, _)
+      | RuntimeError::EvalError(m, _)
+      | RuntimeError::TypeError(m, _)
+      | RuntimeError::ResourceExhausted(m, _) => m,
+    }
+  }
+
+  pub fn source_ref(&self) -> Option<&SourceRef> {
+    match self {
+      RuntimeError::SyntaxError(_, s)
+      | RuntimeError::EvalError(_, s)
+      | RuntimeError::TypeError(_, s)
+      | RuntimeError::ResourceExhausted(_, s) => s.as_ref(),
+    }
+  }
+
+  /**
+   * Prints the error along with the line of source it happened on. The path
+   * names the file the source was read from.
+   */
+  pub fn log(&self, path: &str, source: &str) {
+    let kind = match self {
+      RuntimeError::SyntaxError(_, _) => "Syntax error",
+      RuntimeError::EvalError(_, _) => "Runtime error",
+      RuntimeError::TypeError(_, _) => "Type error",
+      RuntimeError::ResourceExhausted(_, _) => "Resource exhausted",
+    };
+    log_source_line(kind, self.message().red(), self.source_ref(), path, source);
+  }
+}
+
+/**
+ * Prints the heading and message along with the line of source the location
+ * is on (which is in the file at the path). Used for errors and lints alike.
+ */
+pub fn log_source_line(
+  heading: &str,
+  message: ColoredString,
+  source_ref: Option<&SourceRef>,
+  path: &str,
+  source: &str,
+) {
+  let offset = match source_ref {
+    Some(source_ref) => source_ref.left as usize,
+    None => {
+      println!("{}: {}", heading, message);
+      return;
+    }
+  };
+  let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+  let line_end = source[offset..]
+    .find('\n')
+    .map(|i| offset + i)
+    .unwrap_or_else(|| source.len());
+  let line_number = source[..offset].matches('\n').count() + 1;
+  println!();
+  println!(
+    "{} in {} on line {}, column {}:",
+    heading,
+    path,
+    line_number,
+    offset - line_start
+  );
+  println!("{}", source[line_start..line_end].blue());
+  for _ in line_start..offset {
+    print!(" ");
+  }
+  println!("^ {}", message);
+  println!();
+}
+
+impl fmt::Display for RuntimeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.source_ref() {
+      Some(s) => write!(f, "{} at [{}-{}]", self.message(), s.left, s.right),
+      None => write!(f, "{}", self.message()),
+    }
+  }
+}
@@ -0,0 +1,1109 @@
+use super::ast::*;
+use num_traits::Signed;
+
+const INDENT: &str = "  ";
+const MAX_WIDTH: usize = 100;
+
+// Binding powers, matching the grammar's tiers. Binary operators sit between
+// the block-like expressions (which need parentheses as operands) and unary
+// expressions.
+const BLOCK_LIKE: u8 = 0;
+const UNARY: u8 = 12;
+const TERM: u8 = 13;
+
+/**
+ * Prints the program back to canonical source: 2 space indents, spaced binary
+ * operators, and only the parentheses precedence requires, so the result
+ * parses back to the same AST. The AST doesn't keep comments, so they are
+ * recovered from the source and kept before (or trailing) the statement they
+ * were written next to, along with single blank lines between statements.
+ * The source can be empty for programs that weren't parsed from any.
+ */
+pub fn format_program(program: &Block, source: &str) -> String {
+  let mut formatter = Formatter {
+    source,
+    comments: scan_comments(source),
+    next_comment: 0,
+    out: String::new(),
+    indent: 0,
+  };
+  formatter.block_body(program, program.source_ref.right);
+  formatter.comments_before(u64::MAX);
+  formatter.out
+}
+
+/**
+ * Prints a function's signature the way its declaration starts, without the
+ * body. Ex: fn add(a: int, b: int) -> int
+ */
+pub fn format_signature(
+  name: Option<&Identifier>,
+  params: &[Param],
+  return_type: &Option<TypeAnnotation>,
+) -> String {
+  let mut formatter = Formatter::detached();
+  formatter.write("fn");
+  if let Some(name) = name {
+    formatter.write(" ");
+    formatter.write(&name.name);
+  }
+  formatter.signature(params, return_type);
+  formatter.out.trim_end().to_owned()
+}
+
+pub fn format_type_annotation(annotation: &TypeAnnotation) -> String {
+  let mut formatter = Formatter::detached();
+  formatter.type_annotation(annotation);
+  formatter.out
+}
+
+struct Comment {
+  offset: u64,
+  text: String,
+  // Whether the comment is the only thing on its line (otherwise it trails
+  // some code).
+  own_line: bool,
+}
+
+/**
+ * Finds the line comments in the source, skipping string literals.
+ */
+fn scan_comments(source: &str) -> Vec<Comment> {
+  let mut comments = vec![];
+  let mut in_string = false;
+  let mut chars = source.char_indices().peekable();
+  while let Some((offset, c)) = chars.next() {
+    match c {
+      '"' => in_string = !in_string,
+      '/' if !in_string && chars.peek().map(|&(_, c)| c) == Some('/') => {
+        let end = source[offset..]
+          .find(['\n', '\r'])
+          .map_or(source.len(), |end| offset + end);
+        let line_start = source[..offset].rfind('\n').map_or(0, |start| start + 1);
+        comments.push(Comment {
+          offset: offset as u64,
+          text: source[offset..end].trim_end().to_owned(),
+          own_line: source[line_start..offset].trim().is_empty(),
+        });
+        while chars.peek().is_some_and(|&(next, _)| next < end) {
+          chars.next();
+        }
+      }
+      _ => {}
+    }
+  }
+  comments
+}
+
+struct Formatter<'a> {
+  source: &'a str,
+  comments: Vec<Comment>,
+  next_comment: usize,
+  out: String,
+  indent: usize,
+}
+
+impl<'a> Formatter<'a> {
+  /**
+   * A formatter for pieces of the AST printed on their own, without source.
+   */
+  fn detached() -> Formatter<'a> {
+    Formatter {
+      source: "",
+      comments: vec![],
+      next_comment: 0,
+      out: String::new(),
+      indent: 0,
+    }
+  }
+
+  fn write(&mut self, text: &str) {
+    self.out.push_str(text);
+  }
+
+  fn write_indent(&mut self) {
+    for _ in 0..self.indent {
+      self.out.push_str(INDENT);
+    }
+  }
+
+  fn column(&self) -> usize {
+    let line_start = self.out.rfind('\n').map_or(0, |start| start + 1);
+    self.out[line_start..].chars().count()
+  }
+
+  /**
+   * Renders something on a single line if it can be. The render function
+   * returns false if it can't, and it is rolled back if it spanned lines or
+   * went past the max width anyway.
+   */
+  fn attempt(&mut self, render: impl FnOnce(&mut Self) -> bool) -> bool {
+    let (length, next_comment) = (self.out.len(), self.next_comment);
+    if render(self) && !self.out[length..].contains('\n') && self.column() <= MAX_WIDTH {
+      return true;
+    }
+    self.out.truncate(length);
+    self.next_comment = next_comment;
+    false
+  }
+
+  /**
+   * Writes the comments found before the offset that haven't been written.
+   */
+  fn comments_before(&mut self, offset: u64) {
+    while let Some(comment) = self.comments.get(self.next_comment) {
+      if comment.offset >= offset {
+        break;
+      }
+      let (text, own_line, offset) = (comment.text.clone(), comment.own_line, comment.offset);
+      self.next_comment += 1;
+      if !own_line && self.out.ends_with('\n') {
+        self.out.pop();
+        self.write(" ");
+      } else {
+        if self.blank_line_before(offset) {
+          self.blank_line();
+        }
+        self.write_indent();
+      }
+      self.write(&text);
+      self.write("\n");
+    }
+  }
+
+  fn has_comments_in(&self, source_ref: &SourceRef) -> bool {
+    self.comments[self.next_comment..]
+      .iter()
+      .take_while(|comment| comment.offset < source_ref.right)
+      .any(|comment| comment.offset > source_ref.left)
+  }
+
+  /**
+   * Whether the line before the one the offset is on is blank in the source.
+   */
+  fn blank_line_before(&self, offset: u64) -> bool {
+    let offset = offset as usize;
+    if offset > self.source.len() {
+      return false;
+    }
+    let line_start = match self.source[..offset].rfind('\n') {
+      Some(line_start) => line_start,
+      None => return false,
+    };
+    let previous_line_start = self.source[..line_start].rfind('\n').map_or(0, |start| start + 1);
+    self.source[previous_line_start..line_start].trim().is_empty()
+  }
+
+  /**
+   * Where the source text before the offset ends, skipping back over
+   * whitespace, comments and the ";" ending a statement.
+   */
+  fn text_end_before(&self, offset: u64) -> u64 {
+    let mut end = (offset as usize).min(self.source.len());
+    let mut semicolon = false;
+    loop {
+      end = self.source[..end].trim_end().len();
+      let line_start = self.source[..end].rfind('\n').map_or(0, |start| start + 1);
+      // Comments run to the end of their line.
+      let comment = self.comments.iter().find(|comment| {
+        let offset = comment.offset as usize;
+        offset >= line_start && offset < end
+      });
+      match comment {
+        Some(comment) => end = comment.offset as usize,
+        None if !semicolon && self.source[..end].ends_with(';') => {
+          semicolon = true;
+          end -= 1;
+        }
+        None => return end as u64,
+      }
+    }
+  }
+
+  /**
+   * Separates what follows with a blank line, unless it starts a block (or
+   * the program) or already follows one.
+   */
+  fn blank_line(&mut self) {
+    if !self.out.is_empty() && !self.out.ends_with("{\n") && !self.out.ends_with("\n\n") {
+      self.write("\n");
+    }
+  }
+
+  /**
+   * Starts a line of the block body for the node beginning at the offset,
+   * writing the comments that come before it first.
+   */
+  fn line_start(&mut self, offset: Option<u64>) {
+    if let Some(offset) = offset {
+      self.comments_before(offset);
+      if self.blank_line_before(offset) {
+        self.blank_line();
+      }
+    }
+    self.write_indent();
+  }
+
+  /**
+   * The statements and return expression of the block, one per line at the
+   * current indent. The body's text ends before the end offset.
+   */
+  fn block_body(&mut self, block: &Block, end: u64) {
+    let returns_unit = is_unit(&block.return_expression);
+    let mut starts: Vec<Option<u64>> = block.statements.iter().map(statement_start).collect();
+    if !returns_unit {
+      starts.push(expression_start(&block.return_expression));
+    }
+    // Literals and lists don't record their position, so they are placed by
+    // where their text ends instead, found from what follows them.
+    let mut next = end;
+    for start in starts.iter_mut().rev() {
+      next = start.unwrap_or_else(|| self.text_end_before(next));
+      *start = Some(next);
+    }
+    for (i, statement) in block.statements.iter().enumerate() {
+      self.line_start(starts[i]);
+      let last = returns_unit && i + 1 == block.statements.len();
+      self.statement(statement, last);
+      self.write("\n");
+    }
+    if !returns_unit {
+      self.line_start(starts[block.statements.len()]);
+      self.expression(&block.return_expression, BLOCK_LIKE);
+      self.write("\n");
+    }
+    self.comments_before(block.source_ref.right);
+  }
+
+  fn block(&mut self, block: &Block) {
+    let empty = block.statements.is_empty() && is_unit(&block.return_expression);
+    if empty && !self.has_comments_in(&block.source_ref) {
+      self.write("{}");
+      return;
+    }
+    self.write("{\n");
+    self.indent += 1;
+    // Before the closing brace.
+    self.block_body(block, block.source_ref.right.saturating_sub(1));
+    self.indent -= 1;
+    self.write_indent();
+    self.write("}");
+  }
+
+  /**
+   * Writes a block without statements on one line. Returns false (without
+   * writing anything) for any other block.
+   */
+  fn inline_block(&mut self, block: &Block) -> bool {
+    if !block.statements.is_empty() || self.has_comments_in(&block.source_ref) {
+      return false;
+    }
+    if is_unit(&block.return_expression) {
+      self.write("{}");
+    } else {
+      self.write("{ ");
+      self.expression(&block.return_expression, BLOCK_LIKE);
+      self.write(" }");
+    }
+    true
+  }
+
+  fn block_or_inline(&mut self, block: &Block) {
+    if !self.attempt(|f| f.inline_block(block)) {
+      self.block(block);
+    }
+  }
+
+  /**
+   * Writes the statement, without a line break. Block-like expressions don't
+   * need a ";" as statements, unless they are last (they would be returned).
+   */
+  fn statement(&mut self, statement: &Statement, last: bool) {
+    match statement {
+      Statement::LetStmt(ref identifier, ref annotation, ref expression) => {
+        self.write("let ");
+        self.write(&identifier.name);
+        if let Some(annotation) = annotation {
+          self.write(": ");
+          self.type_annotation(annotation);
+        }
+        self.write(" = ");
+        self.expression(expression, BLOCK_LIKE);
+        self.write(";");
+      }
+      Statement::AssignmentStmt(ref identifier, ref expression) => {
+        self.write(&identifier.name);
+        match compound_assignment(identifier, expression) {
+          Some((op, value)) => {
+            self.write(&format!(" {}= ", bin_op(op)));
+            self.expression(value, BLOCK_LIKE);
+          }
+          None => {
+            self.write(" = ");
+            self.expression(expression, BLOCK_LIKE);
+          }
+        }
+        self.write(";");
+      }
+      Statement::IndexAssignmentStmt(ref identifier, ref indices, ref op, ref expression, _) => {
+        self.write(&identifier.name);
+        for index in indices {
+          self.write("[");
+          self.expression(index, BLOCK_LIKE);
+          self.write("]");
+        }
+        match op {
+          Some(op) => self.write(&format!(" {}= ", bin_op(op))),
+          None => self.write(" = "),
+        }
+        self.expression(expression, BLOCK_LIKE);
+        self.write(";");
+      }
+      Statement::UnusedExprEvalStmt(ref expression) => {
+        self.expression(expression, BLOCK_LIKE);
+        if last || !is_block_like(expression) {
+          self.write(";");
+        }
+      }
+      Statement::FunctionDeclarationStmt(ref name, ref params, ref return_type, ref body) => {
+        self.write("fn ");
+        self.write(&name.name);
+        self.signature(params, return_type);
+        self.block(body);
+      }
+      Statement::EnumDeclarationStmt(ref name, ref variants) => {
+        self.write("enum ");
+        self.write(&name.name);
+        self.write(" ");
+        if variants.is_empty() {
+          self.write("{}");
+        } else if !self.attempt(|f| {
+          f.write("{ ");
+          f.comma_separated(variants, Formatter::enum_variant);
+          f.write(" }");
+          true
+        }) {
+          self.write("{\n");
+          self.indent += 1;
+          for variant in variants {
+            self.write_indent();
+            self.enum_variant(variant);
+            self.write(",\n");
+          }
+          self.indent -= 1;
+          self.write_indent();
+          self.write("}");
+        }
+      }
+      Statement::ImportStmt(ref path, ref alias, _) => {
+        self.write(&format!("import \"{}\" as {};", path, alias.name));
+      }
+    }
+  }
+
+  fn enum_variant(&mut self, variant: &EnumVariant) {
+    self.write(&variant.name.name);
+    if !variant.fields.is_empty() {
+      self.write("(");
+      self.comma_separated(&variant.fields, |f, field| f.write(&field.name));
+      self.write(")");
+    }
+  }
+
+  /**
+   * The parameters and return type of a function, followed by a space.
+   */
+  fn signature(&mut self, params: &[Param], return_type: &Option<TypeAnnotation>) {
+    self.write("(");
+    self.comma_separated(params, |f, param| {
+      f.write(&param.name.name);
+      if let Some(ref annotation) = param.annotation {
+        f.write(": ");
+        f.type_annotation(annotation);
+      }
+    });
+    self.write(") ");
+    if let Some(return_type) = return_type {
+      self.write("-> ");
+      self.type_annotation(return_type);
+      self.write(" ");
+    }
+  }
+
+  fn comma_separated<T>(&mut self, items: &[T], mut write_item: impl FnMut(&mut Self, &T)) {
+    for (i, item) in items.iter().enumerate() {
+      if i > 0 {
+        self.write(", ");
+      }
+      write_item(self, item);
+    }
+  }
+
+  /**
+   * Writes the expression, wrapped in parentheses if it binds less tightly
+   * than the position it is in requires.
+   */
+  fn expression(&mut self, expression: &Expression, min_precedence: u8) {
+    let parenthesize = precedence(expression) < min_precedence;
+    if parenthesize {
+      self.write("(");
+    }
+    match expression {
+      Expression::LiteralExpr(ref literal) => self.literal(literal),
+      Expression::BinExpr(ref l, ref op, ref r, ref source_ref) => {
+        // Operators are left associative, so only the right operand needs
+        // parentheses on the same tier.
+        let precedence = bin_precedence(op);
+        self.expression(l, precedence);
+        // Comments between the operands break the line after the operator.
+        let r_start = expression_start(r).unwrap_or(source_ref.right);
+        let commented = self
+          .comments
+          .get(self.next_comment)
+          .is_some_and(|comment| comment.offset > source_ref.left && comment.offset < r_start);
+        if commented {
+          self.write(&format!(" {}\n", bin_op(op)));
+          self.indent += 1;
+          self.comments_before(r_start);
+          self.write_indent();
+          self.expression(r, precedence + 1);
+          self.indent -= 1;
+        } else {
+          self.write(&format!(" {} ", bin_op(op)));
+          self.expression(r, precedence + 1);
+        }
+      }
+      Expression::UnaryExpr(ref op, ref operand, _) => {
+        self.write(unary_op(op));
+        // Otherwise "- -a" would be written as "--a".
+        if starts_with_unary_op(operand) == Some(unary_op(op)) {
+          self.write(" ");
+        }
+        self.expression(operand, UNARY);
+      }
+      Expression::BlockExpr(ref block) => self.block_or_inline(block),
+      Expression::IdentifierDerefExpr(ref identifier) => self.write(&identifier.name),
+      Expression::FunctionInvokeExpr(ref callee, ref args, _) => {
+        self.expression(callee, TERM);
+        self.write("(");
+        self.comma_separated(args, |f, arg| f.expression(arg, BLOCK_LIKE));
+        self.write(")");
+      }
+      Expression::ListExpr(ref items) => {
+        self.write("[");
+        self.comma_separated(items, |f, item| f.expression(item, BLOCK_LIKE));
+        self.write("]");
+      }
+      Expression::IndexExpr(ref list, ref index, _) => {
+        self.expression(list, TERM);
+        self.write("[");
+        self.expression(index, BLOCK_LIKE);
+        self.write("]");
+      }
+      Expression::FunctionExpr(ref params, ref return_type, ref body) => {
+        self.write("fn ");
+        self.signature(params, return_type);
+        self.block_or_inline(body);
+      }
+      Expression::IfElseExpr(ref condition, ref then_block, ref else_block, _) => {
+        // Either every branch fits on the line, or none of them are inlined.
+        let else_block = else_block.as_deref();
+        if !self.attempt(|f| f.if_else(condition, then_block, else_block, true)) {
+          self.if_else(condition, then_block, else_block, false);
+        }
+      }
+      Expression::PathExpr(ref name, ref member) => {
+        self.write(&format!("{}::{}", name.name, member.name));
+      }
+      Expression::MatchExpr(ref subject, ref arms, ref source_ref) => {
+        self.write("match ");
+        self.expression(subject, BLOCK_LIKE);
+        self.write(" {\n");
+        self.indent += 1;
+        let starts: Vec<Option<u64>> = arms
+          .iter()
+          .map(|arm| pattern_start(&arm.pattern).or_else(|| expression_start(&arm.expression)))
+          .collect();
+        for (i, arm) in arms.iter().enumerate() {
+          // Arms made of only literals and wildcards have no position, so
+          // they take the comments up to the next arm (or the end) with them.
+          let start = starts[i..].iter().find_map(|&start| start).unwrap_or(source_ref.right);
+          self.line_start(Some(start));
+          self.pattern(&arm.pattern);
+          self.write(" => ");
+          self.expression(&arm.expression, BLOCK_LIKE);
+          self.write(",\n");
+        }
+        self.comments_before(source_ref.right);
+        self.indent -= 1;
+        self.write_indent();
+        self.write("}");
+      }
+    }
+    if parenthesize {
+      self.write(")");
+    }
+  }
+
+  fn if_else(
+    &mut self,
+    condition: &Expression,
+    then_block: &Block,
+    else_block: Option<&Block>,
+    inline: bool,
+  ) -> bool {
+    self.write("if ");
+    self.expression(condition, BLOCK_LIKE);
+    self.write(" ");
+    for (i, block) in std::iter::once(then_block).chain(else_block).enumerate() {
+      if i > 0 {
+        self.write(" else ");
+      }
+      if !inline {
+        self.block(block);
+      } else if !self.inline_block(block) {
+        return false;
+      }
+    }
+    true
+  }
+
+  fn literal(&mut self, literal: &LiteralValue) {
+    match literal {
+      // Only ever an omitted return expression, which isn't written.
+      LiteralValue::Unit => {}
+      LiteralValue::Int64(i) => self.write(&i.to_string()),
+      LiteralValue::BigInt(ref i) => self.write(&i.to_string()),
+      // Debug formatting always keeps a "." or exponent, so it parses back as
+      // a float.
+      LiteralValue::Float64(f) => self.write(&format!("{:?}", f)),
+      LiteralValue::Str(ref s) => self.write(&format!("\"{}\"", s)),
+      LiteralValue::Bool(b) => self.write(&b.to_string()),
+    }
+  }
+
+  fn type_annotation(&mut self, annotation: &TypeAnnotation) {
+    match annotation {
+      TypeAnnotation::Named(ref identifier) => self.write(&identifier.name),
+      TypeAnnotation::List(ref element) => {
+        self.write("[");
+        self.type_annotation(element);
+        self.write("]");
+      }
+      TypeAnnotation::Function(ref params, ref return_type) => {
+        self.write("fn(");
+        self.comma_separated(params, Formatter::type_annotation);
+        self.write(") -> ");
+        self.type_annotation(return_type);
+      }
+    }
+  }
+
+  fn pattern(&mut self, pattern: &Pattern) {
+    match pattern {
+      Pattern::Wildcard => self.write("_"),
+      Pattern::Literal(ref literal) => self.literal(literal),
+      Pattern::Binding(ref identifier) => self.write(&identifier.name),
+      Pattern::EnumVariant(ref name, ref variant, ref patterns) => {
+        self.write(&format!("{}::{}", name.name, variant.name));
+        if !patterns.is_empty() {
+          self.write("(");
+          self.comma_separated(patterns, Formatter::pattern);
+          self.write(")");
+        }
+      }
+    }
+  }
+}
+
+fn is_unit(expression: &Expression) -> bool {
+  matches!(expression, Expression::LiteralExpr(LiteralValue::Unit))
+}
+
+fn is_block_like(expression: &Expression) -> bool {
+  matches!(
+    expression,
+    Expression::BlockExpr(_) | Expression::IfElseExpr(_, _, _, _) | Expression::MatchExpr(_, _, _)
+  )
+}
+
+/**
+ * How tightly the expression binds, as the grammar tier it parses at.
+ */
+fn precedence(expression: &Expression) -> u8 {
+  match expression {
+    Expression::BinExpr(_, ref op, _, _) => bin_precedence(op),
+    // Negative literals are only made by passes over the AST, and are written
+    // as a negation.
+    Expression::LiteralExpr(LiteralValue::Int64(i)) if *i < 0 => UNARY,
+    Expression::LiteralExpr(LiteralValue::BigInt(i)) if i.is_negative() => UNARY,
+    Expression::LiteralExpr(LiteralValue::Float64(f)) if f.is_sign_negative() => UNARY,
+    Expression::UnaryExpr(_, _, _) => UNARY,
+    Expression::BlockExpr(_)
+    | Expression::IfElseExpr(_, _, _, _)
+    | Expression::MatchExpr(_, _, _)
+    | Expression::FunctionExpr(_, _, _) => BLOCK_LIKE,
+    _ => TERM,
+  }
+}
+
+fn bin_precedence(op: &BinOp) -> u8 {
+  match op {
+    BinOp::Pipe => 1,
+    BinOp::Or => 2,
+    BinOp::And => 3,
+    BinOp::BitOr => 4,
+    BinOp::BitXor => 5,
+    BinOp::BitAnd => 6,
+    BinOp::Eql | BinOp::Neq => 7,
+    BinOp::Gt | BinOp::Ge | BinOp::Lt | BinOp::Le => 8,
+    BinOp::Shl | BinOp::Shr => 9,
+    BinOp::Plus | BinOp::Minus => 10,
+    BinOp::Star | BinOp::Slash | BinOp::Mod => 11,
+  }
+}
+
+fn unary_op(op: &UnOp) -> &'static str {
+  match op {
+    UnOp::Neg => "-",
+    UnOp::Not => "!",
+    UnOp::BitNot => "~",
+  }
+}
+
+/**
+ * The unary operator the expression is written starting with, if any.
+ */
+fn starts_with_unary_op(expression: &Expression) -> Option<&'static str> {
+  match expression {
+    Expression::UnaryExpr(ref op, _, _) => Some(unary_op(op)),
+    Expression::LiteralExpr(LiteralValue::Int64(i)) if *i < 0 => Some("-"),
+    Expression::LiteralExpr(LiteralValue::BigInt(i)) if i.is_negative() => Some("-"),
+    Expression::LiteralExpr(LiteralValue::Float64(f)) if f.is_sign_negative() => Some("-"),
+    _ => None,
+  }
+}
+
+fn bin_op(op: &BinOp) -> &'static str {
+  match op {
+    BinOp::Ge => ">=",
+    BinOp::Gt => ">",
+    BinOp::Eql => "==",
+    BinOp::Neq => "!=",
+    BinOp::Le => "<=",
+    BinOp::Lt => "<",
+    BinOp::And => "&&",
+    BinOp::Or => "||",
+    BinOp::Minus => "-",
+    BinOp::Plus => "+",
+    BinOp::Slash => "/",
+    BinOp::Star => "*",
+    BinOp::Mod => "%",
+    BinOp::BitAnd => "&",
+    BinOp::BitOr => "|",
+    BinOp::BitXor => "^",
+    BinOp::Shl => "<<",
+    BinOp::Shr => ">>",
+    BinOp::Pipe => "|>",
+  }
+}
+
+/**
+ * The operator and value of an assignment written as a compound assignment,
+ * which is sugar for assigning the binary expression.
+ */
+fn compound_assignment<'e>(
+  identifier: &Identifier,
+  expression: &'e Expression,
+) -> Option<(&'e BinOp, &'e Expression)> {
+  match expression {
+    Expression::BinExpr(ref l, ref op, ref r, _) => match (l.as_ref(), op) {
+      (
+        Expression::IdentifierDerefExpr(ref target),
+        BinOp::Plus | BinOp::Minus | BinOp::Star | BinOp::Slash | BinOp::Mod,
+      ) if target.name == identifier.name => Some((op, r)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+/**
+ * Where the statement starts in the source, if it is known.
+ */
+fn statement_start(statement: &Statement) -> Option<u64> {
+  match statement {
+    Statement::LetStmt(ref identifier, _, _)
+    | Statement::AssignmentStmt(ref identifier, _)
+    | Statement::FunctionDeclarationStmt(ref identifier, _, _, _)
+    | Statement::EnumDeclarationStmt(ref identifier, _) => Some(identifier.source_ref.left),
+    Statement::IndexAssignmentStmt(_, _, _, _, ref source_ref)
+    | Statement::ImportStmt(_, _, ref source_ref) => Some(source_ref.left),
+    Statement::UnusedExprEvalStmt(ref expression) => expression_start(expression),
+  }
+}
+
+fn expression_start(expression: &Expression) -> Option<u64> {
+  match expression {
+    Expression::ListExpr(ref items) => items.iter().find_map(expression_start),
+    _ => expression.source_ref().map(|source_ref| source_ref.left),
+  }
+}
+
+fn pattern_start(pattern: &Pattern) -> Option<u64> {
+  match pattern {
+    Pattern::Binding(ref identifier) | Pattern::EnumVariant(ref identifier, _, _) => {
+      Some(identifier.source_ref.left)
+    }
+    Pattern::Wildcard | Pattern::Literal(_) => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vm::grammar::ProgramParser;
+  use crate::vm::PRELUDE;
+
+  fn parse(source: &str) -> Block {
+    ProgramParser::new()
+      .parse(0, source)
+      .unwrap_or_else(|e| panic!("{:?} parsing:\n{}", e, source))
+  }
+
+  fn format(source: &str) -> String {
+    format_program(&parse(source), source)
+  }
+
+  #[test]
+  fn normalizes_spacing_and_indentation() {
+    let source = "fn add(a:int,b:int)->int{a+b}\nlet xs=[1,2.5,\"s\"];\n\
+                  fn main(args){ let f=fn(x){x*2}; if f(1)>1{println(xs[0]);}else{ 3 }\n\
+                  enum E{A(x,y),B}  x=x+1; xs[0][1]-=2; match e {E::A(_, -1)=>true,_=>false} }";
+    let expected = "\
+fn add(a: int, b: int) -> int {
+  a + b
+}
+let xs = [1, 2.5, \"s\"];
+fn main(args) {
+  let f = fn (x) { x * 2 };
+  if f(1) > 1 {
+    println(xs[0]);
+  } else {
+    3
+  }
+  enum E { A(x, y), B }
+  x += 1;
+  xs[0][1] -= 2;
+  match e {
+    E::A(_, -1) => true,
+    _ => false,
+  }
+}
+";
+    assert_eq!(format(source), expected);
+  }
+
+  #[test]
+  fn parenthesizes_by_precedence() {
+    let source = "((1 + 2)) * 3 - (4 - 5) + -(6 + 7) - (-8) + (fn (x) { x })(1) \
+                  + (if a { 1 } else { 2 }) |> (f |> g) |> (h || (i && j)) |> (a || b) && c";
+    assert_eq!(
+      format(source),
+      "(1 + 2) * 3 - (4 - 5) + -(6 + 7) - -8 + (fn (x) { x })(1) + (if a { 1 } else { 2 }) \
+       |> (f |> g) |> h || i && j |> (a || b) && c\n"
+    );
+  }
+
+  #[test]
+  fn separates_repeated_unary_operators() {
+    let source = "let a = -(-b); let c = - -1; let d = ~(~e); let f = !-g; let h = -(-(-i));";
+    let expected = "let a = - -b;\nlet c = - -1;\nlet d = ~ ~e;\nlet f = !-g;\nlet h = - - -i;\n";
+    assert_eq!(format(source), expected);
+    assert_eq!(format(expected), expected);
+    // Negative literals made by the optimizer start with the operator too.
+    let program = Block::new(
+      vec![],
+      Expression::UnaryExpr(
+        UnOp::Neg,
+        Box::new(Expression::LiteralExpr(LiteralValue::Int64(-1))),
+        src(),
+      ),
+      src(),
+    );
+    assert_eq!(format_program(&program, ""), "- -1\n");
+  }
+
+  #[test]
+  fn block_like_statements_keep_their_semicolons_only_when_needed() {
+    assert_eq!(format("{ 1 }; 2"), "{ 1 }\n2\n");
+    assert_eq!(format("fn f() { if a { b(); }; }"), "fn f() {\n  if a {\n    b();\n  };\n}\n");
+    assert_eq!(format("fn f() { if a { b(); } }"), "fn f() {\n  if a {\n    b();\n  }\n}\n");
+  }
+
+  #[test]
+  fn keeps_comments_and_blank_lines() {
+    let source = "// Leading.
+
+fn f() { // Trailing the brace.
+  let x = 1;   // Trailing x.
+
+
+      // Before the return.
+  x
+  // At the end of the block.
+}
+fn answer() {
+  // Before a literal.
+  [42] // Trailing it.
+}
+let total = 1 +
+  // Between the operands.
+  2;
+let t = 3 + // Trailing the operator.
+  4;
+let s = \"not // a comment\";
+match s {
+  // Before an arm.
+  1 => 0,
+  n => n,
+  // After the arms.
+}
+// The end.";
+    let expected = "// Leading.
+
+fn f() { // Trailing the brace.
+  let x = 1; // Trailing x.
+
+  // Before the return.
+  x
+  // At the end of the block.
+}
+fn answer() {
+  // Before a literal.
+  [42] // Trailing it.
+}
+let total = 1 +
+  // Between the operands.
+  2;
+let t = 3 + // Trailing the operator.
+  4;
+let s = \"not // a comment\";
+match s {
+  // Before an arm.
+  1 => 0,
+  n => n,
+  // After the arms.
+}
+// The end.
+";
+    assert_eq!(format(source), expected);
+    assert_eq!(format(expected), expected);
+  }
+
+  #[test]
+  fn prelude_is_formatted() {
+    assert_eq!(format(PRELUDE), PRELUDE);
+  }
+
+  /**
+   * A xorshift generator, so generated programs are the same on every run.
+   */
+  struct Rng(u64);
+
+  impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      (self.0 % n as u64) as usize
+    }
+  }
+
+  fn src() -> SourceRef {
+    SourceRef::new(0, 0, 0)
+  }
+
+  fn identifier(rng: &mut Rng) -> Identifier {
+    let names = ["a", "b", "foo", "bar_2", "Baz"];
+    Identifier {
+      name: names[rng.below(names.len())].to_owned(),
+      source_ref: src(),
+    }
+  }
+
+  fn literal(rng: &mut Rng) -> LiteralValue {
+    match rng.below(4) {
+      0 => LiteralValue::Int64(rng.below(1000) as i64),
+      1 => LiteralValue::Float64(rng.below(1000) as f64 / 8.0),
+      2 => LiteralValue::Str(["", "hi there", "http://x"][rng.below(3)].to_owned()),
+      _ => LiteralValue::Bool(rng.below(2) == 0),
+    }
+  }
+
+  fn annotation(rng: &mut Rng, depth: usize) -> TypeAnnotation {
+    match rng.below(if depth == 0 { 1 } else { 3 }) {
+      0 => TypeAnnotation::Named(identifier(rng)),
+      1 => TypeAnnotation::List(Box::new(annotation(rng, depth - 1))),
+      _ => TypeAnnotation::Function(
+        (0..rng.below(3)).map(|_| annotation(rng, depth - 1)).collect(),
+        Box::new(annotation(rng, depth - 1)),
+      ),
+    }
+  }
+
+  fn params(rng: &mut Rng) -> Vec<Param> {
+    (0..rng.below(3))
+      .map(|_| Param {
+        name: identifier(rng),
+        annotation: if rng.below(2) == 0 { Some(annotation(rng, 2)) } else { None },
+      })
+      .collect()
+  }
+
+  fn pattern(rng: &mut Rng, depth: usize) -> Pattern {
+    match rng.below(if depth == 0 { 3 } else { 4 }) {
+      0 => Pattern::Wildcard,
+      1 => Pattern::Literal(LiteralValue::Int64(rng.below(20) as i64 - 10)),
+      2 => Pattern::Binding(identifier(rng)),
+      _ => Pattern::EnumVariant(
+        identifier(rng),
+        identifier(rng),
+        (0..rng.below(3)).map(|_| pattern(rng, depth - 1)).collect(),
+      ),
+    }
+  }
+
+  fn expression(rng: &mut Rng, depth: usize) -> Expression {
+    let ops = [
+      BinOp::Pipe,
+      BinOp::Or,
+      BinOp::And,
+      BinOp::BitOr,
+      BinOp::BitXor,
+      BinOp::BitAnd,
+      BinOp::Eql,
+      BinOp::Neq,
+      BinOp::Lt,
+      BinOp::Ge,
+      BinOp::Shl,
+      BinOp::Plus,
+      BinOp::Minus,
+      BinOp::Star,
+      BinOp::Mod,
+    ];
+    let boxed = |rng: &mut Rng| Box::new(expression(rng, depth - 1));
+    match rng.below(if depth == 0 { 3 } else { 14 }) {
+      0 => Expression::LiteralExpr(literal(rng)),
+      1 => Expression::IdentifierDerefExpr(identifier(rng)),
+      2 => Expression::PathExpr(identifier(rng), identifier(rng)),
+      3..=5 => {
+        let op = ops[rng.below(ops.len())].clone();
+        Expression::BinExpr(boxed(rng), op, boxed(rng), src())
+      }
+      6 => {
+        let op = [UnOp::Neg, UnOp::Not, UnOp::BitNot][rng.below(3)].clone();
+        Expression::UnaryExpr(op, boxed(rng), src())
+      }
+      7 => Expression::BlockExpr(Box::new(block(rng, depth - 1))),
+      8 => {
+        let args = (0..rng.below(3)).map(|_| expression(rng, depth - 1)).collect();
+        Expression::FunctionInvokeExpr(boxed(rng), args, src())
+      }
+      9 => Expression::ListExpr((0..rng.below(3)).map(|_| expression(rng, depth - 1)).collect()),
+      10 => Expression::IndexExpr(boxed(rng), boxed(rng), src()),
+      11 => {
+        let return_type = if rng.below(2) == 0 { Some(annotation(rng, 1)) } else { None };
+        Expression::FunctionExpr(params(rng), return_type, Box::new(block(rng, depth - 1)))
+      }
+      12 => {
+        let else_block = match rng.below(2) {
+          0 => Some(Box::new(block(rng, depth - 1))),
+          _ => None,
+        };
+        Expression::IfElseExpr(boxed(rng), Box::new(block(rng, depth - 1)), else_block, src())
+      }
+      _ => {
+        let arms = (0..rng.below(3))
+          .map(|_| MatchArm {
+            pattern: pattern(rng, 2),
+            expression: expression(rng, depth - 1),
+          })
+          .collect();
+        Expression::MatchExpr(boxed(rng), arms, src())
+      }
+    }
+  }
+
+  fn statement(rng: &mut Rng, depth: usize) -> Statement {
+    match rng.below(8) {
+      0 => {
+        let annotation = if rng.below(2) == 0 { Some(annotation(rng, 2)) } else { None };
+        Statement::LetStmt(identifier(rng), annotation, expression(rng, depth))
+      }
+      1 => {
+        let target = identifier(rng);
+        let value = match rng.below(2) {
+          0 => expression(rng, depth),
+          _ => {
+            let ops = [BinOp::Plus, BinOp::Minus, BinOp::Star, BinOp::Slash, BinOp::Mod];
+            Expression::BinExpr(
+              Box::new(Expression::IdentifierDerefExpr(target.clone())),
+              ops[rng.below(ops.len())].clone(),
+              Box::new(expression(rng, depth)),
+              src(),
+            )
+          }
+        };
+        Statement::AssignmentStmt(target, value)
+      }
+      2 => {
+        let indices = (0..1 + rng.below(2)).map(|_| expression(rng, depth)).collect();
+        let op = [None, Some(BinOp::Plus), Some(BinOp::Mod)][rng.below(3)].clone();
+        Statement::IndexAssignmentStmt(identifier(rng), indices, op, expression(rng, depth), src())
+      }
+      3 | 4 => Statement::UnusedExprEvalStmt(expression(rng, depth)),
+      5 => {
+        let return_type = if rng.below(2) == 0 { Some(annotation(rng, 1)) } else { None };
+        let body = Box::new(block(rng, depth));
+        Statement::FunctionDeclarationStmt(identifier(rng), params(rng), return_type, body)
+      }
+      6 => {
+        let variants = (0..rng.below(4))
+          .map(|_| EnumVariant {
+            name: identifier(rng),
+            fields: (0..rng.below(3)).map(|_| identifier(rng)).collect(),
+          })
+          .collect();
+        Statement::EnumDeclarationStmt(identifier(rng), variants)
+      }
+      _ => Statement::ImportStmt("lib/util.at".to_owned(), identifier(rng), src()),
+    }
+  }
+
+  fn block(rng: &mut Rng, depth: usize) -> Block {
+    let statements = (0..rng.below(4)).map(|_| statement(rng, depth)).collect();
+    let return_expression = match rng.below(3) {
+      0 => Expression::LiteralExpr(LiteralValue::Unit),
+      _ => expression(rng, depth),
+    };
+    Block::new(statements, return_expression, src())
+  }
+
+  #[test]
+  fn round_trips_generated_programs() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..200 {
+      let program = block(&mut rng, 3);
+      let formatted = format_program(&program, "");
+      let reparsed = parse(&formatted);
+      assert_eq!(
+        format!("{:?}", reparsed),
+        format!("{:?}", program),
+        "reparsing:\n{}",
+        formatted
+      );
+      assert_eq!(format_program(&reparsed, &formatted), formatted);
+    }
+  }
+}
@@ -16,6 +16,7 @@ pub enum Statement {
 pub enum Expression {
   LiteralExpr(LiteralValue),
   BinaryExpr(Box<Expression>, BinaryOp, Box<Expression>),
+  UnaryExpr(UnaryOp, Box<Expression>),
   IdentDerefExpr(Ident),
   CodeBlockExpr(Box<CodeBlock>),
   IfElseExpr(Box<Expression>, Box<CodeBlock>, Option<Box<CodeBlock>>),
@@ -33,6 +34,13 @@ pub enum LiteralValue {
 #[derive(Clone, Debug)]
 pub struct CodeBlock(Vec<Statement>, Expression);
 
+#[derive(Clone, Debug)]
+pub enum UnaryOp {
+  Neg,
+  Not,
+  BitNot,
+}
+
 #[derive(Clone, Debug)]
 pub enum BinaryOp {
   Ge,
@@ -50,17 +58,20 @@ pub enum BinaryOp {
   Slash,
   Star,
   Mod,
+
+  BitAnd,
+  BitOr,
+  BitXor,
+  Shl,
+  Shr,
 }
 
 productions! {
 
-  // Root program
+  // Root program. Globals are bound by top-level statements, after which the
+  // VM calls `fn main(args)` if one was declared.
   program -> Program {
-    { s:[0..; global_decl] _:[end_of_input] } => s,
-  }
-
-  global_decl -> Statement {
-    { function_decl:[function_decl_statement] } => function_decl,
+    { s:[0..; statement] _:[end_of_input] } => s,
   }
 
   // Statements
@@ -75,6 +86,20 @@ productions! {
         => Statement::LetStmt(ident, expr),
     { ident:[ident] _:[r"="] expr:[expression] _:[r";"] }
         => Statement::Assignment(ident, expr),
+    // Compound assignment, sugar for assigning the binary expression.
+    { ident:[ident] op:[compound_assignment_op] expr:[expression] _:[r";"] }
+        => Statement::Assignment(
+          ident.clone(),
+          Expression::BinaryExpr(Box::new(Expression::IdentDerefExpr(ident)), op, Box::new(expr)),
+        ),
+  }
+
+  compound_assignment_op -> BinaryOp {
+    { _:[r"\+="] } => BinaryOp::Plus,
+    { _:[r"-="] } => BinaryOp::Minus,
+    { _:[r"\*="] } => BinaryOp::Star,
+    { _:[r"/="] } => BinaryOp::Slash,
+    { _:[r"%="] } => BinaryOp::Mod,
   }
 
   function_decl_statement -> Statement {
@@ -116,6 +141,7 @@ productions! {
   }
 
   literal_value -> LiteralValue {
+    { v:[r"[0-9]+(\.[0-9]+)?[eE][+-]?[0-9]+"] } => LiteralValue::Float64(v.parse::<f64>().unwrap()),
     { v:[r"[0-9]+\.[0-9]+"] } => LiteralValue::Float64(v.parse::<f64>().unwrap()),
     { v:[r"[0-9]+"] } => LiteralValue::Int64(v.parse::<i64>().unwrap()),
     { v:[r#""[^"]*""#] } => LiteralValue::Str(v[1..v.len() - 1].to_owned()),
@@ -135,8 +161,28 @@ productions! {
   }
 
   and_expression -> Expression {
-    { l:[eq_expression] _:[r"&&"] r:[and_expression] }
+    { l:[bit_or_expression] _:[r"&&"] r:[and_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::And, Box::new(r)),
+    { p:[bit_or_expression] } => p,
+  }
+
+  // A lone "|" or "&" fails to find a right operand for "||" or "&&", which
+  // backtracks to the next alternative.
+  bit_or_expression -> Expression {
+    { l:[bit_xor_expression] _:[r"\|"] r:[bit_or_expression] }
+        => Expression::BinaryExpr(Box::new(l), BinaryOp::BitOr, Box::new(r)),
+    { p:[bit_xor_expression] } => p,
+  }
+
+  bit_xor_expression -> Expression {
+    { l:[bit_and_expression] _:[r"\^"] r:[bit_xor_expression] }
+        => Expression::BinaryExpr(Box::new(l), BinaryOp::BitXor, Box::new(r)),
+    { p:[bit_and_expression] } => p,
+  }
+
+  bit_and_expression -> Expression {
+    { l:[eq_expression] _:[r"&"] r:[bit_and_expression] }
+        => Expression::BinaryExpr(Box::new(l), BinaryOp::BitAnd, Box::new(r)),
     { p:[eq_expression] } => p,
   }
 
@@ -149,14 +195,22 @@ productions! {
   }
 
   cmp_expression -> Expression {
-    { l:[sum_expression] _:[r">"] r:[cmp_expression] }
+    { l:[shift_expression] _:[r">"] r:[cmp_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Gt, Box::new(r)),
-    { l:[sum_expression] _:[r"<"] r:[cmp_expression] }
+    { l:[shift_expression] _:[r"<"] r:[cmp_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Lt, Box::new(r)),
-    { l:[sum_expression] _:[r">="] r:[cmp_expression] }
+    { l:[shift_expression] _:[r">="] r:[cmp_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Ge, Box::new(r)),
-    { l:[sum_expression] _:[r"<="] r:[cmp_expression] }
+    { l:[shift_expression] _:[r"<="] r:[cmp_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Le, Box::new(r)),
+    { p:[shift_expression] } => p,
+  }
+
+  shift_expression -> Expression {
+    { l:[sum_expression] _:[r"<<"] r:[shift_expression] }
+        => Expression::BinaryExpr(Box::new(l), BinaryOp::Shl, Box::new(r)),
+    { l:[sum_expression] _:[r">>"] r:[shift_expression] }
+        => Expression::BinaryExpr(Box::new(l), BinaryOp::Shr, Box::new(r)),
     { p:[sum_expression] } => p,
   }
 
@@ -169,12 +223,19 @@ productions! {
   }
 
   product_expression -> Expression {
-    { l:[unary_atom] _:[r"\*"] r:[product_expression] }
+    { l:[prefix_expression] _:[r"\*"] r:[product_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Star, Box::new(r)),
-    { l:[unary_atom] _:[r"/"] r:[product_expression] }
+    { l:[prefix_expression] _:[r"/"] r:[product_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Slash, Box::new(r)),
-    { l:[unary_atom] _:[r"%"] r:[product_expression] }
+    { l:[prefix_expression] _:[r"%"] r:[product_expression] }
         => Expression::BinaryExpr(Box::new(l), BinaryOp::Mod, Box::new(r)),
+    { a:[prefix_expression] } => a,
+  }
+
+  prefix_expression -> Expression {
+    { _:[r"-"] e:[prefix_expression] } => Expression::UnaryExpr(UnaryOp::Neg, Box::new(e)),
+    { _:[r"!"] e:[prefix_expression] } => Expression::UnaryExpr(UnaryOp::Not, Box::new(e)),
+    { _:[r"~"] e:[prefix_expression] } => Expression::UnaryExpr(UnaryOp::BitNot, Box::new(e)),
     { a:[unary_atom] } => a,
   }
 
@@ -212,3 +273,75 @@ productions! {
   }
 
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::Parser;
+
+  fn parse_float(source: &str) -> f64 {
+    match Parser::new().parse_or_log_errors(literal_value, source) {
+      Some(LiteralValue::Float64(f)) => f,
+      v => panic!("Expected a float literal, found {:?}", v),
+    }
+  }
+
+  #[test]
+  fn float_literals() {
+    assert_eq!(parse_float("1.5"), 1.5);
+    assert_eq!(parse_float("2.5e2"), 250.0);
+    assert_eq!(parse_float("1e-2"), 0.01);
+  }
+
+  #[test]
+  fn unary_binds_tighter_than_binary() {
+    let parsed = Parser::new().parse_or_log_errors(unary_expression, "-1 * !foo");
+    assert_eq!(
+      format!("{:?}", parsed),
+      "Some(BinaryExpr(UnaryExpr(Neg, LiteralExpr(Int64(1))), Star, \
+       UnaryExpr(Not, IdentDerefExpr(\"foo\"))))"
+    );
+  }
+
+  #[test]
+  fn bitwise_operators_use_c_precedence() {
+    let parse =
+      |source| format!("{:?}", Parser::new().parse_or_log_errors(unary_expression, source));
+    assert_eq!(
+      parse("a | b ^ c & d"),
+      "Some(BinaryExpr(IdentDerefExpr(\"a\"), BitOr, BinaryExpr(IdentDerefExpr(\"b\"), BitXor, \
+       BinaryExpr(IdentDerefExpr(\"c\"), BitAnd, IdentDerefExpr(\"d\")))))"
+    );
+    assert_eq!(
+      parse("1 << 2 + 3 < ~x"),
+      "Some(BinaryExpr(BinaryExpr(LiteralExpr(Int64(1)), Shl, BinaryExpr(LiteralExpr(Int64(2)), \
+       Plus, LiteralExpr(Int64(3)))), Lt, UnaryExpr(BitNot, IdentDerefExpr(\"x\"))))"
+    );
+    assert_eq!(
+      parse("a || b && c | d"),
+      "Some(BinaryExpr(IdentDerefExpr(\"a\"), Or, BinaryExpr(IdentDerefExpr(\"b\"), And, \
+       BinaryExpr(IdentDerefExpr(\"c\"), BitOr, IdentDerefExpr(\"d\")))))"
+    );
+  }
+
+  #[test]
+  fn compound_assignment_is_sugar() {
+    let parsed = Parser::new().parse_or_log_errors(assignment_statement, "count *= n + 1;");
+    assert_eq!(
+      format!("{:?}", parsed),
+      "Some(Assignment(\"count\", BinaryExpr(IdentDerefExpr(\"count\"), Star, \
+       BinaryExpr(IdentDerefExpr(\"n\"), Plus, LiteralExpr(Int64(1))))))"
+    );
+  }
+
+  #[test]
+  fn program_allows_top_level_statements() {
+    let source = "let limit = 3;\nfn main(args) { limit }\n";
+    let parsed = Parser::new().parse_or_log_errors(program, source);
+    assert_eq!(
+      format!("{:?}", parsed),
+      "Some([LetStmt(\"limit\", LiteralExpr(Int64(3))), \
+       FunctionDeclStmt(\"main\", [\"args\"], CodeBlock([], IdentDerefExpr(\"limit\")))])"
+    );
+  }
+}
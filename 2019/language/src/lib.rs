@@ -0,0 +1,36 @@
+#![allow(clippy::enum_variant_names)]
+
+// Hosts embed the language through vm::VM, converting between Rust values and
+// VM values with the traits in convert.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate lalrpop_util;
+extern crate md5;
+extern crate regex;
+extern crate serde_json;
+
+// The legacy PEG parser and grammar are kept as they were written, before the
+// crate was linted.
+#[macro_use]
+#[allow(clippy::all, non_upper_case_globals, unused_imports)]
+pub mod parser;
+pub mod ast;
+pub mod builtins;
+pub mod config;
+pub mod convert;
+pub mod debugger;
+pub mod error;
+pub mod formatter;
+#[allow(dead_code)]
+pub mod grammar;
+pub mod lints;
+pub mod lsp;
+pub mod numeric;
+pub mod optimizer;
+pub mod patterns;
+pub mod scope;
+pub mod types;
+pub mod visitor;
+pub mod vm;
@@ -0,0 +1,479 @@
+use super::ast::*;
+use super::error::log_source_line;
+use super::visitor::*;
+use colored::*;
+
+/**
+ * The lints `atc lint` reports. Each can be allowed (switched off) by name.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LintKind {
+  // A let binding, parameter, pattern binding or import that is never read.
+  // Names starting with an underscore are meant to be unused.
+  // Ex: fn area(width, height) { width * width }
+  UnusedVariable,
+
+  // A declared function that is never referenced.
+  UnusedFunction,
+
+  // A name declared twice in the same scope, which fails at runtime.
+  // Ex: let mul = 1 * 2; let mul = 1 % 2;
+  Redeclared,
+
+  // A declaration hiding one with the same name from an enclosing scope.
+  Shadowing,
+
+  // An if condition made up only of literals, the same branch always runs.
+  // Ex: if 42 == 42 { ... }
+  ConstantCondition,
+
+  // A statement computing a value without side effects, which is dead as the
+  // value is thrown away. Either a plain expression, or a block-like one
+  // ending in a value with more statements following it (so the value isn't
+  // the block's return expression).
+  // Ex: fn sign(x) { if x < 0 { -1 } else { 1 } println(x); }
+  DeadValue,
+}
+
+impl LintKind {
+  pub const ALL: [LintKind; 6] = [
+    LintKind::UnusedVariable,
+    LintKind::UnusedFunction,
+    LintKind::Redeclared,
+    LintKind::Shadowing,
+    LintKind::ConstantCondition,
+    LintKind::DeadValue,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      LintKind::UnusedVariable => "unused_variable",
+      LintKind::UnusedFunction => "unused_function",
+      LintKind::Redeclared => "redeclared",
+      LintKind::Shadowing => "shadowing",
+      LintKind::ConstantCondition => "constant_condition",
+      LintKind::DeadValue => "dead_value",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<LintKind> {
+    LintKind::ALL.iter().copied().find(|kind| kind.name() == name)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lint {
+  pub kind: LintKind,
+  pub message: String,
+  pub source_ref: SourceRef,
+}
+
+impl Lint {
+  /**
+   * Prints the lint along with the line of source it points at.
+   */
+  pub fn log(&self, path: &str, source: &str) {
+    let heading = format!("Warning [{}]", self.kind.name());
+    log_source_line(&heading, self.message.yellow(), Some(&self.source_ref), path, source);
+  }
+}
+
+/**
+ * Lints the program, returning the lints that aren't allowed in source order.
+ * Top-level declarations can be used by the modules importing the program, so
+ * they are only reported unused in scripts (programs declaring `fn main`).
+ */
+pub fn lint_program(program: &Block, allowed: &[LintKind]) -> Vec<Lint> {
+  let script = program.statements.iter().any(|statement| {
+    matches!(statement, Statement::FunctionDeclarationStmt(ref name, _, _, _) if name.name == "main")
+  });
+  let mut linter = Linter {
+    scopes: vec![],
+    lints: vec![],
+    exported: !script,
+  };
+  linter.visit_block(program);
+  let mut lints: Vec<Lint> = linter
+    .lints
+    .into_iter()
+    .filter(|lint| !allowed.contains(&lint.kind))
+    .collect();
+  lints.sort_by_key(|lint| (lint.source_ref.left, lint.source_ref.right));
+  lints
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DeclarationKind {
+  Variable,
+  Parameter,
+  Function,
+  Import,
+}
+
+struct Declaration {
+  identifier: Identifier,
+  kind: DeclarationKind,
+  used: bool,
+}
+
+struct Linter {
+  scopes: Vec<Vec<Declaration>>,
+  lints: Vec<Lint>,
+  // Whether the top-level declarations can be used from outside the program.
+  exported: bool,
+}
+
+impl Linter {
+  fn lint(&mut self, kind: LintKind, message: String, source_ref: &SourceRef) {
+    self.lints.push(Lint {
+      kind,
+      message,
+      source_ref: source_ref.clone(),
+    });
+  }
+
+  fn declare(&mut self, identifier: &Identifier, kind: DeclarationKind) {
+    let name = &identifier.name;
+    let (scope, enclosing) = self.scopes.split_last().unwrap();
+    if scope.iter().any(|d| d.identifier.name == *name) {
+      let message = format!("{} is already declared in this scope", name);
+      self.lint(LintKind::Redeclared, message, &identifier.source_ref);
+    } else if enclosing.iter().flatten().any(|d| d.identifier.name == *name) {
+      let message = format!("{} shadows a declaration of an enclosing scope", name);
+      self.lint(LintKind::Shadowing, message, &identifier.source_ref);
+    }
+    self.scopes.last_mut().unwrap().push(Declaration {
+      identifier: identifier.clone(),
+      kind,
+      used: false,
+    });
+  }
+
+  /**
+   * Marks the innermost declaration of the name as used. Names that were
+   * never declared are builtins (or mistakes left for the VM to report).
+   */
+  fn use_name(&mut self, name: &str) {
+    let declaration = self
+      .scopes
+      .iter_mut()
+      .rev()
+      .flat_map(|scope| scope.iter_mut().rev())
+      .find(|d| d.identifier.name == name);
+    if let Some(declaration) = declaration {
+      declaration.used = true;
+    }
+  }
+
+  fn pop_scope(&mut self) {
+    let top_level = self.scopes.len() == 1;
+    for declaration in self.scopes.pop().unwrap() {
+      let name = &declaration.identifier.name;
+      let exported = top_level && (self.exported || name == "main");
+      if declaration.used
+        || name.starts_with('_')
+        || (exported && declaration.kind != DeclarationKind::Import)
+      {
+        continue;
+      }
+      let (kind, what) = match declaration.kind {
+        DeclarationKind::Variable => (LintKind::UnusedVariable, "variable"),
+        DeclarationKind::Parameter => (LintKind::UnusedVariable, "parameter"),
+        DeclarationKind::Function => (LintKind::UnusedFunction, "function"),
+        DeclarationKind::Import => (LintKind::UnusedVariable, "import"),
+      };
+      let message = format!("Unused {} {}", what, name);
+      self.lint(kind, message, &declaration.identifier.source_ref);
+    }
+  }
+}
+
+impl<'ast> Visitor<'ast> for Linter {
+  fn visit_block(&mut self, block: &'ast Block) {
+    self.scopes.push(vec![]);
+    // Functions are hoisted. Their bodies are linted after the rest of the
+    // block, as by the time they are called they can use anything in it.
+    let mut functions = vec![];
+    for statement in &block.statements {
+      if let Statement::FunctionDeclarationStmt(ref name, ref params, ref ret, ref body) =
+        statement
+      {
+        self.declare(name, DeclarationKind::Function);
+        functions.push((name, params, ret, body));
+      }
+    }
+    for statement in &block.statements {
+      self.visit_statement(statement);
+    }
+    self.visit_expression(&block.return_expression);
+    for (name, params, ret, body) in functions {
+      self.visit_function(Some(name), params, ret.as_ref(), body);
+    }
+    self.pop_scope();
+  }
+
+  fn visit_statement(&mut self, statement: &'ast Statement) {
+    match statement {
+      Statement::LetStmt(ref identifier, _, ref expression) => {
+        self.visit_expression(expression);
+        self.declare(identifier, DeclarationKind::Variable);
+      }
+      Statement::IndexAssignmentStmt(ref identifier, _, _, _, _) => {
+        self.use_name(&identifier.name);
+        walk_statement(self, statement);
+      }
+      Statement::UnusedExprEvalStmt(ref expression) => {
+        let message = if is_pure_value(expression) {
+          Some("The value of this statement is discarded, so it does nothing")
+        } else if discards_value(expression) {
+          Some("Statements follow this block, so the value it ends with is discarded")
+        } else {
+          None
+        };
+        if let (Some(message), Some(source_ref)) = (message, expression.source_ref()) {
+          self.lint(LintKind::DeadValue, message.to_owned(), source_ref);
+        }
+        self.visit_expression(expression);
+      }
+      // Linted along with the rest of the functions of the block.
+      Statement::FunctionDeclarationStmt(_, _, _, _) => {}
+      Statement::ImportStmt(_, ref alias, _) => self.declare(alias, DeclarationKind::Import),
+      _ => walk_statement(self, statement),
+    }
+  }
+
+  fn visit_expression(&mut self, expression: &'ast Expression) {
+    match expression {
+      Expression::IdentifierDerefExpr(ref identifier) => self.use_name(&identifier.name),
+      Expression::PathExpr(ref identifier, _) => self.use_name(&identifier.name),
+      Expression::IfElseExpr(ref condition, _, _, ref source_ref) => {
+        if is_constant(condition) {
+          let message = "The condition is constant, the same branch always runs";
+          let source_ref = condition.source_ref().unwrap_or(source_ref);
+          self.lint(LintKind::ConstantCondition, message.to_owned(), source_ref);
+        }
+        walk_expression(self, expression);
+      }
+      _ => walk_expression(self, expression),
+    }
+  }
+
+  fn visit_function(
+    &mut self,
+    _name: Option<&'ast Identifier>,
+    params: &'ast [Param],
+    _return_type: Option<&'ast TypeAnnotation>,
+    body: &'ast Block,
+  ) {
+    self.scopes.push(vec![]);
+    for param in params {
+      self.declare(&param.name, DeclarationKind::Parameter);
+    }
+    self.visit_block(body);
+    self.pop_scope();
+  }
+
+  fn visit_match_arm(&mut self, arm: &'ast MatchArm) {
+    self.scopes.push(vec![]);
+    self.visit_pattern(&arm.pattern);
+    self.visit_expression(&arm.expression);
+    self.pop_scope();
+  }
+
+  fn visit_pattern(&mut self, pattern: &'ast Pattern) {
+    match pattern {
+      Pattern::Binding(ref identifier) => self.declare(identifier, DeclarationKind::Variable),
+      _ => walk_pattern(self, pattern),
+    }
+  }
+}
+
+/**
+ * Whether the expression is made up of only literals and operators.
+ */
+fn is_constant(expression: &Expression) -> bool {
+  match expression {
+    Expression::LiteralExpr(_) => true,
+    Expression::BinExpr(_, BinOp::Pipe, _, _) => false,
+    Expression::BinExpr(ref l, _, ref r, _) => is_constant(l) && is_constant(r),
+    Expression::UnaryExpr(_, ref operand, _) => is_constant(operand),
+    _ => false,
+  }
+}
+
+/**
+ * Whether the block-like expression ends in a value computed without side
+ * effects, which is pointless to compute when the value is discarded.
+ */
+fn discards_value(expression: &Expression) -> bool {
+  match expression {
+    Expression::BlockExpr(ref block) => is_pure_value(&block.return_expression),
+    Expression::IfElseExpr(_, ref then_block, ref else_block, _) => {
+      is_pure_value(&then_block.return_expression)
+        || else_block
+          .iter()
+          .any(|else_block| is_pure_value(&else_block.return_expression))
+    }
+    Expression::MatchExpr(_, ref arms, _) => arms.iter().any(|arm| is_pure_value(&arm.expression)),
+    _ => false,
+  }
+}
+
+fn is_pure_value(expression: &Expression) -> bool {
+  match expression {
+    Expression::LiteralExpr(LiteralValue::Unit) => false,
+    Expression::LiteralExpr(_)
+    | Expression::IdentifierDerefExpr(_)
+    | Expression::PathExpr(_, _) => true,
+    Expression::BinExpr(_, BinOp::Pipe, _, _) => false,
+    Expression::BinExpr(ref l, _, ref r, _) => is_pure_value(l) && is_pure_value(r),
+    Expression::UnaryExpr(_, ref operand, _) => is_pure_value(operand),
+    Expression::ListExpr(ref items) => items.iter().all(is_pure_value),
+    Expression::IndexExpr(ref list, ref index, _) => is_pure_value(list) && is_pure_value(index),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vm::grammar::ProgramParser;
+
+  /**
+   * Lints the source, returning the name, message and the source text pointed
+   * at of each lint.
+   */
+  fn lint(source: &str, allowed: &[LintKind]) -> Vec<(String, String, String)> {
+    let program = ProgramParser::new().parse(0, source).unwrap();
+    lint_program(&program, allowed)
+      .into_iter()
+      .map(|lint| {
+        let at = &source[lint.source_ref.left as usize..lint.source_ref.right as usize];
+        (lint.kind.name().to_owned(), lint.message, at.to_owned())
+      })
+      .collect()
+  }
+
+  fn expected(lints: &[(&str, &str, &str)]) -> Vec<(String, String, String)> {
+    lints
+      .iter()
+      .map(|(name, message, at)| (name.to_string(), message.to_string(), at.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn unused_declarations() {
+    let source = "
+      import \"util.at\" as util;
+      fn area(width, height) {
+        let unused = 1;
+        let squared = width * width;
+        match squared { 0 => 0, n => 1 }
+      }
+      fn main(args) { area(1, 2) }";
+    assert_eq!(
+      lint(source, &[]),
+      expected(&[
+        ("unused_variable", "Unused import util", "util"),
+        ("unused_variable", "Unused parameter height", "height"),
+        ("unused_variable", "Unused variable unused", "unused"),
+        ("unused_variable", "Unused variable n", "n"),
+        ("unused_variable", "Unused parameter args", "args"),
+      ])
+    );
+    // Functions can use globals declared after them, and compound assignment
+    // reads the variable.
+    assert_eq!(
+      lint("fn next() { counter += 1; } let counter = 0; fn main() { next() }", &[]),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn unused_functions_in_scripts_only() {
+    let source = "fn helper() { fn nested() { 1 } 2 } fn used() { 3 }";
+    assert_eq!(
+      lint(source, &[]),
+      expected(&[("unused_function", "Unused function nested", "nested")])
+    );
+    let script = format!("{} fn main() {{ used() }}", source);
+    assert_eq!(
+      lint(&script, &[]),
+      expected(&[
+        ("unused_function", "Unused function helper", "helper"),
+        ("unused_function", "Unused function nested", "nested"),
+      ])
+    );
+  }
+
+  #[test]
+  fn redeclared_and_shadowed_names() {
+    let source = "let x = 1; let x = 2; fn f(x) { x } { let f = 3; f }";
+    assert_eq!(
+      lint(source, &[]),
+      expected(&[
+        ("redeclared", "x is already declared in this scope", "x"),
+        ("shadowing", "x shadows a declaration of an enclosing scope", "x"),
+        ("shadowing", "f shadows a declaration of an enclosing scope", "f"),
+      ])
+    );
+  }
+
+  #[test]
+  fn constant_conditions() {
+    let source = "let x = 1; if 42 == 42 { 1 } else { 2 }; if x == 42 { 3 }; if !true { 4 }";
+    assert_eq!(
+      lint(source, &[LintKind::DeadValue]),
+      expected(&[
+        ("constant_condition", "The condition is constant, the same branch always runs", "42 == 42"),
+        ("constant_condition", "The condition is constant, the same branch always runs", "!true"),
+      ])
+    );
+  }
+
+  #[test]
+  fn dead_values() {
+    let source = "fn sign(x) { if x < 0 { -1 } else { 1 } println(x); } sign(1)";
+    assert_eq!(
+      lint(source, &[]),
+      expected(&[(
+        "dead_value",
+        "Statements follow this block, so the value it ends with is discarded",
+        "if x < 0 { -1 } else { 1 }"
+      )])
+    );
+    let source = "let x = 1; x + 1; -x; x";
+    assert_eq!(
+      lint(source, &[]),
+      expected(&[
+        ("dead_value", "The value of this statement is discarded, so it does nothing", "x + 1"),
+        ("dead_value", "The value of this statement is discarded, so it does nothing", "-x"),
+      ])
+    );
+    // Blocks and calls run for their side effects are fine.
+    assert_eq!(lint("let x = 1; if x < 0 { println(x) } println(x); x", &[]), vec![]);
+  }
+
+  #[test]
+  fn underscore_names_can_be_unused() {
+    let source = "fn main(_args) { let _unused = 1; match 2 { _n => 3 } }";
+    assert_eq!(lint(source, &[]), vec![]);
+    // They are still checked for being redeclared.
+    assert_eq!(
+      lint("fn main() { let _x = 1; let _x = 2; }", &[]),
+      expected(&[("redeclared", "_x is already declared in this scope", "_x")])
+    );
+  }
+
+  #[test]
+  fn lints_can_be_allowed() {
+    let source = "fn main(args) { let x = 1; let x = 2; if true { x } else { 0 } }";
+    assert_eq!(lint(source, &LintKind::ALL).len(), 0);
+    assert_eq!(
+      lint(source, &[LintKind::UnusedVariable, LintKind::ConstantCondition]),
+      expected(&[("redeclared", "x is already declared in this scope", "x")])
+    );
+    assert_eq!(LintKind::from_name("shadowing"), Some(LintKind::Shadowing));
+    assert_eq!(LintKind::from_name("unknown"), None);
+  }
+}
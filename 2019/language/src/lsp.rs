@@ -0,0 +1,758 @@
+use super::ast::*;
+use super::error::RuntimeError;
+use super::formatter::{format_signature, format_type_annotation};
+use super::visitor::*;
+use super::vm::grammar::ProgramParser;
+use super::vm::{syntax_error, VM};
+use serde_json::{json, Value as Json};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::iter;
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// Values of the protocol's enums.
+const TEXT_DOCUMENT_SYNC_FULL: u8 = 1;
+const SEVERITY_ERROR: u8 = 1;
+const SYMBOL_KIND_FUNCTION: u8 = 12;
+
+/**
+ * Serves the Language Server Protocol over the streams (stdin and stdout for
+ * `atc lsp`) until the client sends exit or closes the input. Documents are
+ * synced in full and re-parsed on every change, publishing the syntax error or
+ * the names that don't resolve as diagnostics. Returns whether the client shut
+ * the server down before it exited.
+ */
+pub fn serve<R: BufRead, W: Write>(mut input: R, output: W) -> io::Result<bool> {
+  let mut server = Server {
+    output,
+    parser: ProgramParser::new(),
+    vm: VM::new(),
+    documents: HashMap::new(),
+    shut_down: false,
+  };
+  while let Some(body) = read_message(&mut input)? {
+    match serde_json::from_slice::<Json>(&body) {
+      Ok(ref message) if message["method"] == "exit" => break,
+      Ok(message) => server.handle(&message)?,
+      Err(e) => {
+        let error = ResponseError::new(PARSE_ERROR, e.to_string());
+        server.respond(&Json::Null, Err(error))?
+      }
+    }
+  }
+  Ok(server.shut_down)
+}
+
+/**
+ * Reads the body of the next message, framed by a Content-Length header.
+ * Returns None once the input is closed.
+ */
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+  let mut length = None;
+  loop {
+    let mut header = String::new();
+    if input.read_line(&mut header)? == 0 {
+      return Ok(None);
+    }
+    let header = header.trim_end();
+    if header.is_empty() && length.is_some() {
+      break;
+    }
+    if let Some((name, value)) = header.split_once(':') {
+      if name.eq_ignore_ascii_case("Content-Length") {
+        let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length");
+        length = Some(value.trim().parse::<usize>().map_err(invalid)?);
+      }
+    }
+  }
+  let mut body = vec![0; length.unwrap()];
+  input.read_exact(&mut body)?;
+  Ok(Some(body))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+  let body = message.to_string();
+  write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+  output.flush()
+}
+
+struct ResponseError {
+  code: i64,
+  message: String,
+}
+
+impl ResponseError {
+  fn new<S: Into<String>>(code: i64, message: S) -> ResponseError {
+    ResponseError {
+      code,
+      message: message.into(),
+    }
+  }
+}
+
+struct Server<W> {
+  output: W,
+  parser: ProgramParser,
+  // Tells the builtins (and the prelude's functions) apart from unknown names.
+  vm: VM,
+  documents: HashMap<String, Document>,
+  shut_down: bool,
+}
+
+struct Document {
+  // None when the document doesn't parse.
+  program: Option<Block>,
+  lines: LineIndex,
+  index: Index,
+}
+
+impl<W: Write> Server<W> {
+  fn handle(&mut self, message: &Json) -> io::Result<()> {
+    let method = match message["method"].as_str() {
+      Some(method) => method,
+      // A response, the server never sends requests of its own.
+      None => return Ok(()),
+    };
+    let params = &message["params"];
+    let id = match message.get("id") {
+      Some(id) => id,
+      None => return self.notification(method, params),
+    };
+    let result = if self.shut_down {
+      Err(ResponseError::new(INVALID_REQUEST, "The server is shut down"))
+    } else {
+      self.request(method, params)
+    };
+    self.respond(id, result)
+  }
+
+  fn request(&mut self, method: &str, params: &Json) -> Result<Json, ResponseError> {
+    match method {
+      "initialize" => Ok(json!({
+        "capabilities": {
+          "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+          "definitionProvider": true,
+          "referencesProvider": true,
+          "hoverProvider": true,
+          "documentSymbolProvider": true,
+        },
+        "serverInfo": { "name": "atc", "version": env!("CARGO_PKG_VERSION") },
+      })),
+      "shutdown" => {
+        self.shut_down = true;
+        Ok(Json::Null)
+      }
+      "textDocument/definition" => self.definition(params),
+      "textDocument/references" => self.references(params),
+      "textDocument/hover" => self.hover(params),
+      "textDocument/documentSymbol" => self.document_symbols(params),
+      _ => Err(ResponseError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+    }
+  }
+
+  fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+    match method {
+      "textDocument/didOpen" => {
+        let source = params["textDocument"]["text"].as_str().unwrap_or_default();
+        self.update(uri, source)
+      }
+      // Changes are synced in full, so the last one holds the whole document.
+      "textDocument/didChange" => match params["contentChanges"].as_array().and_then(|c| c.last()) {
+        Some(change) => self.update(uri, change["text"].as_str().unwrap_or_default()),
+        None => Ok(()),
+      },
+      "textDocument/didClose" => {
+        self.documents.remove(uri);
+        self.publish_diagnostics(uri, vec![])
+      }
+      _ => Ok(()),
+    }
+  }
+
+  fn respond(&mut self, id: &Json, result: Result<Json, ResponseError>) -> io::Result<()> {
+    let message = match result {
+      Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+      Err(error) => json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+      }),
+    };
+    write_message(&mut self.output, &message)
+  }
+
+  fn publish_diagnostics(&mut self, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+    let message = json!({
+      "jsonrpc": "2.0",
+      "method": "textDocument/publishDiagnostics",
+      "params": { "uri": uri, "diagnostics": diagnostics },
+    });
+    write_message(&mut self.output, &message)
+  }
+
+  /**
+   * Re-parses and resolves the document, then publishes its diagnostics.
+   */
+  fn update(&mut self, uri: &str, source: &str) -> io::Result<()> {
+    let lines = LineIndex::new(source);
+    // Documents are parsed on their own, so every SourceRef is in file 0.
+    let (program, index, errors) = match self.parser.parse(0, source) {
+      Ok(program) => {
+        let index = resolve_program(&program);
+        let errors = index
+          .unresolved
+          .iter()
+          .filter(|identifier| self.vm.get_global(&identifier.name).is_none())
+          .map(|identifier| {
+            let message = format!("Unknown name {}", identifier.name);
+            RuntimeError::new_at(message, &identifier.source_ref)
+          })
+          .collect();
+        (Some(program), index, errors)
+      }
+      Err(e) => (None, Index::default(), vec![syntax_error(0, e)]),
+    };
+    let diagnostics = errors
+      .iter()
+      .map(|error| {
+        json!({
+          "range": lines.range(error.source_ref()),
+          "severity": SEVERITY_ERROR,
+          "source": "atc",
+          "message": error.message(),
+        })
+      })
+      .collect();
+    let document = Document {
+      program,
+      lines,
+      index,
+    };
+    self.documents.insert(uri.to_owned(), document);
+    self.publish_diagnostics(uri, diagnostics)
+  }
+
+  fn document(&self, params: &Json) -> Result<&Document, ResponseError> {
+    let uri = params["textDocument"]["uri"]
+      .as_str()
+      .ok_or_else(|| ResponseError::new(INVALID_PARAMS, "Missing textDocument.uri"))?;
+    self
+      .documents
+      .get(uri)
+      .ok_or_else(|| ResponseError::new(INVALID_PARAMS, format!("Unknown document {}", uri)))
+  }
+
+  /**
+   * The document of the params, and the symbol declared or used at their
+   * position (if any).
+   */
+  fn symbol_at(&self, params: &Json) -> Result<(&Document, Option<usize>), ResponseError> {
+    let document = self.document(params)?;
+    let position = &params["position"];
+    let offset = match (position["line"].as_u64(), position["character"].as_u64()) {
+      (Some(line), Some(character)) => document.lines.offset(line as usize, character as usize),
+      _ => return Err(ResponseError::new(INVALID_PARAMS, "Missing position")),
+    };
+    Ok((document, document.index.symbol_at(offset)))
+  }
+
+  fn definition(&self, params: &Json) -> Result<Json, ResponseError> {
+    let (document, symbol) = self.symbol_at(params)?;
+    Ok(match symbol {
+      Some(symbol) => {
+        let source_ref = &document.index.symbols[symbol].identifier.source_ref;
+        location(params, &document.lines, source_ref)
+      }
+      None => Json::Null,
+    })
+  }
+
+  fn references(&self, params: &Json) -> Result<Json, ResponseError> {
+    let (document, symbol) = match self.symbol_at(params)? {
+      (document, Some(symbol)) => (document, symbol),
+      (_, None) => return Ok(json!([])),
+    };
+    let mut source_refs = vec![];
+    if params["context"]["includeDeclaration"] == true {
+      source_refs.push(&document.index.symbols[symbol].identifier.source_ref);
+    }
+    for (source_ref, referenced) in &document.index.references {
+      if *referenced == symbol {
+        source_refs.push(source_ref);
+      }
+    }
+    source_refs.sort_by_key(|source_ref| source_ref.left);
+    let locations: Vec<Json> = source_refs
+      .into_iter()
+      .map(|source_ref| location(params, &document.lines, source_ref))
+      .collect();
+    Ok(json!(locations))
+  }
+
+  fn hover(&self, params: &Json) -> Result<Json, ResponseError> {
+    let (document, symbol) = self.symbol_at(params)?;
+    Ok(match symbol {
+      Some(symbol) => {
+        let detail = &document.index.symbols[symbol].detail;
+        json!({ "contents": { "kind": "markdown", "value": format!("```\n{}\n```", detail) } })
+      }
+      None => Json::Null,
+    })
+  }
+
+  fn document_symbols(&self, params: &Json) -> Result<Json, ResponseError> {
+    let document = self.document(params)?;
+    let mut collector = FunctionCollector {
+      lines: &document.lines,
+      symbols: vec![],
+    };
+    if let Some(ref program) = document.program {
+      collector.visit_block(program);
+    }
+    Ok(json!(collector.symbols))
+  }
+}
+
+/**
+ * The location of the SourceRef in the document of the params.
+ */
+fn location(params: &Json, lines: &LineIndex, source_ref: &SourceRef) -> Json {
+  json!({ "uri": params["textDocument"]["uri"], "range": lines.range(Some(source_ref)) })
+}
+
+/**
+ * Converts the byte offsets of SourceRefs to the protocol's positions (zero
+ * based lines, and UTF-16 code units into the line) and back.
+ */
+struct LineIndex {
+  source: String,
+  line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+  fn new(source: &str) -> LineIndex {
+    LineIndex {
+      source: source.to_owned(),
+      line_starts: iter::once(0)
+        .chain(source.match_indices('\n').map(|(offset, _)| offset + 1))
+        .collect(),
+    }
+  }
+
+  fn position(&self, offset: usize) -> Json {
+    let offset = offset.min(self.source.len());
+    let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+    let character = self.source[self.line_starts[line]..offset]
+      .encode_utf16()
+      .count();
+    json!({ "line": line, "character": character })
+  }
+
+  /**
+   * The range of the SourceRef, the start of the document for errors without
+   * a location.
+   */
+  fn range(&self, source_ref: Option<&SourceRef>) -> Json {
+    let (left, right) = source_ref.map_or((0, 0), |s| (s.left as usize, s.right as usize));
+    json!({ "start": self.position(left), "end": self.position(right) })
+  }
+
+  /**
+   * The offset of the position. Positions past the end of their line are at
+   * the end of it.
+   */
+  fn offset(&self, line: usize, character: usize) -> usize {
+    let start = match self.line_starts.get(line) {
+      Some(&start) => start,
+      None => return self.source.len(),
+    };
+    let mut units = 0;
+    for (offset, c) in self.source[start..].char_indices() {
+      if units >= character || c == '\n' {
+        return start + offset;
+      }
+      units += c.len_utf16();
+    }
+    self.source.len()
+  }
+}
+
+/**
+ * The declarations of a program and the uses of the names resolving to them.
+ */
+#[derive(Default)]
+struct Index {
+  symbols: Vec<Symbol>,
+  // Each use of a declared name, with the symbol it resolves to.
+  references: Vec<(SourceRef, usize)>,
+  // Uses of names the program doesn't declare, either builtins or mistakes.
+  unresolved: Vec<Identifier>,
+}
+
+struct Symbol {
+  identifier: Identifier,
+  // Shown on hover, like the symbol was declared. Ex: fn add(a: int, b: int)
+  detail: String,
+  // The variants of an enum, which are only in scope through the enum.
+  variants: Vec<usize>,
+}
+
+impl Index {
+  /**
+   * The symbol declared or used at the offset. Offsets right after a name are
+   * in it, like a cursor at the end of the name.
+   */
+  fn symbol_at(&self, offset: usize) -> Option<usize> {
+    let offset = offset as u64;
+    let contains = |source_ref: &SourceRef| source_ref.left <= offset && offset <= source_ref.right;
+    self
+      .references
+      .iter()
+      .find(|(source_ref, _)| contains(source_ref))
+      .map(|&(_, symbol)| symbol)
+      .or_else(|| {
+        self
+          .symbols
+          .iter()
+          .position(|symbol| contains(&symbol.identifier.source_ref))
+      })
+  }
+}
+
+/**
+ * Resolves each use of a name to its declaration, scoped like the VM: functions
+ * are hoisted to the top of their block, everything else is visible from its
+ * declaration on.
+ */
+fn resolve_program(program: &Block) -> Index {
+  let mut resolver = Resolver {
+    scopes: vec![],
+    index: Index::default(),
+  };
+  resolver.visit_block(program);
+  let mut index = resolver.index;
+  // Compound assignments are sugar reusing the target, which is only one use.
+  index
+    .references
+    .sort_by_key(|(source_ref, _)| (source_ref.left, source_ref.right));
+  index.references.dedup_by(|a, b| a.0 == b.0);
+  index.unresolved.dedup_by(|a, b| a.source_ref == b.source_ref);
+  index
+}
+
+struct Resolver {
+  // The symbols declared in each enclosing scope, innermost last.
+  scopes: Vec<Vec<usize>>,
+  index: Index,
+}
+
+impl Resolver {
+  fn declare(&mut self, identifier: &Identifier, detail: String) -> usize {
+    let symbol = self.declare_member(identifier, detail);
+    self.scopes.last_mut().unwrap().push(symbol);
+    symbol
+  }
+
+  /**
+   * Adds a symbol that isn't in scope by itself.
+   */
+  fn declare_member(&mut self, identifier: &Identifier, detail: String) -> usize {
+    self.index.symbols.push(Symbol {
+      identifier: identifier.clone(),
+      detail,
+      variants: vec![],
+    });
+    self.index.symbols.len() - 1
+  }
+
+  fn lookup(&self, name: &str) -> Option<usize> {
+    self
+      .scopes
+      .iter()
+      .rev()
+      .flat_map(|scope| scope.iter().rev())
+      .copied()
+      .find(|&symbol| self.index.symbols[symbol].identifier.name == name)
+  }
+
+  fn use_name(&mut self, identifier: &Identifier) -> Option<usize> {
+    let symbol = self.lookup(&identifier.name);
+    match symbol {
+      Some(symbol) => self
+        .index
+        .references
+        .push((identifier.source_ref.clone(), symbol)),
+      None => self.index.unresolved.push(identifier.clone()),
+    }
+    symbol
+  }
+
+  /**
+   * Resolves an Enum::Variant path. The members of modules aren't resolved,
+   * they are declared in another file.
+   */
+  fn use_path(&mut self, enumeration: &Identifier, variant: &Identifier) {
+    let symbol = match self.use_name(enumeration) {
+      Some(symbol) => symbol,
+      None => return,
+    };
+    let symbols = &self.index.symbols;
+    let member = symbols[symbol]
+      .variants
+      .iter()
+      .copied()
+      .find(|&member| symbols[member].identifier.name == variant.name);
+    if let Some(member) = member {
+      self
+        .index
+        .references
+        .push((variant.source_ref.clone(), member));
+    }
+  }
+}
+
+impl<'ast> Visitor<'ast> for Resolver {
+  fn visit_block(&mut self, block: &'ast Block) {
+    self.scopes.push(vec![]);
+    // Functions are hoisted. Their bodies are resolved after the rest of the
+    // block, as by the time they are called they can use anything in it.
+    let mut functions = vec![];
+    for statement in &block.statements {
+      if let Statement::FunctionDeclarationStmt(ref name, ref params, ref ret, ref body) =
+        statement
+      {
+        self.declare(name, format_signature(Some(name), params, ret));
+        functions.push((name, params, ret, body));
+      }
+    }
+    walk_block(self, block);
+    for (name, params, ret, body) in functions {
+      self.visit_function(Some(name), params, ret.as_ref(), body);
+    }
+    self.scopes.pop();
+  }
+
+  fn visit_statement(&mut self, statement: &'ast Statement) {
+    match statement {
+      Statement::LetStmt(ref identifier, ref annotation, ref expression) => {
+        walk_statement(self, statement);
+        let detail = match (annotation, expression) {
+          (_, Expression::FunctionExpr(ref params, ref ret, _)) => {
+            format!("let {} = {}", identifier.name, format_signature(None, params, ret))
+          }
+          (Some(annotation), _) => {
+            format!("let {}: {}", identifier.name, format_type_annotation(annotation))
+          }
+          (None, _) => format!("let {}", identifier.name),
+        };
+        self.declare(identifier, detail);
+      }
+      Statement::AssignmentStmt(ref identifier, ref expression) => {
+        self.use_name(identifier);
+        self.visit_expression(expression);
+      }
+      Statement::IndexAssignmentStmt(ref identifier, ref indices, _, ref expression, _) => {
+        self.use_name(identifier);
+        for index in indices {
+          self.visit_expression(index);
+        }
+        self.visit_expression(expression);
+      }
+      // Resolved along with the rest of the functions of the block.
+      Statement::FunctionDeclarationStmt(_, _, _, _) => {}
+      Statement::EnumDeclarationStmt(ref identifier, ref variants) => {
+        let symbol = self.declare(identifier, format!("enum {}", identifier.name));
+        for variant in variants {
+          let mut detail = format!("{}::{}", identifier.name, variant.name.name);
+          if !variant.fields.is_empty() {
+            let fields: Vec<&str> = variant.fields.iter().map(|f| f.name.as_str()).collect();
+            detail = format!("{}({})", detail, fields.join(", "));
+          }
+          let member = self.declare_member(&variant.name, detail);
+          self.index.symbols[symbol].variants.push(member);
+        }
+      }
+      Statement::ImportStmt(ref path, ref alias, _) => {
+        self.declare(alias, format!("import \"{}\" as {}", path, alias.name));
+      }
+      Statement::UnusedExprEvalStmt(_) => walk_statement(self, statement),
+    }
+  }
+
+  fn visit_expression(&mut self, expression: &'ast Expression) {
+    match expression {
+      Expression::IdentifierDerefExpr(ref identifier) => {
+        self.use_name(identifier);
+      }
+      Expression::PathExpr(ref enumeration, ref variant) => self.use_path(enumeration, variant),
+      _ => walk_expression(self, expression),
+    }
+  }
+
+  fn visit_function(
+    &mut self,
+    name: Option<&'ast Identifier>,
+    params: &'ast [Param],
+    return_type: Option<&'ast TypeAnnotation>,
+    body: &'ast Block,
+  ) {
+    self.scopes.push(vec![]);
+    walk_function(self, name, params, return_type, body);
+    self.scopes.pop();
+  }
+
+  fn visit_param(&mut self, param: &'ast Param) {
+    walk_param(self, param);
+    let name = &param.name.name;
+    let detail = match param.annotation {
+      Some(ref annotation) => format!("{}: {}", name, format_type_annotation(annotation)),
+      None => name.clone(),
+    };
+    self.declare(&param.name, detail);
+  }
+
+  fn visit_type_annotation(&mut self, annotation: &'ast TypeAnnotation) {
+    match annotation {
+      // Names that don't resolve are builtin types, left to the type checker.
+      TypeAnnotation::Named(ref identifier) => {
+        if let Some(symbol) = self.lookup(&identifier.name) {
+          let source_ref = identifier.source_ref.clone();
+          self.index.references.push((source_ref, symbol));
+        }
+      }
+      _ => walk_type_annotation(self, annotation),
+    }
+  }
+
+  fn visit_match_arm(&mut self, arm: &'ast MatchArm) {
+    self.scopes.push(vec![]);
+    walk_match_arm(self, arm);
+    self.scopes.pop();
+  }
+
+  fn visit_pattern(&mut self, pattern: &'ast Pattern) {
+    match pattern {
+      Pattern::Binding(ref identifier) => {
+        self.declare(identifier, identifier.name.clone());
+      }
+      Pattern::EnumVariant(ref enumeration, ref variant, ref patterns) => {
+        self.use_path(enumeration, variant);
+        for pattern in patterns {
+          self.visit_pattern(pattern);
+        }
+      }
+      _ => walk_pattern(self, pattern),
+    }
+  }
+}
+
+/**
+ * Collects the document symbols of the declared functions, nesting the ones
+ * declared inside a function under it.
+ */
+struct FunctionCollector<'a> {
+  lines: &'a LineIndex,
+  symbols: Vec<Json>,
+}
+
+impl<'a, 'ast> Visitor<'ast> for FunctionCollector<'a> {
+  fn visit_function(
+    &mut self,
+    name: Option<&'ast Identifier>,
+    params: &'ast [Param],
+    return_type: Option<&'ast TypeAnnotation>,
+    body: &'ast Block,
+  ) {
+    // Functions declared in a closure are listed with the closure's function.
+    let name = match name {
+      Some(name) => name,
+      None => return walk_function(self, name, params, return_type, body),
+    };
+    let mut children = FunctionCollector {
+      lines: self.lines,
+      symbols: vec![],
+    };
+    children.visit_block(body);
+    let range = SourceRef {
+      right: body.source_ref.right,
+      ..name.source_ref.clone()
+    };
+    self.symbols.push(json!({
+      "name": name.name,
+      "detail": format_signature(Some(name), params, &return_type.cloned()),
+      "kind": SYMBOL_KIND_FUNCTION,
+      "range": self.lines.range(Some(&range)),
+      "selectionRange": self.lines.range(Some(&name.source_ref)),
+      "children": children.symbols,
+    }));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn resolve(source: &str) -> Index {
+    resolve_program(&ProgramParser::new().parse(0, source).unwrap())
+  }
+
+  /**
+   * The source each use resolves to, as name@offset of the declaration.
+   */
+  fn resolutions(source: &str) -> Vec<String> {
+    let index = resolve(source);
+    index
+      .references
+      .iter()
+      .map(|(source_ref, symbol)| {
+        let declaration = &index.symbols[*symbol].identifier;
+        let name = &source[source_ref.left as usize..source_ref.right as usize];
+        format!("{}->{}", name, declaration.source_ref.left)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn resolves_scopes() {
+    // Functions are hoisted, parameters and match bindings shadow globals.
+    let source = "let x = f(1); fn f(x) { x + y } let y = 2; \
+                  match x { x => x, _ => y }; x += 1;";
+    assert_eq!(
+      resolutions(source),
+      vec!["f->17", "x->19", "y->36", "x->4", "x->53", "y->36", "x->4"]
+    );
+    assert!(resolve(source).unresolved.is_empty());
+  }
+
+  #[test]
+  fn resolves_enums_and_reports_unknown_names() {
+    let source = "enum Shape { Circle(r), Empty } \
+                  fn area(s: Shape) { match s { Shape::Circle(r) => r * pi, _ => nope } }";
+    let index = resolve(source);
+    let unresolved: Vec<&str> = index.unresolved.iter().map(|i| i.name.as_str()).collect();
+    assert_eq!(unresolved, vec!["pi", "nope"]);
+    let offset = source.rfind("Circle").unwrap() + 1;
+    let symbol = &index.symbols[index.symbol_at(offset).unwrap()];
+    assert_eq!(symbol.detail, "Shape::Circle(r)");
+    let offset = source.find("area").unwrap();
+    let symbol = &index.symbols[index.symbol_at(offset).unwrap()];
+    assert_eq!(symbol.detail, "fn area(s: Shape)");
+  }
+
+  #[test]
+  fn converts_positions() {
+    let lines = LineIndex::new("let a = \"é😀\";\nlet b = a;");
+    assert_eq!(lines.position(15), json!({ "line": 0, "character": 12 }));
+    assert_eq!(lines.offset(0, 12), 15);
+    assert_eq!(lines.position(26), json!({ "line": 1, "character": 8 }));
+    assert_eq!(lines.offset(1, 8), 26);
+    assert_eq!(lines.offset(0, 99), 17);
+    assert_eq!(lines.offset(5, 0), 28);
+  }
+}
@@ -1,17 +1,19 @@
-#[macro_use]
-extern crate lazy_static;
 extern crate clap;
-extern crate regex;
-extern crate md5;
+extern crate language_lalrpop;
 
-#[macro_use]
-mod parser;
-mod grammar;
-
-use crate::grammar::*;
-use crate::parser::*;
-use clap::{App, Arg};
+use language_lalrpop::config::VmConfig;
+use language_lalrpop::debugger::StepDebugger;
+use language_lalrpop::grammar::*;
+use language_lalrpop::lints::LintKind;
+use language_lalrpop::lsp;
+use language_lalrpop::parser::*;
+use language_lalrpop::scope::Value;
+use language_lalrpop::vm::VM;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
 
 // productions! {
 
@@ -61,15 +63,111 @@ use std::fs;
 
 fn main() {
   let matches = App::new("atc")
+    .setting(AppSettings::SubcommandsNegateReqs)
     .arg(
       Arg::with_name("input_file")
         .required(true)
         .takes_value(true),
     )
+    .subcommand(
+      SubCommand::with_name("run")
+        .about("Runs a script, calling its main function with the trailing arguments")
+        .arg(Arg::with_name("input_file").required(true))
+        .args(&vm_config_args())
+        .arg(Arg::with_name("args").multiple(true).last(true)),
+    )
+    .subcommand(
+      SubCommand::with_name("debug")
+        .about("Runs a script in the debugger, stopping at breakpoints and stepping by line")
+        .arg(Arg::with_name("input_file").required(true))
+        .arg(
+          Arg::with_name("break")
+            .long("break")
+            .short("b")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Sets a breakpoint on the line, instead of stopping at the first statement"),
+        )
+        .args(&vm_config_args())
+        .arg(Arg::with_name("args").multiple(true).last(true)),
+    )
+    .subcommand(
+      SubCommand::with_name("check")
+        .about("Type checks a script without running it")
+        .arg(Arg::with_name("input_file").required(true)),
+    )
+    .subcommand(
+      SubCommand::with_name("lint")
+        .about("Reports likely mistakes in a script, exiting with 1 if there are any")
+        .arg(Arg::with_name("input_file").required(true))
+        .arg(
+          Arg::with_name("allow")
+            .long("allow")
+            .short("A")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&LintKind::ALL.iter().map(|kind| kind.name()).collect::<Vec<_>>())
+            .help("Doesn't report the lint"),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("fmt")
+        .about("Formats scripts in place")
+        .arg(Arg::with_name("input_file").required(true).multiple(true))
+        .arg(
+          Arg::with_name("check")
+            .long("check")
+            .help("Lists the scripts that aren't formatted instead, exiting with 1 if any"),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("lsp")
+        .about("Runs a language server, speaking LSP over stdin and stdout"),
+    )
     .get_matches();
+
+  if let Some(matches) = matches.subcommand_matches("run") {
+    let filename = matches.value_of("input_file").unwrap();
+    run_script(filename, script_args(matches), VM::with_config(vm_config(matches)));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("debug") {
+    let filename = matches.value_of("input_file").unwrap();
+    let breakpoints: Vec<usize> = match matches.values_of("break") {
+      Some(lines) => lines.map(breakpoint_line).collect(),
+      None => vec![],
+    };
+    let mut vm = VM::with_config(vm_config(matches));
+    let input = io::BufReader::new(io::stdin());
+    vm.set_debugger(StepDebugger::new(filename, &breakpoints, input, io::stdout()));
+    run_script(filename, script_args(matches), vm);
+  }
+
+  if let Some(matches) = matches.subcommand_matches("check") {
+    check_script(matches.value_of("input_file").unwrap());
+  }
+
+  if let Some(matches) = matches.subcommand_matches("lint") {
+    let allowed: Vec<LintKind> = match matches.values_of("allow") {
+      Some(names) => names.filter_map(LintKind::from_name).collect(),
+      None => vec![],
+    };
+    lint_script(matches.value_of("input_file").unwrap(), &allowed);
+  }
+
+  if let Some(matches) = matches.subcommand_matches("fmt") {
+    let filenames: Vec<&str> = matches.values_of("input_file").unwrap().collect();
+    fmt_scripts(&filenames, matches.is_present("check"));
+  }
+
+  if matches.subcommand_matches("lsp").is_some() {
+    serve_lsp();
+  }
+
   let filename = matches.value_of("input_file").unwrap();
   let contents = fs::read_to_string(filename).expect("Cannot read file");
-
   let mut parser = Parser::new();
   if let Some(res) = parser.parse_or_log_errors(program, &contents) {
     println!("{:#?}", res);
@@ -82,3 +180,224 @@ fn main() {
   //   println!("{:#?}", res);
   // }
 }
+
+fn vm_config_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("no_prelude")
+      .long("no-prelude")
+      .help("Doesn't bind the prelude's helper functions (abs, min, range, ...)"),
+    Arg::with_name("sandbox")
+      .long("sandbox")
+      .help("Runs the script without any capabilities, other than those allowed below"),
+    Arg::with_name("allow_read")
+      .long("allow-read")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .requires("sandbox")
+      .help("Allows the sandboxed script to read (and import) files in the directory"),
+    Arg::with_name("allow_write")
+      .long("allow-write")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .requires("sandbox")
+      .help("Allows the sandboxed script to write files in the directory"),
+    Arg::with_name("allow_env")
+      .long("allow-env")
+      .requires("sandbox")
+      .help("Allows the sandboxed script to read environment variables"),
+    Arg::with_name("allow_process")
+      .long("allow-process")
+      .requires("sandbox")
+      .help("Allows the sandboxed script to run other programs"),
+    Arg::with_name("max_steps")
+      .long("max-steps")
+      .takes_value(true)
+      .help("Stops the script after evaluating this many expressions"),
+    Arg::with_name("max_call_depth")
+      .long("max-call-depth")
+      .takes_value(true)
+      .help("Stops the script when function calls nest deeper than this"),
+    Arg::with_name("max_alloc")
+      .long("max-alloc")
+      .takes_value(true)
+      .help(
+        "Stops the script once it has allocated roughly this many bytes of strs and lists in \
+         total, counting those already freed",
+      ),
+    Arg::with_name("optimize")
+      .long("optimize")
+      .short("O")
+      .help("Folds constants and inlines small functions before running the script"),
+  ]
+}
+
+/**
+ * Scripts are trusted unless they are run in the sandbox.
+ */
+fn vm_config(matches: &ArgMatches) -> VmConfig {
+  let roots = |name| match matches.values_of(name) {
+    Some(roots) => roots.map(PathBuf::from).collect(),
+    None => vec![],
+  };
+  let config = if matches.is_present("sandbox") {
+    VmConfig {
+      read_roots: roots("allow_read"),
+      write_roots: roots("allow_write"),
+      env: matches.is_present("allow_env"),
+      process: matches.is_present("allow_process"),
+      ..VmConfig::default()
+    }
+  } else {
+    VmConfig::trusted()
+  };
+  VmConfig {
+    prelude: !matches.is_present("no_prelude"),
+    max_steps: limit(matches, "max_steps"),
+    max_call_depth: limit(matches, "max_call_depth"),
+    max_alloc_bytes: limit(matches, "max_alloc"),
+    optimize: matches.is_present("optimize"),
+    ..config
+  }
+}
+
+fn limit<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+  matches.value_of(name).map(|value| match value.parse() {
+    Ok(limit) => limit,
+    Err(_) => {
+      eprintln!("Invalid --{} limit: {}", name.replace('_', "-"), value);
+      process::exit(2)
+    }
+  })
+}
+
+/**
+ * The arguments after `--`, passed on to the script's main.
+ */
+fn script_args(matches: &ArgMatches) -> Vec<String> {
+  match matches.values_of("args") {
+    Some(args) => args.map(|arg| arg.to_owned()).collect(),
+    None => vec![],
+  }
+}
+
+fn breakpoint_line(line: &str) -> usize {
+  match line.parse() {
+    Ok(line) if line > 0 => line,
+    _ => {
+      eprintln!("Invalid --break line: {}", line);
+      process::exit(2)
+    }
+  }
+}
+
+/**
+ * Runs the script on the VM and exits. An int returned from main becomes the
+ * exit code, anything else (other than unit) is printed.
+ */
+fn run_script(filename: &str, args: Vec<String>, mut vm: VM) -> ! {
+  let result = vm
+    .exec_file(filename)
+    .and_then(|value| vm.call_main(value, args));
+  match result {
+    Ok(Value::Unit) => process::exit(0),
+    Ok(Value::Int64(code)) if code >= i32::MIN as i64 && code <= i32::MAX as i64 => {
+      process::exit(code as i32)
+    }
+    Ok(value) => {
+      println!("{}", value);
+      process::exit(0)
+    }
+    Err(error) => {
+      vm.log_error(&error);
+      process::exit(1)
+    }
+  }
+}
+
+/**
+ * Type checks the script and exits, logging each type error found.
+ */
+fn check_script(filename: &str) -> ! {
+  let vm = VM::new();
+  match vm.check_file(filename) {
+    Ok(ref errors) if errors.is_empty() => process::exit(0),
+    Ok(errors) => {
+      for error in &errors {
+        vm.log_error(error);
+      }
+      process::exit(1)
+    }
+    Err(error) => {
+      vm.log_error(&error);
+      process::exit(1)
+    }
+  }
+}
+
+/**
+ * Lints the script and exits, logging each lint that isn't allowed.
+ */
+fn lint_script(filename: &str, allowed: &[LintKind]) -> ! {
+  let vm = VM::new();
+  match vm.lint_file(filename, allowed) {
+    Ok(ref lints) if lints.is_empty() => process::exit(0),
+    Ok(lints) => {
+      for lint in &lints {
+        vm.log_lint(lint);
+      }
+      process::exit(1)
+    }
+    Err(error) => {
+      vm.log_error(&error);
+      process::exit(1)
+    }
+  }
+}
+
+/**
+ * Formats each script and exits. With check set the scripts are left as they
+ * are, and the ones that aren't already formatted are listed instead.
+ */
+fn fmt_scripts(filenames: &[&str], check: bool) -> ! {
+  let vm = VM::new();
+  let mut failed = false;
+  for filename in filenames {
+    let formatted = match vm.format_file(filename) {
+      Ok(formatted) => formatted,
+      Err(error) => {
+        vm.log_error(&error);
+        failed = true;
+        continue;
+      }
+    };
+    if fs::read_to_string(filename).ok().as_ref() == Some(&formatted) {
+      continue;
+    }
+    if check {
+      println!("{} is not formatted", filename);
+      failed = true;
+    } else if let Err(e) = fs::write(filename, formatted) {
+      eprintln!("Cannot write file {}: {}", filename, e);
+      failed = true;
+    }
+  }
+  process::exit(if failed { 1 } else { 0 })
+}
+
+/**
+ * Serves LSP on stdin and stdout and exits, with 1 if the client exited
+ * without shutting the server down first (as the protocol asks).
+ */
+fn serve_lsp() -> ! {
+  let stdin = io::stdin();
+  match lsp::serve(stdin.lock(), io::stdout()) {
+    Ok(true) => process::exit(0),
+    Ok(false) => process::exit(1),
+    Err(e) => {
+      eprintln!("Cannot serve LSP: {}", e);
+      process::exit(1)
+    }
+  }
+}
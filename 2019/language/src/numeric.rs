@@ -0,0 +1,109 @@
+use super::ast::BinOp;
+use super::scope::Value;
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use std::rc::Rc;
+
+/**
+ * Checked Int64 arithmetic. Overflow is promoted to a BigInt instead of
+ * wrapping or panicking. Returns None for operators ints don't support.
+ */
+pub fn eval_int_bin_op(op: &BinOp, l: i64, r: i64) -> Option<Result<Value, String>> {
+  if let Some(result) = eval_bitwise_bin_op(op, l, r) {
+    return Some(result.map(Value::Int64));
+  }
+  let checked = match op {
+    BinOp::Plus => l.checked_add(r),
+    BinOp::Minus => l.checked_sub(r),
+    BinOp::Star => l.checked_mul(r),
+    BinOp::Slash if r == 0 => return Some(Err("Division by zero".to_owned())),
+    BinOp::Slash => l.checked_div(r),
+    BinOp::Mod if r == 0 => return Some(Err("Modulo by zero".to_owned())),
+    // Only i64::MIN % -1 overflows, which is always 0.
+    BinOp::Mod => Some(l.checked_rem(r).unwrap_or(0)),
+    BinOp::Ge => return Some(Ok(Value::Bool(l >= r))),
+    BinOp::Gt => return Some(Ok(Value::Bool(l > r))),
+    BinOp::Le => return Some(Ok(Value::Bool(l <= r))),
+    BinOp::Lt => return Some(Ok(Value::Bool(l < r))),
+    BinOp::Eql => return Some(Ok(Value::Bool(l == r))),
+    BinOp::Neq => return Some(Ok(Value::Bool(l != r))),
+    _ => return None,
+  };
+  Some(match checked {
+    Some(v) => Ok(Value::Int64(v)),
+    None => eval_big_int_bin_op(op, &BigInt::from(l), &BigInt::from(r))?,
+  })
+}
+
+/**
+ * Bitwise operators, with the same semantics as on an i64 in Rust: bits
+ * shifted out are lost and >> is an arithmetic (sign extending) shift. These
+ * never promote to a BigInt.
+ */
+fn eval_bitwise_bin_op(op: &BinOp, l: i64, r: i64) -> Option<Result<i64, String>> {
+  Some(Ok(match op {
+    BinOp::BitAnd => l & r,
+    BinOp::BitOr => l | r,
+    BinOp::BitXor => l ^ r,
+    BinOp::Shl | BinOp::Shr if !(0..64).contains(&r) => {
+      return Some(Err(format!("Shift amount {} is out of range 0..64", r)))
+    }
+    BinOp::Shl => l << r,
+    BinOp::Shr => l >> r,
+    _ => return None,
+  }))
+}
+
+/**
+ * Arbitrary-precision integer arithmetic. Division truncates towards zero, the
+ * same as Int64 division.
+ */
+pub fn eval_big_int_bin_op(op: &BinOp, l: &BigInt, r: &BigInt) -> Option<Result<Value, String>> {
+  Some(Ok(normalize(match op {
+    BinOp::Plus => l + r,
+    BinOp::Minus => l - r,
+    BinOp::Star => l * r,
+    BinOp::Slash if r.is_zero() => return Some(Err("Division by zero".to_owned())),
+    BinOp::Slash => l / r,
+    BinOp::Mod if r.is_zero() => return Some(Err("Modulo by zero".to_owned())),
+    BinOp::Mod => l % r,
+    BinOp::Ge => return Some(Ok(Value::Bool(l >= r))),
+    BinOp::Gt => return Some(Ok(Value::Bool(l > r))),
+    BinOp::Le => return Some(Ok(Value::Bool(l <= r))),
+    BinOp::Lt => return Some(Ok(Value::Bool(l < r))),
+    BinOp::Eql => return Some(Ok(Value::Bool(l == r))),
+    BinOp::Neq => return Some(Ok(Value::Bool(l != r))),
+    _ => return None,
+  })))
+}
+
+/**
+ * Negates an int, promoting i64::MIN to a BigInt.
+ */
+pub fn negate_int(i: i64) -> Value {
+  match i.checked_neg() {
+    Some(v) => Value::Int64(v),
+    None => Value::BigInt(Rc::new(-BigInt::from(i))),
+  }
+}
+
+/**
+ * Demotes a BigInt back to an Int64 when it fits.
+ */
+pub fn normalize(i: BigInt) -> Value {
+  match i.to_i64() {
+    Some(v) => Value::Int64(v),
+    None => Value::BigInt(Rc::new(i)),
+  }
+}
+
+/**
+ * Widens either integer representation to a BigInt.
+ */
+pub fn to_big_int(value: &Value) -> Option<BigInt> {
+  match value {
+    Value::Int64(i) => Some(BigInt::from(*i)),
+    Value::BigInt(i) => Some((**i).clone()),
+    _ => None,
+  }
+}
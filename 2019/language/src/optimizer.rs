@@ -0,0 +1,479 @@
+use super::ast::*;
+use super::numeric::{negate_int, normalize};
+use super::scope::Value;
+use super::visitor::*;
+use super::vm::eval_bin_op;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// The most nodes a function body can have to be inlined.
+const MAX_INLINE_NODES: usize = 16;
+
+/**
+ * Optimizes the program without changing what it does, errors included:
+ *
+ * - Operators applied to literals are evaluated, unless that fails (the error
+ *   is left for the VM to raise) or makes a value that has no literal.
+ * - If expressions with a literal condition are replaced by the branch taken.
+ * - Calls to small top-level functions, computed only from their params, are
+ *   replaced by the function's body.
+ *
+ * Inlining assumes that top-level functions aren't reassigned by code outside
+ * of the program, such as later programs run on the same VM.
+ */
+pub fn optimize_program(program: Block) -> Block {
+  let inlinable = inlinable_functions(&program);
+  Optimizer { inlinable }.fold_block(program)
+}
+
+struct InlinableFunction {
+  params: Vec<Identifier>,
+  body: Expression,
+}
+
+struct Optimizer {
+  inlinable: HashMap<String, InlinableFunction>,
+}
+
+impl Folder for Optimizer {
+  fn fold_block(&mut self, block: Block) -> Block {
+    let mut block = fold_block_children(self, block);
+    // Left over from folding, they do nothing.
+    block.statements.retain(|statement| {
+      !matches!(statement, Statement::UnusedExprEvalStmt(Expression::LiteralExpr(_)))
+    });
+    block
+  }
+
+  fn fold_expression(&mut self, expression: Expression) -> Expression {
+    match fold_expression_children(self, expression) {
+      Expression::BinExpr(l, op, r, source_ref) => fold_bin_expr(*l, op, *r, source_ref),
+      Expression::UnaryExpr(op, operand, source_ref) => fold_unary_expr(op, *operand, source_ref),
+      Expression::IfElseExpr(condition, then_block, else_block, source_ref) => match *condition {
+        Expression::LiteralExpr(LiteralValue::Bool(true)) => flatten(*then_block),
+        Expression::LiteralExpr(LiteralValue::Bool(false)) => match else_block {
+          Some(else_block) => flatten(*else_block),
+          None => Expression::LiteralExpr(LiteralValue::Unit),
+        },
+        condition => {
+          Expression::IfElseExpr(Box::new(condition), then_block, else_block, source_ref)
+        }
+      },
+      Expression::BlockExpr(block) => flatten(*block),
+      Expression::FunctionInvokeExpr(callee, args, source_ref) => {
+        let inlined = match *callee {
+          Expression::IdentifierDerefExpr(ref name) => self
+            .inlinable
+            .get(&name.name)
+            .and_then(|function| inline(function, &args, &source_ref)),
+          _ => None,
+        };
+        match inlined {
+          // Folded again, now that the params are known.
+          Some(inlined) => self.fold_expression(inlined),
+          None => Expression::FunctionInvokeExpr(callee, args, source_ref),
+        }
+      }
+      expression => expression,
+    }
+  }
+}
+
+fn fold_bin_expr(l: Expression, op: BinOp, r: Expression, source_ref: SourceRef) -> Expression {
+  let folded = match (&l, &op, &r) {
+    // The right operand isn't evaluated at all when these short-circuit.
+    (Expression::LiteralExpr(LiteralValue::Bool(false)), BinOp::And, _) => Some(Value::Bool(false)),
+    (Expression::LiteralExpr(LiteralValue::Bool(true)), BinOp::Or, _) => Some(Value::Bool(true)),
+    (
+      Expression::LiteralExpr(LiteralValue::Bool(_)),
+      BinOp::And | BinOp::Or,
+      Expression::LiteralExpr(LiteralValue::Bool(r)),
+    ) => Some(Value::Bool(*r)),
+    (_, BinOp::And | BinOp::Or | BinOp::Pipe, _) => None,
+    (Expression::LiteralExpr(ref l), op, Expression::LiteralExpr(ref r)) => {
+      eval_bin_op(op, to_value(l), to_value(r), &source_ref).ok()
+    }
+    _ => None,
+  };
+  match folded.and_then(to_literal) {
+    Some(literal) => Expression::LiteralExpr(literal),
+    None => Expression::BinExpr(Box::new(l), op, Box::new(r), source_ref),
+  }
+}
+
+fn fold_unary_expr(op: UnOp, operand: Expression, source_ref: SourceRef) -> Expression {
+  let folded = match (&op, &operand) {
+    (UnOp::Neg, Expression::LiteralExpr(LiteralValue::Int64(i))) => Some(negate_int(*i)),
+    (UnOp::Neg, Expression::LiteralExpr(LiteralValue::BigInt(i))) => Some(normalize(-i.clone())),
+    (UnOp::Neg, Expression::LiteralExpr(LiteralValue::Float64(f))) => Some(Value::Float64(-f)),
+    (UnOp::Not, Expression::LiteralExpr(LiteralValue::Bool(b))) => Some(Value::Bool(!b)),
+    (UnOp::BitNot, Expression::LiteralExpr(LiteralValue::Int64(i))) => Some(Value::Int64(!i)),
+    _ => None,
+  };
+  match folded.and_then(to_literal) {
+    Some(literal) => Expression::LiteralExpr(literal),
+    None => Expression::UnaryExpr(op, Box::new(operand), source_ref),
+  }
+}
+
+/**
+ * A block of just a literal is the literal, as it can't use the block's scope.
+ */
+fn flatten(block: Block) -> Expression {
+  match block.return_expression {
+    Expression::LiteralExpr(literal) if block.statements.is_empty() => {
+      Expression::LiteralExpr(literal)
+    }
+    _ => Expression::BlockExpr(Box::new(block)),
+  }
+}
+
+fn to_value(literal: &LiteralValue) -> Value {
+  match literal {
+    LiteralValue::Unit => Value::Unit,
+    LiteralValue::Int64(i) => Value::Int64(*i),
+    LiteralValue::BigInt(i) => Value::BigInt(Rc::new(i.clone())),
+    LiteralValue::Float64(f) => Value::Float64(*f),
+    LiteralValue::Str(ref s) => Value::Str(s.clone()),
+    LiteralValue::Bool(b) => Value::Bool(*b),
+  }
+}
+
+/**
+ * The literal for a folded value. Strs aren't folded, as creating one counts
+ * against the VM's allocation limit.
+ */
+fn to_literal(value: Value) -> Option<LiteralValue> {
+  match value {
+    Value::Int64(i) => Some(LiteralValue::Int64(i)),
+    Value::Float64(f) => Some(LiteralValue::Float64(f)),
+    Value::Bool(b) => Some(LiteralValue::Bool(b)),
+    _ => None,
+  }
+}
+
+/**
+ * The top-level functions that calls can be replaced with. Their name must be
+ * bound only once in the whole program (so every call refers to them), and
+ * their body must be small and made up only of operators on their params.
+ * Such a function can't call itself, or anything else.
+ */
+fn inlinable_functions(program: &Block) -> HashMap<String, InlinableFunction> {
+  let mut bindings = BindingCounter(HashMap::new());
+  bindings.visit_block(program);
+  let mut inlinable = HashMap::new();
+  for statement in &program.statements {
+    if let Statement::FunctionDeclarationStmt(ref name, ref params, _, ref body) = statement {
+      let params: Vec<Identifier> = params.iter().map(|param| param.name.clone()).collect();
+      let unique_params = params
+        .iter()
+        .enumerate()
+        .all(|(i, param)| !params[..i].contains(param));
+      if bindings.0.get(name.name.as_str()) == Some(&1)
+        && unique_params
+        && body.statements.is_empty()
+        && count_nodes(body) <= MAX_INLINE_NODES
+        && computes_from(&body.return_expression, &params)
+      {
+        let body = body.return_expression.clone();
+        inlinable.insert(name.name.clone(), InlinableFunction { params, body });
+      }
+    }
+  }
+  inlinable
+}
+
+/**
+ * Counts the times each name is bound or assigned to.
+ */
+struct BindingCounter<'ast>(HashMap<&'ast str, usize>);
+
+impl<'ast> BindingCounter<'ast> {
+  fn bind(&mut self, identifier: &'ast Identifier) {
+    *self.0.entry(&identifier.name).or_insert(0) += 1;
+  }
+}
+
+impl<'ast> Visitor<'ast> for BindingCounter<'ast> {
+  fn visit_statement(&mut self, statement: &'ast Statement) {
+    match statement {
+      Statement::LetStmt(ref identifier, _, _)
+      | Statement::AssignmentStmt(ref identifier, _)
+      | Statement::IndexAssignmentStmt(ref identifier, _, _, _, _)
+      | Statement::FunctionDeclarationStmt(ref identifier, _, _, _)
+      | Statement::EnumDeclarationStmt(ref identifier, _)
+      | Statement::ImportStmt(_, ref identifier, _) => self.bind(identifier),
+      Statement::UnusedExprEvalStmt(_) => {}
+    }
+    walk_statement(self, statement)
+  }
+
+  fn visit_param(&mut self, param: &'ast Param) {
+    self.bind(&param.name);
+  }
+
+  fn visit_pattern(&mut self, pattern: &'ast Pattern) {
+    match pattern {
+      Pattern::Binding(ref identifier) => self.bind(identifier),
+      _ => walk_pattern(self, pattern),
+    }
+  }
+}
+
+/**
+ * Whether the expression is computed only from the names, without calling
+ * anything or binding any names of its own.
+ */
+fn computes_from(expression: &Expression, names: &[Identifier]) -> bool {
+  struct Checker<'a> {
+    names: &'a [Identifier],
+    computes: bool,
+  }
+
+  impl<'a, 'ast> Visitor<'ast> for Checker<'a> {
+    fn visit_statement(&mut self, _statement: &'ast Statement) {
+      self.computes = false;
+    }
+
+    fn visit_expression(&mut self, expression: &'ast Expression) {
+      match expression {
+        Expression::IdentifierDerefExpr(ref identifier) if self.names.contains(identifier) => {}
+        Expression::IdentifierDerefExpr(_)
+        | Expression::BinExpr(_, BinOp::Pipe, _, _)
+        | Expression::FunctionInvokeExpr(_, _, _)
+        | Expression::FunctionExpr(_, _, _)
+        | Expression::PathExpr(_, _)
+        | Expression::MatchExpr(_, _, _) => self.computes = false,
+        _ => walk_expression(self, expression),
+      }
+    }
+  }
+
+  let mut checker = Checker {
+    names,
+    computes: true,
+  };
+  checker.visit_expression(expression);
+  checker.computes
+}
+
+/**
+ * Whether any of the names appear anywhere in the expression.
+ */
+fn mentions(expression: &Expression, names: &[Identifier]) -> bool {
+  struct Finder<'a> {
+    names: &'a [Identifier],
+    found: bool,
+  }
+
+  impl<'a, 'ast> Visitor<'ast> for Finder<'a> {
+    fn visit_identifier(&mut self, identifier: &'ast Identifier) {
+      self.found |= self.names.contains(identifier);
+    }
+  }
+
+  let mut finder = Finder {
+    names,
+    found: false,
+  };
+  finder.visit_expression(expression);
+  finder.found
+}
+
+/**
+ * The function's body in place of a call to it. Literal arguments are
+ * substituted for their param, the others are bound to it by a let (in order,
+ * as the call would have evaluated them). Returns None if the call can't be
+ * inlined: it has the wrong number of arguments (an error left for the VM), or
+ * an argument uses one of the param names, which the lets would hide.
+ */
+fn inline(
+  function: &InlinableFunction,
+  args: &[Expression],
+  source_ref: &SourceRef,
+) -> Option<Expression> {
+  if args.len() != function.params.len() || args.iter().any(|arg| mentions(arg, &function.params)) {
+    return None;
+  }
+  let mut statements = vec![];
+  let mut literals = HashMap::new();
+  for (param, arg) in function.params.iter().zip(args) {
+    match arg {
+      Expression::LiteralExpr(ref literal) => {
+        literals.insert(param.name.clone(), literal.clone());
+      }
+      _ => statements.push(Statement::LetStmt(param.clone(), None, arg.clone())),
+    }
+  }
+  let body = Substitution(literals).fold_expression(function.body.clone());
+  Some(Expression::BlockExpr(Box::new(Block::new(statements, body, source_ref.clone()))))
+}
+
+/**
+ * Replaces names with literals. Only used on inlinable bodies, which can't
+ * bind any names of their own to shadow them.
+ */
+struct Substitution(HashMap<String, LiteralValue>);
+
+impl Folder for Substitution {
+  fn fold_expression(&mut self, expression: Expression) -> Expression {
+    match expression {
+      Expression::IdentifierDerefExpr(identifier) => match self.0.get(&identifier.name) {
+        Some(literal) => Expression::LiteralExpr(literal.clone()),
+        None => Expression::IdentifierDerefExpr(identifier),
+      },
+      expression => fold_expression_children(self, expression),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::VmConfig;
+  use crate::formatter::format_program;
+  use crate::vm::grammar::ProgramParser;
+  use crate::vm::VM;
+
+  fn optimize(source: &str) -> String {
+    let program = ProgramParser::new().parse(0, source).unwrap();
+    format_program(&optimize_program(program), "")
+  }
+
+  /**
+   * Runs the source on a VM, returning the value (or error message and the
+   * source it points at).
+   */
+  fn run(source: &str, optimize: bool) -> String {
+    let mut vm = VM::with_config(VmConfig {
+      optimize,
+      ..VmConfig::trusted()
+    });
+    match vm.exec(source) {
+      Ok(value) => format!("{:?}", value),
+      Err(error) => {
+        let at = error
+          .source_ref()
+          .map(|source_ref| &source[source_ref.left as usize..source_ref.right as usize]);
+        format!("{} at {:?}", error.message(), at)
+      }
+    }
+  }
+
+  #[test]
+  fn folds_constants() {
+    let source = "let order = 1 * 2 + 3; let f = -(1.5 * 2) + 1; let b = !(1 < 2) || 3 >= 3;
+                  let c = (2 & 3) << 4 ^ ~0; let s = \"a\" == \"b\";";
+    assert_eq!(
+      optimize(source),
+      "let order = 5;\nlet f = -2.0;\nlet b = true;\nlet c = -33;\nlet s = false;\n"
+    );
+  }
+
+  #[test]
+  fn leaves_operations_that_fail_or_make_other_values() {
+    let source = "let x = 1 / 0; let y = 1 + true; let z = 9223372036854775807 + 1;
+                  let s = \"a\" + \"b\"; let l = [1] + [2]; let n = x + 1;";
+    assert_eq!(
+      optimize(source),
+      "let x = 1 / 0;\nlet y = 1 + true;\nlet z = 9223372036854775807 + 1;\n\
+       let s = \"a\" + \"b\";\nlet l = [1] + [2];\nlet n = x + 1;\n"
+    );
+  }
+
+  #[test]
+  fn negated_big_int_literals_fold_when_they_fit() {
+    assert_eq!(
+      optimize("let min = -9223372036854775808; let big = -99999999999999999999;"),
+      "let min = -9223372036854775808;\nlet big = -99999999999999999999;\n"
+    );
+    assert_eq!(run("-9223372036854775808 - 0", true), "i-9223372036854775808");
+  }
+
+  #[test]
+  fn short_circuits_without_the_right_operand() {
+    assert_eq!(
+      optimize("let a = false && f(); let b = true || f(); let c = true && f();"),
+      "let a = false;\nlet b = true;\nlet c = true && f();\n"
+    );
+  }
+
+  #[test]
+  fn removes_dead_branches() {
+    let source = "if 42 == 42 { println(1); } else { println(2); }
+                  let x = if 1 > 2 { 1 } else { 2 };
+                  if false { println(3); }
+                  let y = if 1 { 2 } else { 3 };
+                  if x == 2 { 4 }";
+    assert_eq!(
+      optimize(source),
+      "{\n  println(1);\n}\nlet x = 2;\nlet y = if 1 { 2 } else { 3 };\nif x == 2 { 4 }\n"
+    );
+  }
+
+  #[test]
+  fn inlines_small_pure_functions() {
+    let source = "fn sq(x) { x * x } fn add(a, b) { a + b } let m = 2;
+                  let n = sq(3) + add(m, 1) + add(1, m) + sq(sq(2));";
+    let optimized = optimize(source);
+    let statement = &optimized[optimized.find("let n").unwrap()..];
+    assert_eq!(
+      statement,
+      "let n = 9 + ({\n  let a = m;\n  a + 1\n}) + ({\n  let b = m;\n  1 + b\n}) + 16;\n"
+    );
+  }
+
+  #[test]
+  fn only_inlines_what_is_safe_to() {
+    let source = "
+      fn fact(n) { if n < 2 { 1 } else { n * fact(n - 1) } }
+      fn calls(x) { println(x) }
+      fn global(x) { x + m }
+      fn reassigned(x) { x }
+      fn shadowed(x) { x }
+      fn with_statements(x) { let y = x; y }
+      fn big(x) { x + x + x + x + x + x + x + x + x }
+      fn pair(a, b) { a - b }
+      let m = 1;
+      reassigned = fn (x) { x + 1 };
+      let f = fn (shadowed) { shadowed(1) };
+      let a = 2;
+      [fact(3), calls(1), global(1), reassigned(1), with_statements(1), big(1), pair(1), pair(1, a)]
+    ";
+    assert_eq!(
+      optimize(source).lines().last(),
+      Some(
+        "[fact(3), calls(1), global(1), reassigned(1), with_statements(1), big(1), pair(1), \
+         pair(1, a)]"
+      )
+    );
+  }
+
+  #[test]
+  fn preserves_behavior() {
+    let sources = [
+      "let order = 1 * 2 + 3; if 42 == 42 { order * 2 } else { 0 }",
+      "fn sq(x) { x * x } fn add(a, b) { a + b } let m = 2; sq(3) + add(m, sq(m))",
+      "fn avg(a, b) { (a + b) / 2 } [avg(1, 3), avg(1.0, 2), avg(5, 6)]",
+      "fn inc(x) { x + 1 } let xs = []; fn push(x) { xs = xs + [x]; x }
+       inc(push(1)) + inc(push(2)); xs",
+      "1 / 0",
+      "let zero = 0; 1 % zero",
+      "fn div(a, b) { a / b } div(1, 0)",
+      "fn div(a, b) { a / b } let x = 4; div(x, 2) + div(x, x - 4)",
+      "fn first(xs) { xs[0] } first([])",
+      "fn pick(c) { if c { 1 } else { 2 } } pick(3)",
+      "if 1 + 1 { 2 }",
+      "-9223372036854775807 - 2",
+      "fn neg(x) { -x } neg(1) + neg(true)",
+      "fn add(a, b) { a + b } add(1)",
+      "fn add(a, b) { a + b } add(1, undefined)",
+      "false && undefined",
+      "true && undefined",
+      "1 << 64",
+    ];
+    for source in sources.iter() {
+      assert_eq!(run(source, true), run(source, false), "running {}", source);
+    }
+  }
+}
@@ -169,6 +169,7 @@ pub fn end_of_input(source: &str, _meta: &mut MetaData, offset: &mut usize) -> O
   }
 }
 
+#[derive(Default)]
 pub struct Parser {}
 
 impl Parser {
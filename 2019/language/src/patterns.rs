@@ -0,0 +1,203 @@
+use super::ast::*;
+use super::error::RuntimeError;
+use super::scope::*;
+use super::visitor::*;
+use std::collections::HashMap;
+
+/**
+ * Matches a value against a pattern, pushing any bindings it makes. Bindings
+ * are only meaningful if the entire pattern matched.
+ */
+pub fn match_pattern(
+  pattern: &Pattern,
+  value: &Value,
+  bindings: &mut Vec<(Identifier, Value)>,
+) -> bool {
+  match (pattern, value) {
+    (Pattern::Wildcard, _) => true,
+    (Pattern::Binding(ref identifier), _) => {
+      bindings.push((identifier.clone(), value.clone()));
+      true
+    }
+    (Pattern::Literal(ref literal), _) => match (literal, value) {
+      (LiteralValue::Unit, Value::Unit) => true,
+      (LiteralValue::Int64(l), Value::Int64(r)) => l == r,
+      (LiteralValue::BigInt(l), Value::BigInt(r)) => l == &**r,
+      (LiteralValue::Float64(l), Value::Float64(r)) => l == r,
+      (LiteralValue::Str(ref l), Value::Str(ref r)) => l == r,
+      (LiteralValue::Bool(l), Value::Bool(r)) => l == r,
+      _ => false,
+    },
+    (Pattern::EnumVariant(ref name, ref variant, ref patterns), Value::EnumValue(n, v, fields)) => {
+      name == n
+        && variant == v
+        && patterns.len() == fields.len()
+        && patterns
+          .iter()
+          .zip(fields.iter())
+          .all(|(p, f)| match_pattern(p, f, bindings))
+    }
+    _ => false,
+  }
+}
+
+/**
+ * Statically checks that every match expression over enum variants in the
+ * block (recursively) covers each variant of the enum. Enums are looked up by
+ * name from every enum declared anywhere in the block. Patterns naming an
+ * unknown variant, or with the wrong number of fields, are also rejected.
+ */
+pub fn check_match_exhaustiveness(block: &Block) -> Result<(), RuntimeError> {
+  let mut collector = EnumCollector(HashMap::new());
+  collector.visit_block(block);
+  let mut checker = ExhaustivenessChecker {
+    enums: collector.0,
+    error: None,
+  };
+  checker.visit_block(block);
+  checker.error.map_or(Ok(()), Err)
+}
+
+type EnumDecls<'a> = HashMap<&'a str, &'a Vec<EnumVariant>>;
+
+struct EnumCollector<'ast>(EnumDecls<'ast>);
+
+impl<'ast> Visitor<'ast> for EnumCollector<'ast> {
+  fn visit_statement(&mut self, statement: &'ast Statement) {
+    if let Statement::EnumDeclarationStmt(ref identifier, ref variants) = statement {
+      self.0.insert(&identifier.name, variants);
+    }
+    walk_statement(self, statement)
+  }
+}
+
+/**
+ * Checks every match expression, keeping the first error found.
+ */
+struct ExhaustivenessChecker<'ast> {
+  enums: EnumDecls<'ast>,
+  error: Option<RuntimeError>,
+}
+
+impl<'ast> Visitor<'ast> for ExhaustivenessChecker<'ast> {
+  fn visit_expression(&mut self, expression: &'ast Expression) {
+    if self.error.is_some() {
+      return;
+    }
+    walk_expression(self, expression);
+    if let (Expression::MatchExpr(_, ref arms, ref source_ref), None) = (expression, &self.error) {
+      let rows = arms.iter().map(|arm| vec![&arm.pattern]).collect();
+      if let Some(ref missing) = missing_variants(rows, 1, &self.enums) {
+        if !missing.is_empty() {
+          self.error = Some(RuntimeError::new_at(
+            format!("Non-exhaustive match, missing: {}", missing.join(", ")),
+            source_ref,
+          ));
+        }
+      }
+    }
+  }
+
+  fn visit_pattern(&mut self, pattern: &'ast Pattern) {
+    if self.error.is_none() {
+      self.error = check_pattern(pattern, &self.enums).err();
+    }
+  }
+}
+
+fn check_pattern(pattern: &Pattern, enums: &EnumDecls) -> Result<(), RuntimeError> {
+  if let Pattern::EnumVariant(ref name, ref variant, ref patterns) = pattern {
+    // Enums we never saw declared are left for the VM to fail on.
+    if let Some(variants) = enums.get(name.name.as_str()) {
+      match variants.iter().find(|v| v.name == *variant) {
+        Some(v) if v.fields.len() != patterns.len() => {
+          return Err(RuntimeError::new_at(
+            format!(
+              "Pattern {}::{} expects {} fields, found {}",
+              name.name,
+              variant.name,
+              v.fields.len(),
+              patterns.len()
+            ),
+            &variant.source_ref,
+          ))
+        }
+        Some(_) => {}
+        None => {
+          return Err(RuntimeError::new_at(
+            format!("Enum {} has no variant {}", name.name, variant.name),
+            &variant.source_ref,
+          ))
+        }
+      }
+    }
+    for pattern in patterns {
+      check_pattern(pattern, enums)?;
+    }
+  }
+  Ok(())
+}
+
+/**
+ * Finds the enum variants (of the first column) not covered by any row of
+ * patterns, where each row is `width` patterns wide. Returns None if the first
+ * column isn't matching over a known enum, in which case there is nothing to
+ * check statically.
+ */
+fn missing_variants(
+  rows: Vec<Vec<&Pattern>>,
+  width: usize,
+  enums: &EnumDecls,
+) -> Option<Vec<String>> {
+  if width == 0 {
+    return None;
+  }
+  let name = rows.iter().find_map(|row| match row[0] {
+    Pattern::EnumVariant(ref name, _, _) => Some(name),
+    _ => None,
+  })?;
+  let variants = enums.get(name.name.as_str())?;
+  let missing = variants
+    .iter()
+    .filter(|variant| {
+      // Specialize the rows to this variant: its fields become new columns.
+      let arity = variant.fields.len();
+      let specialized: Vec<Vec<&Pattern>> = rows
+        .iter()
+        .filter_map(|row| match row[0] {
+          Pattern::EnumVariant(_, ref v, ref patterns) if v == &variant.name => {
+            Some(patterns.iter().chain(row[1..].iter().cloned()).collect())
+          }
+          p if p.is_irrefutable() => Some(
+            std::iter::repeat_n(&Pattern::Wildcard, arity)
+              .chain(row[1..].iter().cloned())
+              .collect(),
+          ),
+          _ => None,
+        })
+        .collect();
+      !is_exhaustive(specialized, arity + width - 1, enums)
+    })
+    .map(|variant| format!("{}::{}", name.name, variant.name.name))
+    .collect();
+  Some(missing)
+}
+
+fn is_exhaustive(rows: Vec<Vec<&Pattern>>, width: usize, enums: &EnumDecls) -> bool {
+  if rows.is_empty() {
+    return false;
+  }
+  if width == 0 {
+    return true;
+  }
+  if let Some(missing) = missing_variants(rows.clone(), width, enums) {
+    return missing.is_empty();
+  }
+  // Not an enum column, so only irrefutable patterns cover it.
+  let rest = rows
+    .into_iter()
+    .filter(|row| row[0].is_irrefutable())
+    .map(|row| row[1..].to_vec())
+    .collect();
+  is_exhaustive(rest, width - 1, enums)
+}
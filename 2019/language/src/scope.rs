@@ -1,4 +1,7 @@
 use super::ast::*;
+use super::error::RuntimeError;
+use super::vm::VM;
+use num_bigint::BigInt;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
@@ -10,14 +13,43 @@ pub enum Value {
   Unit,
   // Closed-over function.
   Function(Rc<RefCell<Scope>>, Vec<Identifier>, Block),
-  // Numeric Values.
+  // Numeric Values. Int64 arithmetic that overflows is promoted to a BigInt,
+  // and BigInt results that fit in an i64 are demoted back again.
   Int64(i64),
+  BigInt(Rc<BigInt>),
+  Float64(f64),
   // Other.
   Str(String),
   Bool(bool),
+  // Immutable list, shared between copies.
+  List(Rc<Vec<Value>>),
+  // Enums: the declared type itself, and a constructed variant of one.
+  Enum(Identifier, Vec<EnumVariant>),
+  EnumValue(Identifier, Identifier, Vec<Value>),
+  EnumConstructor(Identifier, Identifier, usize),
+  // An imported module, by path and its global scope.
+  Module(String, Rc<RefCell<Scope>>),
   // Struct
   // ...
-  BuiltInFunction(fn(Vec<Value>) -> Value),
+  // A function implemented by the host, which may capture host state.
+  BuiltInFunction(BuiltInFunction),
+}
+
+pub type BuiltInFn = dyn Fn(&VM, Vec<Value>) -> Result<Value, RuntimeError>;
+pub type BuiltInFunction = Rc<BuiltInFn>;
+
+fn fmt_enum_value(
+  f: &mut fmt::Formatter,
+  name: &Identifier,
+  variant: &Identifier,
+  fields: &[Value],
+) -> fmt::Result {
+  write!(f, "{}::{}", name.name, variant.name)?;
+  if !fields.is_empty() {
+    let fields: Vec<String> = fields.iter().map(|v| v.to_string()).collect();
+    write!(f, "({})", fields.join(", "))?;
+  }
+  Ok(())
 }
 
 impl fmt::Debug for Value {
@@ -26,8 +58,15 @@ impl fmt::Debug for Value {
       Value::Unit => write!(f, "()"),
       Value::Function(_, params, _) => write!(f, "Function ({:#?})", params),
       Value::Int64(v) => write!(f, "i{}", v),
+      Value::BigInt(v) => write!(f, "i{}", v),
+      Value::Float64(v) => write!(f, "f{:?}", v),
       Value::Str(v) => write!(f, "\"{}\"", v),
       Value::Bool(v) => write!(f, "{}", v),
+      Value::List(v) => write!(f, "{:?}", v),
+      Value::Enum(name, _) => write!(f, "Enum {}", name.name),
+      Value::EnumValue(name, variant, fields) => fmt_enum_value(f, name, variant, fields),
+      Value::EnumConstructor(name, variant, _) => write!(f, "{}::{}", name.name, variant.name),
+      Value::Module(path, _) => write!(f, "Module {}", path),
       Value::BuiltInFunction(_) => write!(f, "BuiltInFunction"),
     }
   }
@@ -39,8 +78,18 @@ impl fmt::Display for Value {
       Value::Unit => write!(f, "()"),
       Value::Function(_, params, _) => write!(f, "Function ({:#?})", params),
       Value::Int64(v) => write!(f, "{}", v),
+      Value::BigInt(v) => write!(f, "{}", v),
+      Value::Float64(v) => write!(f, "{:?}", v),
       Value::Str(v) => write!(f, "{}", v),
       Value::Bool(v) => write!(f, "{}", v),
+      Value::List(v) => {
+        let items: Vec<String> = v.iter().map(|v| v.to_string()).collect();
+        write!(f, "[{}]", items.join(", "))
+      }
+      Value::Enum(name, _) => write!(f, "Enum {}", name.name),
+      Value::EnumValue(name, variant, fields) => fmt_enum_value(f, name, variant, fields),
+      Value::EnumConstructor(name, variant, _) => write!(f, "{}::{}", name.name, variant.name),
+      Value::Module(path, _) => write!(f, "Module {}", path),
       Value::BuiltInFunction(_) => write!(f, "BuiltInFunction"),
     }
   }
@@ -52,7 +101,7 @@ impl fmt::Display for Value {
  */
 #[derive(Debug)]
 pub struct Scope {
-  // Set to none when the parent of this scope is the global scope.
+  // Set to none for the scope holding the builtins, the root of every chain.
   pub parent: Option<Rc<RefCell<Scope>>>,
   // The locals of this scope.
   pub locals: HashMap<Identifier, Value>,
@@ -69,22 +118,32 @@ impl Scope {
   /**
    * Binds a variable to this scope only.
    */
-  pub fn bind_variable(&mut self, identifier: Identifier, value: Value) {
+  pub fn bind_variable(
+    &mut self,
+    identifier: Identifier,
+    value: Value,
+  ) -> Result<(), RuntimeError> {
     if self.locals.contains_key(&identifier) {
-      panic!(
-        "Re-declaration of Ident [{}] at offset [{}-{}] for scope {:#?}",
-        identifier.name, identifier.source_ref.left, identifier.source_ref.right, self
-      );
+      return Err(RuntimeError::new_at(
+        format!("Re-declaration of Ident [{}]", identifier.name),
+        &identifier.source_ref,
+      ));
     }
     self.locals.insert(identifier, value);
+    Ok(())
   }
 
   /**
    * Assigns an already bound variable anywhere up the parent chain.
    */
-  pub fn assign_variable(&mut self, identifier: &Identifier, value: Value) {
+  pub fn assign_variable(
+    &mut self,
+    identifier: &Identifier,
+    value: Value,
+  ) -> Result<(), RuntimeError> {
     if let Some(v) = self.locals.get_mut(identifier) {
       *v = value;
+      Ok(())
     } else {
       // Check parent (chain)
       if let Some(ref parent_rc) = self.parent {
@@ -93,10 +152,10 @@ impl Scope {
           .assign_variable(identifier, value);
       }
       // We made it all the way up to globals and it wasn't bound.
-      panic!(
-        "Ident [{}] never declared before it's use at [{}-{}] for scope {:#?}",
-        identifier.name, identifier.source_ref.left, identifier.source_ref.right, self
-      );
+      Err(RuntimeError::new_at(
+        format!("Ident [{}] never declared before it's use", identifier.name),
+        &identifier.source_ref,
+      ))
     }
   }
 
@@ -104,7 +163,7 @@ impl Scope {
    * Assigns a variable to this scope if and only if it is not already bound (shadowed).
    */
   pub fn close_variable(&mut self, identifier: &Identifier, value: Value) {
-    if !self.locals.contains_key(&identifier) {
+    if !self.locals.contains_key(identifier) {
       self.locals.insert(identifier.clone(), value);
     }
   }
@@ -112,9 +171,9 @@ impl Scope {
   /**
    * Gets a bound variable anywhere up the parent chain.
    */
-  pub fn get_variable(&self, identifier: &Identifier) -> Value {
-    if self.locals.contains_key(&identifier) {
-      return self.locals[identifier].clone();
+  pub fn get_variable(&self, identifier: &Identifier) -> Result<Value, RuntimeError> {
+    if self.locals.contains_key(identifier) {
+      return Ok(self.locals[identifier].clone());
     } else {
       // Check parent (chain)
       if let Some(ref parent_rc) = self.parent {
@@ -122,9 +181,9 @@ impl Scope {
       }
     }
     // We got all the way up to globals and it didn't have it as well.
-    panic!(
-      "Failed to get-bind Ident [{}] defined at [{}-{}] for scope {:#?}",
-      identifier.name, identifier.source_ref.left, identifier.source_ref.right, self
-    );
+    Err(RuntimeError::new_at(
+      format!("Failed to get-bind Ident [{}]", identifier.name),
+      &identifier.source_ref,
+    ))
   }
 }
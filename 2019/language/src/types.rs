@@ -0,0 +1,960 @@
+use super::ast::*;
+use super::error::RuntimeError;
+use super::vm::grammar::ProgramParser;
+use super::vm::PRELUDE;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/**
+ * A type inferred by the checker. Dynamic is the type of values the checker
+ * can't decide on (or doesn't try to), it's compatible with every other type.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+  Unit,
+  Int,
+  Float,
+  Str,
+  Bool,
+  List(Box<Type>),
+  Function(Vec<Type>, Box<Type>),
+  // A declared enum, by name.
+  Enum(String),
+  // A type variable, solved by unification.
+  Var(usize),
+  Dynamic,
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Type::Unit => write!(f, "unit"),
+      Type::Int => write!(f, "int"),
+      Type::Float => write!(f, "float"),
+      Type::Str => write!(f, "str"),
+      Type::Bool => write!(f, "bool"),
+      Type::List(element) => write!(f, "[{}]", element),
+      Type::Function(params, ret) => {
+        let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+        write!(f, "fn({}) -> {}", params.join(", "), ret)
+      }
+      Type::Enum(name) => write!(f, "{}", name),
+      Type::Var(_) => write!(f, "_"),
+      Type::Dynamic => write!(f, "any"),
+    }
+  }
+}
+
+/**
+ * A type that is polymorphic in the (quantified) type variables.
+ */
+#[derive(Clone, Debug)]
+struct Scheme {
+  vars: Vec<usize>,
+  ty: Type,
+}
+
+impl Scheme {
+  fn monomorphic(ty: Type) -> Scheme {
+    Scheme { vars: vec![], ty }
+  }
+}
+
+#[derive(Clone, Debug)]
+enum Binding {
+  Value(Scheme),
+  Enum(Vec<EnumVariant>),
+  Module,
+}
+
+/**
+ * Statically infers the types of the program (Hindley-Milner style, with the
+ * builtins and the prelude in scope) and returns every type error found.
+ * Annotations are checked where they are given. Values the checker can't
+ * decide on, like the result of int arithmetic with an unknown operand (it may
+ * be a float), are dynamic and accepted everywhere.
+ */
+pub fn check_program(program: &Block) -> Vec<RuntimeError> {
+  let mut checker = Checker::new();
+  // The prelude is part of the VM, failing to parse it is a bug in the VM.
+  let prelude = ProgramParser::new().parse(0, PRELUDE).unwrap();
+  checker.infer_statements(&prelude);
+  checker.scopes.push(HashMap::new());
+  checker.infer_statements(program);
+  checker.errors
+}
+
+struct Checker {
+  // The type each type variable was solved to, if it was.
+  substitution: Vec<Option<Type>>,
+  scopes: Vec<HashMap<String, Binding>>,
+  errors: Vec<RuntimeError>,
+}
+
+impl Checker {
+  fn new() -> Checker {
+    let mut checker = Checker {
+      substitution: vec![],
+      scopes: vec![HashMap::new()],
+      errors: vec![],
+    };
+    let (a, b) = (checker.fresh(), checker.fresh());
+    let list = |t: &Type| Type::List(Box::new(t.clone()));
+    let function = |params: Vec<Type>, ret: Type| Type::Function(params, Box::new(ret));
+    let predicate = function(vec![a.clone()], Type::Bool);
+    let builtins = vec![
+      // println takes any number of arguments.
+      ("println", Type::Dynamic),
+      ("read_file_to_str", function(vec![Type::Str], Type::Str)),
+      ("write_file", function(vec![Type::Str, Type::Str], Type::Unit)),
+      ("get_env", function(vec![Type::Str], Type::Dynamic)),
+      ("run_process", function(vec![Type::Str, list(&Type::Str)], Type::Str)),
+      ("map", function(vec![list(&a), function(vec![a.clone()], b.clone())], list(&b))),
+      ("filter", function(vec![list(&a), predicate.clone()], list(&a))),
+      (
+        "fold",
+        function(
+          vec![list(&a), b.clone(), function(vec![b.clone(), a.clone()], b.clone())],
+          b.clone(),
+        ),
+      ),
+      ("sort_by", function(vec![list(&a), function(vec![a.clone()], b.clone())], list(&a))),
+      ("any", function(vec![list(&a), predicate.clone()], Type::Bool)),
+      ("all", function(vec![list(&a), predicate], Type::Bool)),
+      ("window", function(vec![list(&a), Type::Int], list(&list(&a)))),
+      ("split", function(vec![Type::Str, Type::Str], list(&Type::Str))),
+      ("lines", function(vec![Type::Str], list(&Type::Str))),
+      ("trim", function(vec![Type::Str], Type::Str)),
+      ("chars", function(vec![Type::Str], list(&Type::Str))),
+      ("len", function(vec![Type::Dynamic], Type::Int)),
+      ("contains", function(vec![Type::Str, Type::Str], Type::Bool)),
+      ("starts_with", function(vec![Type::Str, Type::Str], Type::Bool)),
+      ("replace", function(vec![Type::Str, Type::Str, Type::Str], Type::Str)),
+      ("parse_int", function(vec![Type::Str], Type::Int)),
+      ("to_string", function(vec![a.clone()], Type::Str)),
+      ("to_float", function(vec![Type::Dynamic], Type::Float)),
+      ("to_int", function(vec![Type::Dynamic], Type::Int)),
+    ];
+    for (name, ty) in builtins {
+      let scheme = checker.generalize(&ty);
+      checker.scopes[0].insert(name.to_owned(), Binding::Value(scheme));
+    }
+    checker
+  }
+
+  fn error(&mut self, message: String, source_ref: &SourceRef) {
+    self
+      .errors
+      .push(RuntimeError::TypeError(message, Some(source_ref.clone())));
+  }
+
+  //==  Unification  ===========================================================
+
+  fn fresh(&mut self) -> Type {
+    self.substitution.push(None);
+    Type::Var(self.substitution.len() - 1)
+  }
+
+  /**
+   * Follows solved type variables until reaching a type that isn't one.
+   */
+  fn resolve(&self, ty: &Type) -> Type {
+    let mut ty = ty.clone();
+    while let Type::Var(var) = ty {
+      match self.substitution[var] {
+        Some(ref solved) => ty = solved.clone(),
+        None => break,
+      }
+    }
+    ty
+  }
+
+  /**
+   * Resolves the type and (recursively) every type it contains.
+   */
+  fn resolve_deep(&self, ty: &Type) -> Type {
+    match self.resolve(ty) {
+      Type::List(element) => Type::List(Box::new(self.resolve_deep(&element))),
+      Type::Function(params, ret) => Type::Function(
+        params.iter().map(|param| self.resolve_deep(param)).collect(),
+        Box::new(self.resolve_deep(&ret)),
+      ),
+      ty => ty,
+    }
+  }
+
+  fn unify(&mut self, a: &Type, b: &Type) -> bool {
+    match (self.resolve(a), self.resolve(b)) {
+      (Type::Var(a), Type::Var(b)) if a == b => true,
+      (Type::Var(var), ty) | (ty, Type::Var(var)) => {
+        if free_vars(&self.resolve_deep(&ty)).contains(&var) {
+          return false;
+        }
+        self.substitution[var] = Some(ty);
+        true
+      }
+      (Type::Dynamic, _) | (_, Type::Dynamic) => true,
+      (Type::List(a), Type::List(b)) => self.unify(&a, &b),
+      (Type::Function(a_params, a_ret), Type::Function(b_params, b_ret)) => {
+        a_params.len() == b_params.len()
+          && a_params
+            .iter()
+            .zip(b_params.iter())
+            .all(|(a, b)| self.unify(a, b))
+          && self.unify(&a_ret, &b_ret)
+      }
+      (a, b) => a == b,
+    }
+  }
+
+  /**
+   * Unifies the types, leaving every type variable as it was if they don't.
+   */
+  fn try_unify(&mut self, a: &Type, b: &Type) -> bool {
+    let snapshot = self.substitution.clone();
+    if self.unify(a, b) {
+      return true;
+    }
+    self.substitution = snapshot;
+    false
+  }
+
+  fn expect(&mut self, expected: &Type, found: &Type, source_ref: &SourceRef) {
+    if !self.try_unify(expected, found) {
+      let message = format!(
+        "Mismatched types, expected {}, found {}",
+        self.resolve_deep(expected),
+        self.resolve_deep(found)
+      );
+      self.error(message, source_ref);
+    }
+  }
+
+  /**
+   * Quantifies the type over the variables that aren't bound anywhere in the
+   * enclosing scopes.
+   */
+  fn generalize(&self, ty: &Type) -> Scheme {
+    let ty = self.resolve_deep(ty);
+    let mut bound = HashSet::new();
+    for scope in &self.scopes {
+      for binding in scope.values() {
+        if let Binding::Value(ref scheme) = binding {
+          let vars = free_vars(&self.resolve_deep(&scheme.ty));
+          bound.extend(vars.into_iter().filter(|var| !scheme.vars.contains(var)));
+        }
+      }
+    }
+    let mut vars = free_vars(&ty);
+    vars.retain(|var| !bound.contains(var));
+    Scheme { vars, ty }
+  }
+
+  fn instantiate(&mut self, scheme: &Scheme) -> Type {
+    let fresh: HashMap<usize, Type> = scheme
+      .vars
+      .iter()
+      .map(|var| (*var, self.fresh()))
+      .collect();
+    substitute(&scheme.ty, &fresh)
+  }
+
+  //==  Scopes  ================================================================
+
+  fn bind(&mut self, identifier: &Identifier, binding: Binding) {
+    self
+      .scopes
+      .last_mut()
+      .unwrap()
+      .insert(identifier.name.clone(), binding);
+  }
+
+  fn lookup(&self, name: &str) -> Option<&Binding> {
+    self.scopes.iter().rev().find_map(|scope| scope.get(name))
+  }
+
+  /**
+   * The type of the variable. Names the checker doesn't know about (like
+   * globals bound by the host) are dynamic.
+   */
+  fn lookup_type(&mut self, name: &str) -> Type {
+    match self.lookup(name) {
+      Some(Binding::Value(scheme)) => {
+        let scheme = scheme.clone();
+        self.instantiate(&scheme)
+      }
+      _ => Type::Dynamic,
+    }
+  }
+
+  fn annotation_type(&mut self, annotation: &TypeAnnotation) -> Type {
+    match annotation {
+      TypeAnnotation::Named(ref identifier) => match identifier.name.as_str() {
+        "unit" => Type::Unit,
+        "int" => Type::Int,
+        "float" => Type::Float,
+        "str" => Type::Str,
+        "bool" => Type::Bool,
+        "any" => Type::Dynamic,
+        name => match self.lookup(name) {
+          Some(Binding::Enum(_)) => Type::Enum(name.to_owned()),
+          _ => {
+            self.error(format!("Unknown type {}", name), &identifier.source_ref);
+            Type::Dynamic
+          }
+        },
+      },
+      TypeAnnotation::List(ref element) => Type::List(Box::new(self.annotation_type(element))),
+      TypeAnnotation::Function(ref params, ref ret) => Type::Function(
+        params.iter().map(|param| self.annotation_type(param)).collect(),
+        Box::new(self.annotation_type(ret)),
+      ),
+    }
+  }
+
+  //==  Inference  =============================================================
+
+  fn infer_block(&mut self, block: &Block) -> Type {
+    self.scopes.push(HashMap::new());
+    let ty = self.infer_statements(block);
+    self.scopes.pop();
+    ty
+  }
+
+  /**
+   * Infers the block's statements on the current scope, returning the type of
+   * its return expression.
+   */
+  fn infer_statements(&mut self, block: &Block) -> Type {
+    // By the time a function is called the whole block may have run, so its
+    // body sees the block's variables too. Each is bound to a type variable
+    // until its let statement is inferred.
+    let mut variables = vec![];
+    for statement in &block.statements {
+      if let Statement::LetStmt(ref identifier, _, _) = statement {
+        let ty = self.fresh();
+        self.bind(identifier, Binding::Value(Scheme::monomorphic(ty.clone())));
+        variables.push(ty);
+      }
+    }
+    let mut variables = variables.into_iter();
+    // Functions are hoisted like in the VM. Each is bound to a type variable
+    // while their bodies are inferred, so that they can call each other, and
+    // then generalized together.
+    let mut functions = vec![];
+    for statement in &block.statements {
+      if let Statement::FunctionDeclarationStmt(ref identifier, _, _, _) = statement {
+        let ty = self.fresh();
+        self.bind(identifier, Binding::Value(Scheme::monomorphic(ty.clone())));
+        functions.push((identifier, ty));
+      }
+    }
+    for statement in &block.statements {
+      if let Statement::FunctionDeclarationStmt(ref identifier, ref params, ref ret, ref body) =
+        statement
+      {
+        let ty = self.infer_function(params, ret.as_ref(), body, None);
+        let declared = self.lookup_type(&identifier.name);
+        self.expect(&declared, &ty, &identifier.source_ref);
+      }
+    }
+    for (identifier, _) in &functions {
+      self.scopes.last_mut().unwrap().remove(&identifier.name);
+    }
+    let schemes: Vec<Scheme> = functions.iter().map(|(_, ty)| self.generalize(ty)).collect();
+    for ((identifier, _), scheme) in functions.iter().zip(schemes) {
+      self.bind(identifier, Binding::Value(scheme));
+    }
+
+    for statement in &block.statements {
+      match statement {
+        Statement::LetStmt(ref identifier, ref annotation, ref expression) => {
+          // The expression can only use the names declared before it.
+          self.scopes.last_mut().unwrap().remove(&identifier.name);
+          let ty = self.infer_expression(expression, &identifier.source_ref);
+          let source_ref = expression.source_ref().unwrap_or(&identifier.source_ref);
+          let ty = match annotation {
+            Some(annotation) => {
+              let annotated = self.annotation_type(annotation);
+              self.expect(&annotated, &ty, source_ref);
+              annotated
+            }
+            None => ty,
+          };
+          // Functions of the block using the variable must agree with it.
+          let hoisted = variables.next().unwrap();
+          self.expect(&ty, &hoisted, source_ref);
+          let scheme = match annotation {
+            Some(_) => Scheme::monomorphic(ty),
+            // Only function values are polymorphic, other values could later
+            // be assigned to.
+            None if matches!(expression, Expression::FunctionExpr(_, _, _)) => {
+              self.generalize(&ty)
+            }
+            None => Scheme::monomorphic(ty),
+          };
+          self.bind(identifier, Binding::Value(scheme));
+        }
+        Statement::AssignmentStmt(ref identifier, ref expression) => {
+          let ty = self.infer_expression(expression, &identifier.source_ref);
+          let variable = self.lookup_type(&identifier.name);
+          let source_ref = expression.source_ref().unwrap_or(&identifier.source_ref);
+          self.expect(&variable, &ty, source_ref);
+        }
+        Statement::IndexAssignmentStmt(
+          ref identifier,
+          ref indices,
+          ref op,
+          ref expression,
+          ref source_ref,
+        ) => {
+          let mut element = self.lookup_type(&identifier.name);
+          for index in indices {
+            let index_ty = self.infer_expression(index, source_ref);
+            self.expect(&Type::Int, &index_ty, index.source_ref().unwrap_or(source_ref));
+            let list = Type::List(Box::new(self.fresh()));
+            self.expect(&list, &element, &identifier.source_ref);
+            element = match self.resolve(&list) {
+              Type::List(element) => *element,
+              _ => Type::Dynamic,
+            };
+          }
+          let value = self.infer_expression(expression, source_ref);
+          let value = match op {
+            Some(op) => self.bin_op_type(op, &element, &value, source_ref),
+            None => value,
+          };
+          self.expect(&element, &value, source_ref);
+        }
+        Statement::UnusedExprEvalStmt(ref expression) => {
+          self.infer_expression(expression, &block.source_ref);
+        }
+        // Already inferred above.
+        Statement::FunctionDeclarationStmt(_, _, _, _) => {}
+        Statement::EnumDeclarationStmt(ref identifier, ref variants) => {
+          self.bind(identifier, Binding::Enum(variants.clone()));
+        }
+        // The members of modules aren't checked.
+        Statement::ImportStmt(_, ref alias, _) => self.bind(alias, Binding::Module),
+      }
+    }
+    self.infer_expression(&block.return_expression, &block.source_ref)
+  }
+
+  /**
+   * Infers the type of a function. Unannotated parameters take their type from
+   * the expected function type, when there is one.
+   */
+  fn infer_function(
+    &mut self,
+    params: &[Param],
+    ret: Option<&TypeAnnotation>,
+    body: &Block,
+    expected: Option<Type>,
+  ) -> Type {
+    let expected_params = match expected {
+      Some(Type::Function(expected_params, _)) if expected_params.len() == params.len() => {
+        expected_params
+      }
+      _ => vec![],
+    };
+    self.scopes.push(HashMap::new());
+    let mut param_types = vec![];
+    for (i, param) in params.iter().enumerate() {
+      let ty = match (&param.annotation, expected_params.get(i)) {
+        (Some(ref annotation), _) => self.annotation_type(annotation),
+        (None, Some(expected)) => expected.clone(),
+        (None, None) => self.fresh(),
+      };
+      self.bind(&param.name, Binding::Value(Scheme::monomorphic(ty.clone())));
+      param_types.push(ty);
+    }
+    let ret = match ret {
+      Some(annotation) => self.annotation_type(annotation),
+      None => self.fresh(),
+    };
+    let body_ty = self.infer_block(body);
+    let source_ref = body.return_expression.source_ref().unwrap_or(&body.source_ref);
+    self.expect(&ret, &body_ty, source_ref);
+    self.scopes.pop();
+    Type::Function(param_types, Box::new(ret))
+  }
+
+  /**
+   * Infers the type of the expression. Errors in expressions that don't record
+   * a location are reported at the enclosing one.
+   */
+  fn infer_expression(&mut self, expression: &Expression, enclosing: &SourceRef) -> Type {
+    let at = expression.source_ref().unwrap_or(enclosing);
+    match expression {
+      Expression::LiteralExpr(ref literal) => literal_type(literal),
+      Expression::BinExpr(ref l, BinOp::Pipe, ref r, ref source_ref) => {
+        let l = self.infer_expression(l, at);
+        // Either a call to splice the left value into, or a bare function.
+        match **r {
+          Expression::FunctionInvokeExpr(ref callee, ref args, _) => {
+            let callee = self.infer_expression(callee, at);
+            let arg_types = self.infer_args(&callee, Some(l), args, at);
+            self.call_type(&callee, &arg_types, source_ref)
+          }
+          _ => {
+            let callee = self.infer_expression(r, at);
+            self.call_type(&callee, &[l], source_ref)
+          }
+        }
+      }
+      Expression::BinExpr(ref l, ref op, ref r, ref source_ref) => {
+        let l = self.infer_expression(l, at);
+        let r = self.infer_expression(r, at);
+        self.bin_op_type(op, &l, &r, source_ref)
+      }
+      Expression::UnaryExpr(ref op, ref operand, ref source_ref) => {
+        let operand = self.infer_expression(operand, at);
+        match (op, self.resolve(&operand)) {
+          (UnOp::Neg, Type::Int) => Type::Int,
+          (UnOp::Neg, Type::Float) => Type::Float,
+          // May be either an int or a float.
+          (UnOp::Neg, Type::Var(_)) | (UnOp::Neg, Type::Dynamic) => Type::Dynamic,
+          (UnOp::Neg, operand) => {
+            self.error(format!("Cannot apply {:?} to {}", op, operand), source_ref);
+            Type::Dynamic
+          }
+          (UnOp::Not, _) => {
+            self.expect(&Type::Bool, &operand, source_ref);
+            Type::Bool
+          }
+          (UnOp::BitNot, _) => {
+            self.expect(&Type::Int, &operand, source_ref);
+            Type::Int
+          }
+        }
+      }
+      Expression::BlockExpr(ref block) => self.infer_block(block),
+      Expression::IdentifierDerefExpr(ref identifier) => self.lookup_type(&identifier.name),
+      Expression::FunctionInvokeExpr(ref callee, ref args, ref source_ref) => {
+        let callee = self.infer_expression(callee, at);
+        let arg_types = self.infer_args(&callee, None, args, at);
+        self.call_type(&callee, &arg_types, source_ref)
+      }
+      Expression::ListExpr(ref items) => {
+        // Lists may mix types (as tuples often do), their elements are then
+        // dynamic.
+        let mut element = self.fresh();
+        for item in items {
+          let ty = self.infer_expression(item, at);
+          if !self.try_unify(&element, &ty) {
+            element = Type::Dynamic;
+          }
+        }
+        Type::List(Box::new(element))
+      }
+      Expression::IndexExpr(ref list, ref index, ref source_ref) => {
+        let list = self.infer_expression(list, at);
+        let index = self.infer_expression(index, at);
+        self.expect(&Type::Int, &index, source_ref);
+        let element = self.fresh();
+        self.expect(&Type::List(Box::new(element.clone())), &list, source_ref);
+        element
+      }
+      Expression::FunctionExpr(ref params, ref ret, ref body) => {
+        self.infer_function(params, ret.as_ref(), body, None)
+      }
+      Expression::IfElseExpr(ref condition, ref then_block, ref else_block, ref source_ref) => {
+        let condition_ty = self.infer_expression(condition, at);
+        let condition_ref = condition.source_ref().unwrap_or(source_ref);
+        self.expect(&Type::Bool, &condition_ty, condition_ref);
+        let then_ty = self.infer_block(then_block);
+        match else_block {
+          Some(else_block) => {
+            let else_ty = self.infer_block(else_block);
+            self.join(&then_ty, &else_ty)
+          }
+          // The value is unit when the condition is false.
+          None => self.join(&then_ty, &Type::Unit),
+        }
+      }
+      Expression::PathExpr(ref identifier, ref name) => match self.lookup(&identifier.name).cloned() {
+        Some(Binding::Enum(variants)) => match variants.iter().find(|v| v.name == *name) {
+          Some(variant) if variant.fields.is_empty() => Type::Enum(identifier.name.clone()),
+          // Payloads aren't typed.
+          Some(variant) => Type::Function(
+            vec![Type::Dynamic; variant.fields.len()],
+            Box::new(Type::Enum(identifier.name.clone())),
+          ),
+          None => {
+            let message = format!("Enum {} has no variant {}", identifier.name, name.name);
+            self.error(message, &name.source_ref);
+            Type::Dynamic
+          }
+        },
+        _ => Type::Dynamic,
+      },
+      Expression::MatchExpr(ref subject, ref arms, ref source_ref) => {
+        let subject = self.infer_expression(subject, at);
+        let mut ty: Option<Type> = None;
+        for arm in arms {
+          self.scopes.push(HashMap::new());
+          self.bind_pattern(&arm.pattern, &subject, source_ref);
+          let arm_ty = self.infer_expression(&arm.expression, source_ref);
+          self.scopes.pop();
+          ty = Some(match ty {
+            Some(ty) => self.join(&ty, &arm_ty),
+            None => arm_ty,
+          });
+        }
+        ty.unwrap_or(Type::Dynamic)
+      }
+    }
+  }
+
+  /**
+   * The type of a value that is one of the two types, dynamic when they differ.
+   */
+  fn join(&mut self, a: &Type, b: &Type) -> Type {
+    if self.try_unify(a, b) {
+      a.clone()
+    } else {
+      Type::Dynamic
+    }
+  }
+
+  fn bind_pattern(&mut self, pattern: &Pattern, subject: &Type, source_ref: &SourceRef) {
+    match pattern {
+      Pattern::Wildcard => {}
+      Pattern::Literal(ref literal) => self.expect(subject, &literal_type(literal), source_ref),
+      Pattern::Binding(ref identifier) => {
+        self.bind(identifier, Binding::Value(Scheme::monomorphic(subject.clone())))
+      }
+      Pattern::EnumVariant(ref name, _, ref patterns) => {
+        if let Some(Binding::Enum(_)) = self.lookup(&name.name).cloned() {
+          self.expect(subject, &Type::Enum(name.name.clone()), &name.source_ref);
+        }
+        for pattern in patterns {
+          self.bind_pattern(pattern, &Type::Dynamic, source_ref);
+        }
+      }
+    }
+  }
+
+  /**
+   * Infers the types of the arguments to a call, prefixed by the piped value
+   * (if any). Function literals are inferred last, once the other arguments
+   * have been unified with the callee's parameters, so that the types of their
+   * own parameters can be taken from what the callee passes them.
+   */
+  fn infer_args(
+    &mut self,
+    callee: &Type,
+    piped: Option<Type>,
+    args: &[Expression],
+    at: &SourceRef,
+  ) -> Vec<Type> {
+    let params = match self.resolve(callee) {
+      Type::Function(params, _) => params,
+      _ => vec![],
+    };
+    let mut types: Vec<Option<Type>> = piped.into_iter().map(Some).collect();
+    let offset = types.len();
+    for arg in args {
+      types.push(match arg {
+        Expression::FunctionExpr(_, _, _) => None,
+        arg => Some(self.infer_expression(arg, at)),
+      });
+    }
+    for (param, ty) in params.iter().zip(&types) {
+      if let Some(ty) = ty {
+        self.try_unify(param, ty);
+      }
+    }
+    for (i, arg) in args.iter().enumerate() {
+      if let Expression::FunctionExpr(ref fn_params, ref ret, ref body) = arg {
+        let expected = params.get(offset + i).map(|param| self.resolve_deep(param));
+        types[offset + i] = Some(self.infer_function(fn_params, ret.as_ref(), body, expected));
+      }
+    }
+    types.into_iter().map(Option::unwrap).collect()
+  }
+
+  fn call_type(&mut self, callee: &Type, args: &[Type], source_ref: &SourceRef) -> Type {
+    match self.resolve(callee) {
+      Type::Dynamic => Type::Dynamic,
+      Type::Function(ref params, ref ret) if params.len() != args.len() => {
+        let message = format!(
+          "Function takes {} arguments but {} were given",
+          params.len(),
+          args.len()
+        );
+        self.error(message, source_ref);
+        (**ret).clone()
+      }
+      Type::Function(ref params, ref ret) => {
+        for (param, arg) in params.iter().zip(args) {
+          self.expect(param, arg, source_ref);
+        }
+        (**ret).clone()
+      }
+      Type::Var(_) => {
+        let ret = self.fresh();
+        let function = Type::Function(args.to_vec(), Box::new(ret.clone()));
+        self.expect(callee, &function, source_ref);
+        ret
+      }
+      callee => {
+        self.error(format!("Cannot call a value of type {}", callee), source_ref);
+        Type::Dynamic
+      }
+    }
+  }
+
+  fn bin_op_type(&mut self, op: &BinOp, l: &Type, r: &Type, source_ref: &SourceRef) -> Type {
+    let (l, r) = (self.resolve(l), self.resolve(r));
+    let unknown = |ty: &Type| matches!(ty, Type::Var(_) | Type::Dynamic);
+    let numeric = |ty: &Type| matches!(ty, Type::Int | Type::Float);
+    let sequence = |ty: &Type| matches!(ty, Type::Str | Type::List(_));
+    let result = match op {
+      BinOp::And | BinOp::Or => {
+        self.expect(&Type::Bool, &l, source_ref);
+        self.expect(&Type::Bool, &r, source_ref);
+        return Type::Bool;
+      }
+      BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr => {
+        self.expect(&Type::Int, &l, source_ref);
+        self.expect(&Type::Int, &r, source_ref);
+        return Type::Int;
+      }
+      BinOp::Eql | BinOp::Neq | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+        let equality = matches!(op, BinOp::Eql | BinOp::Neq);
+        match (&l, &r) {
+          (l, r) if unknown(l) || unknown(r) => Some(Type::Bool),
+          (l, r) if numeric(l) && numeric(r) => Some(Type::Bool),
+          (Type::Str, Type::Str) => Some(Type::Bool),
+          (Type::Bool, Type::Bool) if equality => Some(Type::Bool),
+          _ => None,
+        }
+      }
+      BinOp::Plus | BinOp::Minus | BinOp::Star | BinOp::Slash | BinOp::Mod => {
+        let plus = matches!(op, BinOp::Plus);
+        match (&l, &r) {
+          (Type::Int, Type::Int) => Some(Type::Int),
+          (l, r) if numeric(l) && numeric(r) => Some(Type::Float),
+          (Type::Str, Type::Str) if plus => Some(Type::Str),
+          // Concatenating lists of different types makes a list of both.
+          (Type::List(_), Type::List(_)) if plus => Some(self.join(&l, &r)),
+          (l, r) if unknown(l) && unknown(r) => Some(Type::Dynamic),
+          // Strs and lists can only be added to their own type.
+          (l, r) if plus && (unknown(l) || unknown(r)) && (sequence(l) || sequence(r)) => {
+            let (known, other) = if unknown(l) { (r, l) } else { (l, r) };
+            self.expect(known, other, source_ref);
+            Some(known.clone())
+          }
+          // A float operand makes the result a float, otherwise the unknown
+          // operand may be either.
+          (Type::Float, other) | (other, Type::Float) if unknown(other) => Some(Type::Float),
+          (Type::Int, other) | (other, Type::Int) if unknown(other) => Some(Type::Dynamic),
+          _ => None,
+        }
+      }
+      BinOp::Pipe => unreachable!("pipes are inferred as calls"),
+    };
+    match result {
+      Some(ty) => ty,
+      None => {
+        let message = format!("Cannot apply {:?} to {} and {}", op, l, r);
+        self.error(message, source_ref);
+        Type::Dynamic
+      }
+    }
+  }
+}
+
+fn literal_type(literal: &LiteralValue) -> Type {
+  match literal {
+    LiteralValue::Unit => Type::Unit,
+    LiteralValue::Int64(_) | LiteralValue::BigInt(_) => Type::Int,
+    LiteralValue::Float64(_) => Type::Float,
+    LiteralValue::Str(_) => Type::Str,
+    LiteralValue::Bool(_) => Type::Bool,
+  }
+}
+
+fn free_vars(ty: &Type) -> Vec<usize> {
+  match ty {
+    Type::Var(var) => vec![*var],
+    Type::List(element) => free_vars(element),
+    Type::Function(params, ret) => {
+      let mut vars: Vec<usize> = params.iter().flat_map(free_vars).collect();
+      for var in free_vars(ret) {
+        if !vars.contains(&var) {
+          vars.push(var);
+        }
+      }
+      vars.dedup();
+      vars
+    }
+    _ => vec![],
+  }
+}
+
+fn substitute(ty: &Type, vars: &HashMap<usize, Type>) -> Type {
+  match ty {
+    Type::Var(var) => vars.get(var).cloned().unwrap_or(Type::Var(*var)),
+    Type::List(element) => Type::List(Box::new(substitute(element, vars))),
+    Type::Function(params, ret) => Type::Function(
+      params.iter().map(|param| substitute(param, vars)).collect(),
+      Box::new(substitute(ret, vars)),
+    ),
+    ty => ty.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /**
+   * Type checks the source, returning each error message along with the
+   * source text it points at.
+   */
+  fn check(source: &str) -> Vec<(String, String)> {
+    let program = ProgramParser::new().parse(0, source).unwrap();
+    check_program(&program)
+      .into_iter()
+      .map(|error| {
+        let source_ref = error.source_ref().unwrap();
+        let at = &source[source_ref.left as usize..source_ref.right as usize];
+        (error.message().to_owned(), at.to_owned())
+      })
+      .collect()
+  }
+
+  fn error(message: &str, at: &str) -> Vec<(String, String)> {
+    vec![(message.to_owned(), at.to_owned())]
+  }
+
+  #[test]
+  fn functions_see_the_variables_of_their_block() {
+    // The global shadows the prelude's max inside main too.
+    assert_eq!(check("let max = 10;\nfn main(args) { max + 1 }"), vec![]);
+    assert_eq!(check("fn next() { count + 1 } let count = 1; next() * 2"), vec![]);
+    assert_eq!(
+      check("let max = 10;\nfn main(args) { max(1, 2) }"),
+      error("Mismatched types, expected int, found fn(int, int) -> _", "max")
+    );
+    // A let's own expression still uses the name declared before it.
+    assert_eq!(check("let max = max(1, 2); max + 1"), vec![]);
+  }
+
+  #[test]
+  fn prelude_and_unannotated_programs_check() {
+    assert_eq!(check(PRELUDE), vec![]);
+    assert_eq!(check(include_str!("../sample.at")), vec![]);
+    assert_eq!(
+      check(
+        "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+         let total = sum(map(range(0, 10), fib)) + max(1, 2);
+         let pair = [\"part1\", 42];
+         let mixed = 1 + 0.5;
+         println(trim(\" a \") + to_string(total));
+         \"done\" |> len"
+      ),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn operator_mismatches() {
+    assert_eq!(check("1 + true"), error("Cannot apply Plus to int and bool", "1 + true"));
+    assert_eq!(check("\"a\" < 1"), error("Cannot apply Lt to str and int", "\"a\" < 1"));
+    assert_eq!(
+      check("let x = 1; !x"),
+      error("Mismatched types, expected bool, found int", "!x")
+    );
+    assert_eq!(
+      check("if 1 { 2 }"),
+      error("Mismatched types, expected bool, found int", "if 1 { 2 }")
+    );
+    assert_eq!(
+      check("let xs = [1, 2]; xs[0] = \"one\";"),
+      error("Mismatched types, expected int, found str", "xs[0] = \"one\"")
+    );
+  }
+
+  #[test]
+  fn calls() {
+    assert_eq!(check("let x = 1; x(2)"), error("Cannot call a value of type int", "x(2)"));
+    assert_eq!(
+      check("fn add(a, b) { a + b } add(1)"),
+      error("Function takes 2 arguments but 1 were given", "add(1)")
+    );
+    // Inferred through higher order functions.
+    assert_eq!(
+      check("fn twice(f, x) { f(f(x)) } twice(fn (s) { s + \"!\" }, 1)"),
+      error("Cannot apply Plus to int and str", "s + \"!\"")
+    );
+    assert_eq!(
+      check("fn greet(name) { \"Hello \" + name } greet(42)"),
+      error("Mismatched types, expected str, found int", "greet(42)")
+    );
+    assert_eq!(
+      check("map(lines(\"a\"), fn (line) { line * 2 })"),
+      error("Cannot apply Star to str and int", "line * 2")
+    );
+  }
+
+  #[test]
+  fn annotations() {
+    assert_eq!(
+      check("fn add(a: int, b: int) -> int { a + b } add(1, \"two\")"),
+      error("Mismatched types, expected int, found str", "add(1, \"two\")")
+    );
+    assert_eq!(
+      check("let x: str = 1 + 2;"),
+      error("Mismatched types, expected str, found int", "1 + 2")
+    );
+    assert_eq!(
+      check("fn name() -> str { 42 }"),
+      error("Mismatched types, expected str, found int", "{ 42 }")
+    );
+    assert_eq!(check("let x: foo = 1;"), error("Unknown type foo", "foo"));
+    assert_eq!(
+      check("let f: fn(int) -> [int] = fn (x) { [x] }; let ys: [str] = f(1);"),
+      error("Mismatched types, expected [str], found [int]", "f(1)")
+    );
+    assert_eq!(
+      check("enum Shape { Circle(r), Empty } let s: Shape = Shape::Circle(1); let n: int = s;"),
+      error("Mismatched types, expected int, found Shape", "s")
+    );
+    // Unannotated parameters are inferred from the annotated ones.
+    assert_eq!(
+      check("let scale = fn (x, factor: float) { x * factor }; let s: str = scale(1, 2.0);"),
+      error("Mismatched types, expected str, found float", "scale(1, 2.0)")
+    );
+  }
+
+  #[test]
+  fn functions_are_polymorphic() {
+    assert_eq!(
+      check("fn id(x) { x } let n: int = id(1); let s: str = id(\"one\");"),
+      vec![]
+    );
+    assert_eq!(
+      check("let first = fn (xs) { xs[0] }; first([1]) + 1; first([\"a\"]) + \"b\""),
+      vec![]
+    );
+    // Variables are not, their type is fixed once inferred.
+    assert_eq!(
+      check("let x = 1; x = \"one\";"),
+      error("Mismatched types, expected int, found str", "x")
+    );
+  }
+
+  #[test]
+  fn undecidable_code_is_dynamic() {
+    // The result may be an int or a float.
+    assert_eq!(check("fn half(x) { x / 2 } let s: str = half(1);"), vec![]);
+    // Branches of different types.
+    assert_eq!(check("let x = if true { 1 } else { \"one\" }; x + 1; len(x)"), vec![]);
+    // Unknown globals and module members.
+    assert_eq!(check("host_value + 1; import \"m.at\" as m; m::f(1)(2)"), vec![]);
+  }
+}
@@ -0,0 +1,600 @@
+use super::ast::*;
+
+/**
+ * A read-only traversal of the AST. Every visit method defaults to walking the
+ * children of the node (with the matching walk function), so a pass only
+ * overrides the nodes it cares about, calling the walk function itself if it
+ * wants to keep descending. The 'ast lifetime lets passes keep references to
+ * the nodes they visit.
+ */
+pub trait Visitor<'ast> {
+  fn visit_block(&mut self, block: &'ast Block) {
+    walk_block(self, block)
+  }
+
+  fn visit_statement(&mut self, statement: &'ast Statement) {
+    walk_statement(self, statement)
+  }
+
+  fn visit_expression(&mut self, expression: &'ast Expression) {
+    walk_expression(self, expression)
+  }
+
+  /**
+   * Both declared functions and function expressions. The name is only set
+   * for declarations.
+   */
+  fn visit_function(
+    &mut self,
+    name: Option<&'ast Identifier>,
+    params: &'ast [Param],
+    return_type: Option<&'ast TypeAnnotation>,
+    body: &'ast Block,
+  ) {
+    walk_function(self, name, params, return_type, body)
+  }
+
+  fn visit_param(&mut self, param: &'ast Param) {
+    walk_param(self, param)
+  }
+
+  fn visit_type_annotation(&mut self, annotation: &'ast TypeAnnotation) {
+    walk_type_annotation(self, annotation)
+  }
+
+  fn visit_enum_variant(&mut self, variant: &'ast EnumVariant) {
+    walk_enum_variant(self, variant)
+  }
+
+  fn visit_match_arm(&mut self, arm: &'ast MatchArm) {
+    walk_match_arm(self, arm)
+  }
+
+  fn visit_pattern(&mut self, pattern: &'ast Pattern) {
+    walk_pattern(self, pattern)
+  }
+
+  fn visit_identifier(&mut self, _identifier: &'ast Identifier) {}
+
+  fn visit_literal(&mut self, _literal: &'ast LiteralValue) {}
+}
+
+pub fn walk_block<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, block: &'ast Block) {
+  for statement in &block.statements {
+    visitor.visit_statement(statement);
+  }
+  visitor.visit_expression(&block.return_expression);
+}
+
+pub fn walk_statement<'ast, V: Visitor<'ast> + ?Sized>(
+  visitor: &mut V,
+  statement: &'ast Statement,
+) {
+  match statement {
+    Statement::LetStmt(ref identifier, ref annotation, ref expression) => {
+      visitor.visit_identifier(identifier);
+      if let Some(annotation) = annotation {
+        visitor.visit_type_annotation(annotation);
+      }
+      visitor.visit_expression(expression);
+    }
+    Statement::AssignmentStmt(ref identifier, ref expression) => {
+      visitor.visit_identifier(identifier);
+      visitor.visit_expression(expression);
+    }
+    Statement::IndexAssignmentStmt(ref identifier, ref indices, _, ref expression, _) => {
+      visitor.visit_identifier(identifier);
+      for index in indices {
+        visitor.visit_expression(index);
+      }
+      visitor.visit_expression(expression);
+    }
+    Statement::UnusedExprEvalStmt(ref expression) => visitor.visit_expression(expression),
+    Statement::FunctionDeclarationStmt(ref name, ref params, ref return_type, ref body) => {
+      visitor.visit_function(Some(name), params, return_type.as_ref(), body)
+    }
+    Statement::EnumDeclarationStmt(ref identifier, ref variants) => {
+      visitor.visit_identifier(identifier);
+      for variant in variants {
+        visitor.visit_enum_variant(variant);
+      }
+    }
+    Statement::ImportStmt(_, ref alias, _) => visitor.visit_identifier(alias),
+  }
+}
+
+pub fn walk_expression<'ast, V: Visitor<'ast> + ?Sized>(
+  visitor: &mut V,
+  expression: &'ast Expression,
+) {
+  match expression {
+    Expression::LiteralExpr(ref literal) => visitor.visit_literal(literal),
+    Expression::IdentifierDerefExpr(ref identifier) => visitor.visit_identifier(identifier),
+    Expression::PathExpr(ref module, ref name) => {
+      visitor.visit_identifier(module);
+      visitor.visit_identifier(name);
+    }
+    Expression::BinExpr(ref l, _, ref r, _) => {
+      visitor.visit_expression(l);
+      visitor.visit_expression(r);
+    }
+    Expression::UnaryExpr(_, ref operand, _) => visitor.visit_expression(operand),
+    Expression::BlockExpr(ref block) => visitor.visit_block(block),
+    Expression::FunctionInvokeExpr(ref callee, ref args, _) => {
+      visitor.visit_expression(callee);
+      for arg in args {
+        visitor.visit_expression(arg);
+      }
+    }
+    Expression::ListExpr(ref items) => {
+      for item in items {
+        visitor.visit_expression(item);
+      }
+    }
+    Expression::IndexExpr(ref list, ref index, _) => {
+      visitor.visit_expression(list);
+      visitor.visit_expression(index);
+    }
+    Expression::FunctionExpr(ref params, ref return_type, ref body) => {
+      visitor.visit_function(None, params, return_type.as_ref(), body)
+    }
+    Expression::IfElseExpr(ref condition, ref then_block, ref else_block, _) => {
+      visitor.visit_expression(condition);
+      visitor.visit_block(then_block);
+      if let Some(else_block) = else_block {
+        visitor.visit_block(else_block);
+      }
+    }
+    Expression::MatchExpr(ref subject, ref arms, _) => {
+      visitor.visit_expression(subject);
+      for arm in arms {
+        visitor.visit_match_arm(arm);
+      }
+    }
+  }
+}
+
+pub fn walk_function<'ast, V: Visitor<'ast> + ?Sized>(
+  visitor: &mut V,
+  name: Option<&'ast Identifier>,
+  params: &'ast [Param],
+  return_type: Option<&'ast TypeAnnotation>,
+  body: &'ast Block,
+) {
+  if let Some(name) = name {
+    visitor.visit_identifier(name);
+  }
+  for param in params {
+    visitor.visit_param(param);
+  }
+  if let Some(return_type) = return_type {
+    visitor.visit_type_annotation(return_type);
+  }
+  visitor.visit_block(body)
+}
+
+pub fn walk_param<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, param: &'ast Param) {
+  visitor.visit_identifier(&param.name);
+  if let Some(ref annotation) = param.annotation {
+    visitor.visit_type_annotation(annotation);
+  }
+}
+
+pub fn walk_type_annotation<'ast, V: Visitor<'ast> + ?Sized>(
+  visitor: &mut V,
+  annotation: &'ast TypeAnnotation,
+) {
+  match annotation {
+    TypeAnnotation::Named(ref identifier) => visitor.visit_identifier(identifier),
+    TypeAnnotation::List(ref element) => visitor.visit_type_annotation(element),
+    TypeAnnotation::Function(ref params, ref return_type) => {
+      for param in params {
+        visitor.visit_type_annotation(param);
+      }
+      visitor.visit_type_annotation(return_type);
+    }
+  }
+}
+
+pub fn walk_enum_variant<'ast, V: Visitor<'ast> + ?Sized>(
+  visitor: &mut V,
+  variant: &'ast EnumVariant,
+) {
+  visitor.visit_identifier(&variant.name);
+  for field in &variant.fields {
+    visitor.visit_identifier(field);
+  }
+}
+
+pub fn walk_match_arm<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, arm: &'ast MatchArm) {
+  visitor.visit_pattern(&arm.pattern);
+  visitor.visit_expression(&arm.expression);
+}
+
+pub fn walk_pattern<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, pattern: &'ast Pattern) {
+  match pattern {
+    Pattern::Wildcard => {}
+    Pattern::Literal(ref literal) => visitor.visit_literal(literal),
+    Pattern::Binding(ref identifier) => visitor.visit_identifier(identifier),
+    Pattern::EnumVariant(ref name, ref variant, ref patterns) => {
+      visitor.visit_identifier(name);
+      visitor.visit_identifier(variant);
+      for pattern in patterns {
+        visitor.visit_pattern(pattern);
+      }
+    }
+  }
+}
+
+/**
+ * A rewriting traversal of the AST, taking each node by value and returning
+ * its replacement. Like the Visitor, every fold method defaults to rebuilding
+ * the node from its folded children (with the matching fold_*_children
+ * function), and leaves are returned as they are.
+ */
+pub trait Folder {
+  fn fold_block(&mut self, block: Block) -> Block {
+    fold_block_children(self, block)
+  }
+
+  fn fold_statement(&mut self, statement: Statement) -> Statement {
+    fold_statement_children(self, statement)
+  }
+
+  fn fold_expression(&mut self, expression: Expression) -> Expression {
+    fold_expression_children(self, expression)
+  }
+
+  fn fold_param(&mut self, param: Param) -> Param {
+    Param {
+      name: self.fold_identifier(param.name),
+      annotation: param.annotation.map(|a| self.fold_type_annotation(a)),
+    }
+  }
+
+  fn fold_type_annotation(&mut self, annotation: TypeAnnotation) -> TypeAnnotation {
+    fold_type_annotation_children(self, annotation)
+  }
+
+  fn fold_enum_variant(&mut self, variant: EnumVariant) -> EnumVariant {
+    EnumVariant {
+      name: self.fold_identifier(variant.name),
+      fields: variant
+        .fields
+        .into_iter()
+        .map(|field| self.fold_identifier(field))
+        .collect(),
+    }
+  }
+
+  fn fold_match_arm(&mut self, arm: MatchArm) -> MatchArm {
+    MatchArm {
+      pattern: self.fold_pattern(arm.pattern),
+      expression: self.fold_expression(arm.expression),
+    }
+  }
+
+  fn fold_pattern(&mut self, pattern: Pattern) -> Pattern {
+    fold_pattern_children(self, pattern)
+  }
+
+  fn fold_identifier(&mut self, identifier: Identifier) -> Identifier {
+    identifier
+  }
+
+  fn fold_literal(&mut self, literal: LiteralValue) -> LiteralValue {
+    literal
+  }
+}
+
+pub fn fold_block_children<F: Folder + ?Sized>(folder: &mut F, block: Block) -> Block {
+  let statements = block
+    .statements
+    .into_iter()
+    .map(|statement| folder.fold_statement(statement))
+    .collect();
+  let return_expression = folder.fold_expression(block.return_expression);
+  Block::new(statements, return_expression, block.source_ref)
+}
+
+pub fn fold_statement_children<F: Folder + ?Sized>(
+  folder: &mut F,
+  statement: Statement,
+) -> Statement {
+  match statement {
+    Statement::LetStmt(identifier, annotation, expression) => Statement::LetStmt(
+      folder.fold_identifier(identifier),
+      annotation.map(|a| folder.fold_type_annotation(a)),
+      folder.fold_expression(expression),
+    ),
+    Statement::AssignmentStmt(identifier, expression) => Statement::AssignmentStmt(
+      folder.fold_identifier(identifier),
+      folder.fold_expression(expression),
+    ),
+    Statement::IndexAssignmentStmt(identifier, indices, op, expression, source_ref) => {
+      Statement::IndexAssignmentStmt(
+        folder.fold_identifier(identifier),
+        fold_expressions(folder, indices),
+        op,
+        folder.fold_expression(expression),
+        source_ref,
+      )
+    }
+    Statement::UnusedExprEvalStmt(expression) => {
+      Statement::UnusedExprEvalStmt(folder.fold_expression(expression))
+    }
+    Statement::FunctionDeclarationStmt(name, params, return_type, body) => {
+      Statement::FunctionDeclarationStmt(
+        folder.fold_identifier(name),
+        params.into_iter().map(|p| folder.fold_param(p)).collect(),
+        return_type.map(|r| folder.fold_type_annotation(r)),
+        Box::new(folder.fold_block(*body)),
+      )
+    }
+    Statement::EnumDeclarationStmt(identifier, variants) => Statement::EnumDeclarationStmt(
+      folder.fold_identifier(identifier),
+      variants
+        .into_iter()
+        .map(|v| folder.fold_enum_variant(v))
+        .collect(),
+    ),
+    Statement::ImportStmt(path, alias, source_ref) => {
+      Statement::ImportStmt(path, folder.fold_identifier(alias), source_ref)
+    }
+  }
+}
+
+pub fn fold_expression_children<F: Folder + ?Sized>(
+  folder: &mut F,
+  expression: Expression,
+) -> Expression {
+  match expression {
+    Expression::LiteralExpr(literal) => Expression::LiteralExpr(folder.fold_literal(literal)),
+    Expression::IdentifierDerefExpr(identifier) => {
+      Expression::IdentifierDerefExpr(folder.fold_identifier(identifier))
+    }
+    Expression::PathExpr(module, name) => {
+      Expression::PathExpr(folder.fold_identifier(module), folder.fold_identifier(name))
+    }
+    Expression::BinExpr(l, op, r, source_ref) => Expression::BinExpr(
+      Box::new(folder.fold_expression(*l)),
+      op,
+      Box::new(folder.fold_expression(*r)),
+      source_ref,
+    ),
+    Expression::UnaryExpr(op, operand, source_ref) => {
+      Expression::UnaryExpr(op, Box::new(folder.fold_expression(*operand)), source_ref)
+    }
+    Expression::BlockExpr(block) => Expression::BlockExpr(Box::new(folder.fold_block(*block))),
+    Expression::FunctionInvokeExpr(callee, args, source_ref) => Expression::FunctionInvokeExpr(
+      Box::new(folder.fold_expression(*callee)),
+      fold_expressions(folder, args),
+      source_ref,
+    ),
+    Expression::ListExpr(items) => Expression::ListExpr(fold_expressions(folder, items)),
+    Expression::IndexExpr(list, index, source_ref) => Expression::IndexExpr(
+      Box::new(folder.fold_expression(*list)),
+      Box::new(folder.fold_expression(*index)),
+      source_ref,
+    ),
+    Expression::FunctionExpr(params, return_type, body) => Expression::FunctionExpr(
+      params.into_iter().map(|p| folder.fold_param(p)).collect(),
+      return_type.map(|r| folder.fold_type_annotation(r)),
+      Box::new(folder.fold_block(*body)),
+    ),
+    Expression::IfElseExpr(condition, then_block, else_block, source_ref) => {
+      Expression::IfElseExpr(
+        Box::new(folder.fold_expression(*condition)),
+        Box::new(folder.fold_block(*then_block)),
+        else_block.map(|else_block| Box::new(folder.fold_block(*else_block))),
+        source_ref,
+      )
+    }
+    Expression::MatchExpr(subject, arms, source_ref) => Expression::MatchExpr(
+      Box::new(folder.fold_expression(*subject)),
+      arms.into_iter().map(|a| folder.fold_match_arm(a)).collect(),
+      source_ref,
+    ),
+  }
+}
+
+pub fn fold_type_annotation_children<F: Folder + ?Sized>(
+  folder: &mut F,
+  annotation: TypeAnnotation,
+) -> TypeAnnotation {
+  match annotation {
+    TypeAnnotation::Named(identifier) => TypeAnnotation::Named(folder.fold_identifier(identifier)),
+    TypeAnnotation::List(element) => {
+      TypeAnnotation::List(Box::new(folder.fold_type_annotation(*element)))
+    }
+    TypeAnnotation::Function(params, return_type) => TypeAnnotation::Function(
+      params
+        .into_iter()
+        .map(|p| folder.fold_type_annotation(p))
+        .collect(),
+      Box::new(folder.fold_type_annotation(*return_type)),
+    ),
+  }
+}
+
+pub fn fold_pattern_children<F: Folder + ?Sized>(folder: &mut F, pattern: Pattern) -> Pattern {
+  match pattern {
+    Pattern::Wildcard => Pattern::Wildcard,
+    Pattern::Literal(literal) => Pattern::Literal(folder.fold_literal(literal)),
+    Pattern::Binding(identifier) => Pattern::Binding(folder.fold_identifier(identifier)),
+    Pattern::EnumVariant(name, variant, patterns) => Pattern::EnumVariant(
+      folder.fold_identifier(name),
+      folder.fold_identifier(variant),
+      patterns
+        .into_iter()
+        .map(|p| folder.fold_pattern(p))
+        .collect(),
+    ),
+  }
+}
+
+fn fold_expressions<F: Folder + ?Sized>(
+  folder: &mut F,
+  expressions: Vec<Expression>,
+) -> Vec<Expression> {
+  expressions
+    .into_iter()
+    .map(|expression| folder.fold_expression(expression))
+    .collect()
+}
+
+/**
+ * Counts every node of the block (itself included): blocks, statements,
+ * expressions, params, type annotations, enum variants, match arms, patterns,
+ * identifiers and literals.
+ */
+pub fn count_nodes(block: &Block) -> usize {
+  struct NodeCounter(usize);
+
+  impl<'ast> Visitor<'ast> for NodeCounter {
+    fn visit_block(&mut self, block: &'ast Block) {
+      self.0 += 1;
+      walk_block(self, block)
+    }
+
+    fn visit_statement(&mut self, statement: &'ast Statement) {
+      self.0 += 1;
+      walk_statement(self, statement)
+    }
+
+    fn visit_expression(&mut self, expression: &'ast Expression) {
+      self.0 += 1;
+      walk_expression(self, expression)
+    }
+
+    fn visit_param(&mut self, param: &'ast Param) {
+      self.0 += 1;
+      walk_param(self, param)
+    }
+
+    fn visit_type_annotation(&mut self, annotation: &'ast TypeAnnotation) {
+      self.0 += 1;
+      walk_type_annotation(self, annotation)
+    }
+
+    fn visit_enum_variant(&mut self, variant: &'ast EnumVariant) {
+      self.0 += 1;
+      walk_enum_variant(self, variant)
+    }
+
+    fn visit_match_arm(&mut self, arm: &'ast MatchArm) {
+      self.0 += 1;
+      walk_match_arm(self, arm)
+    }
+
+    fn visit_pattern(&mut self, pattern: &'ast Pattern) {
+      self.0 += 1;
+      walk_pattern(self, pattern)
+    }
+
+    fn visit_identifier(&mut self, _identifier: &'ast Identifier) {
+      self.0 += 1;
+    }
+
+    fn visit_literal(&mut self, _literal: &'ast LiteralValue) {
+      self.0 += 1;
+    }
+  }
+
+  let mut counter = NodeCounter(0);
+  counter.visit_block(block);
+  counter.0
+}
+
+/**
+ * Collects every identifier in the block in source order, whether it declares
+ * a name or refers to one. Type names in annotations are included.
+ */
+pub fn collect_identifiers(block: &Block) -> Vec<&Identifier> {
+  struct IdentifierCollector<'ast>(Vec<&'ast Identifier>);
+
+  impl<'ast> Visitor<'ast> for IdentifierCollector<'ast> {
+    fn visit_identifier(&mut self, identifier: &'ast Identifier) {
+      self.0.push(identifier);
+    }
+  }
+
+  let mut collector = IdentifierCollector(vec![]);
+  collector.visit_block(block);
+  collector.0
+}
+
+/**
+ * Renames every identifier called `from` to `to`, without regard for scoping.
+ */
+pub fn rename(block: Block, from: &str, to: &str) -> Block {
+  struct Renamer<'a> {
+    from: &'a str,
+    to: &'a str,
+  }
+
+  impl<'a> Folder for Renamer<'a> {
+    fn fold_identifier(&mut self, identifier: Identifier) -> Identifier {
+      if identifier.name != self.from {
+        return identifier;
+      }
+      Identifier {
+        name: self.to.to_owned(),
+        source_ref: identifier.source_ref,
+      }
+    }
+  }
+
+  Renamer { from, to }.fold_block(block)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vm::grammar::ProgramParser;
+
+  fn parse(source: &str) -> Block {
+    ProgramParser::new().parse(0, source).unwrap()
+  }
+
+  fn names(identifiers: Vec<&Identifier>) -> Vec<&str> {
+    identifiers.iter().map(|i| i.name.as_str()).collect()
+  }
+
+  #[test]
+  fn counts_nodes() {
+    // The block and its unit return expression (with its literal), the let,
+    // its identifier, and the addition of two literal expressions.
+    assert_eq!(count_nodes(&parse("let x = 1 + 2;")), 10);
+    let source = "fn f(a: int) -> int { a } match f(1) { 1 => true, _ => false }";
+    // The program block (1), the declaration with its name, annotated param,
+    // return type and body (11), the match on the call (6) and its arms (5 + 4).
+    assert_eq!(count_nodes(&parse(source)), 27);
+  }
+
+  #[test]
+  fn collects_identifiers() {
+    let source = "
+      enum Shape { Circle(r), Empty }
+      let area = fn (s: Shape) { match s { Shape::Circle(r) => r * r, _ => 0 } };
+      import \"util.at\" as util;
+      util::print(area(Shape::Empty));
+    ";
+    assert_eq!(
+      names(collect_identifiers(&parse(source))),
+      vec![
+        "Shape", "Circle", "r", "Empty", "area", "s", "Shape", "s", "Shape", "Circle", "r",
+        "r", "r", "util", "util", "print", "area", "Shape", "Empty",
+      ]
+    );
+  }
+
+  #[test]
+  fn folds_renaming() {
+    let block = rename(parse("let x = [x, y]; fn f(x) { x[0] = y; }"), "x", "z");
+    assert_eq!(
+      names(collect_identifiers(&block)),
+      vec!["z", "z", "y", "f", "z", "z", "y"]
+    );
+  }
+}
@@ -1,86 +1,477 @@
 use super::ast::*;
+use super::builtins::*;
+use super::config::VmConfig;
+use super::convert::IntoValue;
+use super::debugger::Debugger;
+use super::error::RuntimeError;
+use super::formatter::format_program;
+use super::lints::{lint_program, Lint, LintKind};
+use super::numeric::*;
+use super::optimizer::optimize_program;
+use super::patterns::*;
 use super::scope::*;
-use regex::Regex;
-use std::cell::RefCell;
+use super::types::check_program;
+use lalrpop_util::ParseError;
+use num_traits::ToPrimitive;
+use std::cell::{Cell, Ref, RefCell};
 use std::collections::HashMap;
 use std::fs;
+use std::mem;
+use std::path::PathBuf;
 use std::rc::Rc;
 
-lalrpop_mod!(pub grammar);
+lalrpop_mod!(#[allow(clippy::all, unused)] pub grammar);
 
 pub struct VM {
   parser: grammar::ProgramParser,
+  // Holds the builtins, it is the parent of every module's global scope.
+  builtin_scope: Rc<RefCell<Scope>>,
   global_scope: Rc<RefCell<Scope>>,
+  // Every file loaded so far, indexed by the file id of their SourceRefs.
+  files: RefCell<Vec<SourceFile>>,
+  // Imported modules by canonical path, None while the module is executing.
+  modules: RefCell<HashMap<PathBuf, Option<Value>>>,
+  config: VmConfig,
+  // Usage counted against the limits in the config.
+  steps: Cell<u64>,
+  call_depth: Cell<usize>,
+  alloc_bytes: Cell<usize>,
+  debugger: RefCell<Option<Box<dyn Debugger>>>,
 }
 
+impl Default for VM {
+  fn default() -> VM {
+    VM::new()
+  }
+}
+
+pub struct SourceFile {
+  pub path: PathBuf,
+  pub source: String,
+}
+
+// Helpers written in the language itself, see VM::new.
+pub const PRELUDE: &str = include_str!("prelude.at");
+
 impl VM {
+  /**
+   * Creates a VM with the builtins and the prelude bound, running trusted
+   * scripts (with every capability).
+   */
   pub fn new() -> VM {
+    VM::with_config(VmConfig::trusted())
+  }
+
+  pub fn with_config(config: VmConfig) -> VM {
+    let builtin_scope = Rc::new(RefCell::new(Scope {
+      parent: None,
+      locals: HashMap::new(),
+    }));
     let mut vm = VM {
       parser: grammar::ProgramParser::new(),
-      global_scope: Rc::new(RefCell::new(Scope {
-        parent: None,
-        locals: HashMap::new(),
-      })),
+      global_scope: push_scope(&builtin_scope),
+      builtin_scope,
+      files: RefCell::new(vec![]),
+      modules: RefCell::new(HashMap::new()),
+      config,
+      steps: Cell::new(0),
+      call_depth: Cell::new(0),
+      alloc_bytes: Cell::new(0),
+      debugger: RefCell::new(None),
     };
-    vm.add_builtin_function("println", |args| {
+    vm.add_builtin_function("println", |_, args| {
       for arg in args {
         print!("{} ", arg);
       }
       println!();
-      Value::Unit
-    });
-    vm.add_builtin_function("read_file_to_str", |args| {
-      if let Value::Str(ref path) = args[0] {
-        return Value::Str(fs::read_to_string(path).expect("Cannot read file"));
-      }
-      Value::Unit
+      Ok(Value::Unit)
     });
+    add_system_builtins(&mut vm);
+    add_functional_builtins(&mut vm);
+    add_string_builtins(&mut vm);
+    add_numeric_builtins(&mut vm);
+    if vm.config.prelude {
+      let builtin_scope = Rc::clone(&vm.builtin_scope);
+      // The prelude is part of the VM, failing to load it is a bug in the VM.
+      vm.exec_source(PathBuf::from("<prelude>"), PRELUDE.to_owned(), builtin_scope)
+        .unwrap();
+      // Only the program's own usage counts against the limits.
+      vm.steps.set(0);
+      vm.alloc_bytes.set(0);
+    }
     vm
   }
 
-  pub fn add_builtin_function(&mut self, name: &str, function: fn(Vec<Value>) -> Value) {
-    (*self.global_scope).borrow_mut().bind_variable(
-      Identifier {
-        name: name.to_owned(),
-        source_ref: SourceRef::new(0, 0),
-      },
-      Value::BuiltInFunction(function),
-    );
+  pub fn config(&self) -> &VmConfig {
+    &self.config
+  }
+
+  /**
+   * Binds a builtin function, visible to every module. Closures (including
+   * boxed ones) can capture host state.
+   */
+  pub fn add_builtin_function<F>(&mut self, name: &str, function: F)
+  where
+    F: Fn(&VM, Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+  {
+    // Registering a builtin twice is a bug in the host, not in the program.
+    (*self.builtin_scope)
+      .borrow_mut()
+      .bind_variable(host_identifier(name), Value::BuiltInFunction(Rc::new(function)))
+      .unwrap();
+  }
+
+  /**
+   * Has the debugger stop the program before each statement it runs.
+   */
+  pub fn set_debugger<D: Debugger + 'static>(&mut self, debugger: D) {
+    *self.debugger.borrow_mut() = Some(Box::new(debugger));
+  }
+
+  /**
+   * The file with the id (the file of SourceRefs into it), once it is loaded.
+   */
+  pub fn source_file(&self, file: usize) -> Option<Ref<'_, SourceFile>> {
+    Ref::filter_map(self.files.borrow(), |files| files.get(file)).ok()
+  }
+
+  /**
+   * Calls the global function (or builtin) with the name.
+   */
+  pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match self.get_global(name) {
+      Some(function) => self.call_function(function, args),
+      None => Err(RuntimeError::new(format!("No global named {}", name))),
+    }
+  }
+
+  /**
+   * Gets a global of the main program, or a builtin.
+   */
+  pub fn get_global(&self, name: &str) -> Option<Value> {
+    (*self.global_scope)
+      .borrow()
+      .get_variable(&host_identifier(name))
+      .ok()
+  }
+
+  /**
+   * Binds (or re-binds) a global of the main program.
+   */
+  pub fn set_global<V: IntoValue>(&mut self, name: &str, value: V) {
+    (*self.global_scope)
+      .borrow_mut()
+      .locals
+      .insert(host_identifier(name), value.into_value());
+  }
+
+  /**
+   * Parses and executes the source on the global scope, returning the value of
+   * the program's trailing expression (if any). Imports are relative to the
+   * working directory.
+   */
+  pub fn exec(&mut self, source: &str) -> Result<Value, RuntimeError> {
+    let global_scope = Rc::clone(&self.global_scope);
+    self.exec_source(PathBuf::from("<source>"), source.to_owned(), global_scope)
+  }
+
+  /**
+   * Reads and executes the file on the global scope, like exec. Imports are
+   * relative to the file.
+   */
+  pub fn exec_file(&mut self, path: &str) -> Result<Value, RuntimeError> {
+    let source = fs::read_to_string(path)
+      .map_err(|e| RuntimeError::new(format!("Cannot read file {}: {}", path, e)))?;
+    let global_scope = Rc::clone(&self.global_scope);
+    self.exec_source(PathBuf::from(path), source, global_scope)
+  }
+
+  /**
+   * Calls `main` (if the program declared one) with the arguments as a list of
+   * strs, once the program has executed. Returns what main returned, or the
+   * program's own value when there is no main.
+   */
+  pub fn call_main(&mut self, value: Value, args: Vec<String>) -> Result<Value, RuntimeError> {
+    let main = match (*self.global_scope)
+      .borrow()
+      .locals
+      .get(&host_identifier("main"))
+    {
+      Some(main) => main.clone(),
+      None => return Ok(value),
+    };
+    let args = match main {
+      // A main that ignores its arguments can leave them off.
+      Value::Function(_, ref params, _) if params.is_empty() => vec![],
+      _ => vec![Value::List(Rc::new(args.into_iter().map(Value::Str).collect()))],
+    };
+    self.call_function(main, args)
+  }
+
+  /**
+   * Prints the error along with the line of the file it happened in.
+   */
+  pub fn log_error(&self, error: &RuntimeError) {
+    let files = self.files.borrow();
+    match error.source_ref().and_then(|source_ref| files.get(source_ref.file)) {
+      Some(file) => error.log(&file.path.display().to_string(), &file.source),
+      None => error.log("", ""),
+    }
+  }
+
+  /**
+   * Prints the lint along with the line of the file it is in.
+   */
+  pub fn log_lint(&self, lint: &Lint) {
+    let files = self.files.borrow();
+    let file = &files[lint.source_ref.file];
+    lint.log(&file.path.display().to_string(), &file.source);
+  }
+
+  /**
+   * Reads and parses the file, then type checks it without executing it.
+   * Returns the type errors found, or the error that stopped it from being
+   * checked at all.
+   */
+  pub fn check_file(&self, path: &str) -> Result<Vec<RuntimeError>, RuntimeError> {
+    let ast = self.parse_file(path)?;
+    check_match_exhaustiveness(&ast)?;
+    Ok(check_program(&ast))
+  }
+
+  /**
+   * Reads and parses the file, then lints it without executing it. Returns the
+   * lints that aren't allowed.
+   */
+  pub fn lint_file(&self, path: &str, allowed: &[LintKind]) -> Result<Vec<Lint>, RuntimeError> {
+    let ast = self.parse_file(path)?;
+    Ok(lint_program(&ast, allowed))
+  }
+
+  /**
+   * Reads and parses the file, returning it formatted without executing it.
+   */
+  pub fn format_file(&self, path: &str) -> Result<String, RuntimeError> {
+    let ast = self.parse_file(path)?;
+    let files = self.files.borrow();
+    Ok(format_program(&ast, &files[ast.source_ref.file].source))
+  }
+
+  /**
+   * Reads and parses the file, without executing it.
+   */
+  pub fn parse_file(&self, path: &str) -> Result<Block, RuntimeError> {
+    let source = fs::read_to_string(path)
+      .map_err(|e| RuntimeError::new(format!("Cannot read file {}: {}", path, e)))?;
+    self.parse_source(PathBuf::from(path), source)
+  }
+
+  fn exec_source(
+    &self,
+    path: PathBuf,
+    source: String,
+    mut scope: Rc<RefCell<Scope>>,
+  ) -> Result<Value, RuntimeError> {
+    let mut ast = self.parse_source(path, source)?;
+    check_match_exhaustiveness(&ast)?;
+    if self.config.optimize {
+      ast = optimize_program(ast);
+    }
+    self.exec_block_on_scope(&mut scope, &ast)
+  }
+
+  /**
+   * Records the source as the next file (so that errors can point into it)
+   * and parses it.
+   */
+  fn parse_source(&self, path: PathBuf, source: String) -> Result<Block, RuntimeError> {
+    let file = self.files.borrow().len();
+    self.files.borrow_mut().push(SourceFile { path, source });
+    let files = self.files.borrow();
+    self
+      .parser
+      .parse(file, &files[file].source)
+      .map_err(|e| syntax_error(file, e))
+  }
+
+  /**
+   * Imports the module at the path, relative to the file importing it. Each
+   * module is executed once, in its own global scope, and then cached.
+   */
+  fn import_module(&self, path: &str, source_ref: &SourceRef) -> Result<Value, RuntimeError> {
+    let path = match self.files.borrow()[source_ref.file].path.parent() {
+      Some(directory) => directory.join(path),
+      None => PathBuf::from(path),
+    };
+    let canonical_path = self.config.check_read(&path).map_err(|e| e.at(source_ref))?;
+    match self.modules.borrow().get(&canonical_path) {
+      Some(Some(module)) => return Ok(module.clone()),
+      Some(None) => {
+        return Err(RuntimeError::new_at(
+          format!("Cyclic import of {}", path.display()),
+          source_ref,
+        ))
+      }
+      None => {}
+    }
+    let source = fs::read_to_string(&canonical_path).map_err(|e| {
+      RuntimeError::new_at(format!("Cannot import {}: {}", path.display(), e), source_ref)
+    })?;
+    self.modules.borrow_mut().insert(canonical_path.clone(), None);
+    let module_scope = push_scope(&self.builtin_scope);
+    let name = path.display().to_string();
+    if let Err(error) = self.exec_source(path, source, Rc::clone(&module_scope)) {
+      self.modules.borrow_mut().remove(&canonical_path);
+      return Err(error);
+    }
+    let module = Value::Module(name, module_scope);
+    self
+      .modules
+      .borrow_mut()
+      .insert(canonical_path, Some(module.clone()));
+    Ok(module)
+  }
+
+  /**
+   * Calls a function value with already evaluated arguments. Errors raised
+   * here have no location, the caller is expected to attach one.
+   */
+  pub fn call_function(&self, function: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+    match function {
+      Value::Function(ref closure_scope, ref params, ref block) => {
+        let mut function_scope = push_scope(closure_scope);
+        // Bind parameters directly into child scope
+        if params.len() != args.len() {
+          return Err(RuntimeError::new(format!(
+            "Function takes {} arguments but {} were given",
+            params.len(),
+            args.len()
+          )));
+        }
+        for (param, arg_value) in params.iter().zip(args) {
+          (*function_scope)
+            .borrow_mut()
+            .close_variable(param, arg_value);
+        }
+        let depth = self.call_depth.get() + 1;
+        match self.config.max_call_depth {
+          Some(max_call_depth) if depth > max_call_depth => {
+            return Err(RuntimeError::ResourceExhausted(
+              format!("Call depth limit of {} exceeded", max_call_depth),
+              None,
+            ))
+          }
+          _ => self.call_depth.set(depth),
+        }
+        // Exec the function block
+        let result = self.exec_block_on_scope(&mut function_scope, block);
+        self.call_depth.set(depth - 1);
+        result
+      }
+      // Lists and strs made by builtins count against the allocation limit.
+      Value::BuiltInFunction(ref function) => self.allocate(function(self, args)?),
+      Value::EnumConstructor(ref identifier, ref variant, arity) => {
+        if args.len() != arity {
+          return Err(RuntimeError::new(format!(
+            "Variant {}::{} takes {} fields but {} were given",
+            identifier.name,
+            variant.name,
+            arity,
+            args.len()
+          )));
+        }
+        Ok(Value::EnumValue(identifier.clone(), variant.clone(), args))
+      }
+      _ => Err(RuntimeError::new(format!(
+        "Value is not a function: {:?}",
+        function
+      ))),
+    }
   }
 
-  pub fn exec(&mut self, source: &str) {
-    // Strip out line-level comments (Don't see a better way to do this with LALRPOP)
-    let re = Regex::new(r"^\s*//.*$").unwrap();
-    let raw_source: String = source.lines().filter(|line| !re.is_match(line)).collect();
-    let ast = self.parser.parse(&raw_source).unwrap();
-    self.exec_block_on_scope(&mut Rc::clone(&self.global_scope), &ast);
+  fn exec_block_on_scope(
+    &self,
+    scope: &mut Rc<RefCell<Scope>>,
+    block: &Block,
+  ) -> Result<Value, RuntimeError> {
+    // Errors from nodes that don't record a location are reported at the block.
+    self
+      .exec_block(scope, block)
+      .map_err(|e| e.at(&block.source_ref))
   }
 
-  fn exec_block_on_scope(&self, scope: &mut Rc<RefCell<Scope>>, block: &Block) -> Value {
+  fn exec_block(
+    &self,
+    scope: &mut Rc<RefCell<Scope>>,
+    block: &Block,
+  ) -> Result<Value, RuntimeError> {
+    // Functions are hoisted, all of them are bound before any statement runs so
+    // that they can call each other regardless of declaration order.
     for statement in &block.statements {
+      if let Statement::FunctionDeclarationStmt(ref identifier, ref params, _, ref block) =
+        statement
+      {
+        (**scope).borrow_mut().bind_variable(
+          identifier.clone(),
+          Value::Function(Rc::clone(scope), param_names(params), *block.clone()),
+        )?;
+      }
+    }
+    for statement in &block.statements {
+      self.debug_statement(scope, statement_source_ref(statement))?;
       match statement {
-        Statement::LetStmt(ref identifier, ref expression) => {
-          let value = self.eval_expression_on_scope(scope, expression);
+        Statement::LetStmt(ref identifier, _, ref expression) => {
+          let value = self.eval_expression_on_scope(scope, expression)?;
           (**scope)
             .borrow_mut()
-            .bind_variable(identifier.clone(), value);
+            .bind_variable(identifier.clone(), value)?;
         }
         Statement::AssignmentStmt(ref identifier, ref expression) => {
-          let value = self.eval_expression_on_scope(scope, expression);
-          (**scope).borrow_mut().assign_variable(identifier, value);
+          let value = self.eval_expression_on_scope(scope, expression)?;
+          (**scope).borrow_mut().assign_variable(identifier, value)?;
+        }
+        Statement::IndexAssignmentStmt(
+          ref identifier,
+          ref indices,
+          ref op,
+          ref expression,
+          ref source_ref,
+        ) => {
+          let indices = indices
+            .iter()
+            .map(|index| self.eval_expression_on_scope(scope, index))
+            .collect::<Result<Vec<_>, _>>()?;
+          let value = self.eval_expression_on_scope(scope, expression)?;
+          // The list is moved out of its variable while it's updated, so that
+          // it's only copied if it is shared with another value.
+          let mut list = (**scope).borrow().get_variable(identifier)?;
+          (**scope)
+            .borrow_mut()
+            .assign_variable(identifier, Value::Unit)?;
+          let result = assign_index(&mut list, &indices, op.as_ref(), value, source_ref);
+          (**scope).borrow_mut().assign_variable(identifier, list)?;
+          result?;
         }
         Statement::UnusedExprEvalStmt(ref expression) => {
-          self.eval_expression_on_scope(scope, expression);
+          self.eval_expression_on_scope(scope, expression)?;
         }
-        Statement::FunctionDeclarationStmt(ref identifier, ref params, ref block) => {
-          // Create a new child scope
+        // Already bound above.
+        Statement::FunctionDeclarationStmt(_, _, _, _) => {}
+        Statement::EnumDeclarationStmt(ref identifier, ref variants) => {
           (**scope).borrow_mut().bind_variable(
             identifier.clone(),
-            Value::Function(Rc::clone(scope), params.clone(), *block.clone()),
-          );
+            Value::Enum(identifier.clone(), variants.clone()),
+          )?;
+        }
+        Statement::ImportStmt(ref path, ref alias, ref source_ref) => {
+          let module = self.import_module(path, source_ref)?;
+          (**scope).borrow_mut().bind_variable(alias.clone(), module)?;
         }
       }
     }
+    if !matches!(block.return_expression, Expression::LiteralExpr(LiteralValue::Unit)) {
+      self.debug_statement(scope, block.return_expression.source_ref())?;
+    }
     self.eval_expression_on_scope(scope, &block.return_expression)
   }
 
@@ -88,90 +479,1227 @@ impl VM {
     &self,
     scope: &mut Rc<RefCell<Scope>>,
     expression: &Expression,
-  ) -> Value {
-    match expression {
+  ) -> Result<Value, RuntimeError> {
+    let result = self
+      .consume_fuel()
+      .and_then(|_| self.eval_expression(scope, expression));
+    match expression.source_ref() {
+      Some(source_ref) => result.map_err(|e| e.at(source_ref)),
+      None => result,
+    }
+  }
+
+  /**
+   * Lets the debugger (if any) stop before the statement at the location runs.
+   */
+  fn debug_statement(
+    &self,
+    scope: &Rc<RefCell<Scope>>,
+    source_ref: Option<&SourceRef>,
+  ) -> Result<(), RuntimeError> {
+    let source_ref = match source_ref {
+      Some(source_ref) => source_ref,
+      None => return Ok(()),
+    };
+    // Taken out while it runs, so that it can use the VM. Code it runs doesn't
+    // stop.
+    let mut debugger = match self.debugger.take() {
+      Some(debugger) => debugger,
+      None => return Ok(()),
+    };
+    let result = debugger.before_statement(self, source_ref, scope, self.call_depth.get());
+    self.debugger.replace(Some(debugger));
+    result
+  }
+
+  fn consume_fuel(&self) -> Result<(), RuntimeError> {
+    let steps = self.steps.get() + 1;
+    self.steps.set(steps);
+    match self.config.max_steps {
+      Some(max_steps) if steps > max_steps => Err(RuntimeError::ResourceExhausted(
+        format!("Step limit of {} exceeded", max_steps),
+        None,
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  /**
+   * Counts the (approximate) bytes of a newly created str or list against the
+   * allocation limit.
+   */
+  fn allocate(&self, value: Value) -> Result<Value, RuntimeError> {
+    let bytes = match value {
+      Value::Str(ref s) => s.len(),
+      Value::List(ref items) => items.len() * mem::size_of::<Value>(),
+      _ => return Ok(value),
+    };
+    let alloc_bytes = self.alloc_bytes.get() + bytes;
+    self.alloc_bytes.set(alloc_bytes);
+    match self.config.max_alloc_bytes {
+      Some(max_alloc_bytes) if alloc_bytes > max_alloc_bytes => {
+        Err(RuntimeError::ResourceExhausted(
+          format!("Allocation limit of {} bytes exceeded", max_alloc_bytes),
+          None,
+        ))
+      }
+      _ => Ok(value),
+    }
+  }
+
+  fn eval_expression(
+    &self,
+    scope: &mut Rc<RefCell<Scope>>,
+    expression: &Expression,
+  ) -> Result<Value, RuntimeError> {
+    Ok(match expression {
       Expression::LiteralExpr(ref v) => match v {
         LiteralValue::Unit => Value::Unit,
         LiteralValue::Int64(ref i) => Value::Int64(*i),
+        LiteralValue::BigInt(ref i) => Value::BigInt(Rc::new(i.clone())),
+        LiteralValue::Float64(ref f) => Value::Float64(*f),
         LiteralValue::Str(ref i) => Value::Str(i.to_owned()),
         LiteralValue::Bool(ref i) => Value::Bool(*i),
       },
-      Expression::BinExpr(lbox, op, rbox) => {
-        let l = self.eval_expression_on_scope(scope, lbox);
-        let r = self.eval_expression_on_scope(scope, rbox);
-        match (op, l, r) {
-          // Arithmetic Operations
-          (BinOp::Plus, Value::Int64(l), Value::Int64(r)) => Value::Int64(l + r),
-          (BinOp::Minus, Value::Int64(l), Value::Int64(r)) => Value::Int64(l - r),
-          (BinOp::Star, Value::Int64(l), Value::Int64(r)) => Value::Int64(l * r),
-          (BinOp::Slash, Value::Int64(l), Value::Int64(r)) => Value::Int64(l / r),
-          (BinOp::Mod, Value::Int64(l), Value::Int64(r)) => Value::Int64(l % r),
-          (BinOp::Ge, Value::Int64(l), Value::Int64(r)) => Value::Bool(l >= r),
-          (BinOp::Gt, Value::Int64(l), Value::Int64(r)) => Value::Bool(l > r),
-          (BinOp::Le, Value::Int64(l), Value::Int64(r)) => Value::Bool(l <= r),
-          (BinOp::Lt, Value::Int64(l), Value::Int64(r)) => Value::Bool(l < r),
-          (BinOp::Eql, Value::Int64(l), Value::Int64(r)) => Value::Bool(l == r),
-          (BinOp::Neq, Value::Int64(l), Value::Int64(r)) => Value::Bool(l != r),
-
-          // Boolean Operations
-          (BinOp::Eql, Value::Bool(l), Value::Bool(r)) => Value::Bool(l == r),
-          (BinOp::Neq, Value::Bool(l), Value::Bool(r)) => Value::Bool(l != r),
-          (BinOp::And, Value::Bool(l), Value::Bool(r)) => Value::Bool(l && r),
-          (BinOp::Or, Value::Bool(l), Value::Bool(r)) => Value::Bool(l || r),
-
-          // Unsupported operations
-          _ => panic!(format!("Failed to eval binary expression {:?}", expression)),
+      Expression::BinExpr(lbox, BinOp::Pipe, rbox, ref source_ref) => {
+        let l = self.eval_expression_on_scope(scope, lbox)?;
+        // Either a call to splice the left value into, or a bare function.
+        if let Expression::FunctionInvokeExpr(ref callee, ref args, _) = **rbox {
+          let function = self.eval_expression_on_scope(scope, callee)?;
+          let mut arg_values = vec![l];
+          for arg in args {
+            arg_values.push(self.eval_expression_on_scope(scope, arg)?);
+          }
+          self
+            .call_function(function, arg_values)
+            .map_err(|e| e.at(source_ref))?
+        } else {
+          let function = self.eval_expression_on_scope(scope, rbox)?;
+          self
+            .call_function(function, vec![l])
+            .map_err(|e| e.at(source_ref))?
+        }
+      }
+      Expression::BinExpr(lbox, op @ BinOp::And, rbox, ref source_ref)
+      | Expression::BinExpr(lbox, op @ BinOp::Or, rbox, ref source_ref) => {
+        // Short-circuits, the right operand is only evaluated when needed.
+        let short_circuit = matches!(op, BinOp::Or);
+        let l = self.eval_expression_on_scope(scope, lbox)?;
+        match l {
+          Value::Bool(l) if l == short_circuit => Value::Bool(l),
+          Value::Bool(l) => match self.eval_expression_on_scope(scope, rbox)? {
+            Value::Bool(r) => Value::Bool(r),
+            r => return Err(bin_op_error(op, &Value::Bool(l), &r, source_ref)),
+          },
+          l => return Err(RuntimeError::new_at(
+            format!("Cannot apply {:?} to {:?}", op, l),
+            source_ref,
+          )),
+        }
+      }
+      Expression::BinExpr(lbox, op, rbox, ref source_ref) => {
+        let l = self.eval_expression_on_scope(scope, lbox)?;
+        let r = self.eval_expression_on_scope(scope, rbox)?;
+        self.allocate(eval_bin_op(op, l, r, source_ref)?)?
+      }
+      Expression::UnaryExpr(op, ref operand, ref source_ref) => {
+        match (op, self.eval_expression_on_scope(scope, operand)?) {
+          (UnOp::Neg, Value::Int64(i)) => negate_int(i),
+          (UnOp::Neg, Value::BigInt(i)) => normalize(-(*i).clone()),
+          (UnOp::Neg, Value::Float64(f)) => Value::Float64(-f),
+          (UnOp::Not, Value::Bool(b)) => Value::Bool(!b),
+          (UnOp::BitNot, Value::Int64(i)) => Value::Int64(!i),
+          (op, v) => {
+            return Err(RuntimeError::new_at(
+              format!("Cannot apply {:?} to {:?}", op, v),
+              source_ref,
+            ))
+          }
         }
       }
       Expression::BlockExpr(ref block) => {
         let mut child_scope = push_scope(scope);
-        self.exec_block_on_scope(&mut child_scope, block)
+        self.exec_block_on_scope(&mut child_scope, block)?
       }
       Expression::IdentifierDerefExpr(ref identifier) => {
-        (**scope).borrow().get_variable(identifier)
-      }
-      Expression::FunctionInvokeExpr(ref identifier, ref args) => {
-        let mut function = (**scope).borrow().get_variable(identifier);
-        match function {
-          Value::Function(ref mut closure_scope, ref params, ref block) => {
-            let mut function_scope = push_scope(closure_scope);
-            // Bind parameters directly into child scope
-            if params.len() != args.len() {
-              panic!("Mismatched number of arguments");
-            }
-            for (param, arg_expression) in params.iter().zip(args.iter()) {
-              let arg_value = self.eval_expression_on_scope(scope, arg_expression);
-              (*function_scope)
-                .borrow_mut()
-                .close_variable(param, arg_value);
+        (**scope).borrow().get_variable(identifier)?
+      }
+      Expression::FunctionInvokeExpr(ref callee, ref args, ref source_ref) => {
+        let function = self.eval_expression_on_scope(scope, callee)?;
+        let arg_values = args
+          .iter()
+          .map(|arg| self.eval_expression_on_scope(scope, arg))
+          .collect::<Result<_, _>>()?;
+        self
+          .call_function(function, arg_values)
+          .map_err(|e| e.at(source_ref))?
+      }
+      Expression::ListExpr(ref items) => self.allocate(Value::List(Rc::new(
+        items
+          .iter()
+          .map(|item| self.eval_expression_on_scope(scope, item))
+          .collect::<Result<_, _>>()?,
+      )))?,
+      Expression::IndexExpr(ref list, ref index, ref source_ref) => {
+        match (
+          self.eval_expression_on_scope(scope, list)?,
+          self.eval_expression_on_scope(scope, index)?,
+        ) {
+          (Value::List(ref items), Value::Int64(i)) => {
+            if i < 0 || i as usize >= items.len() {
+              return Err(RuntimeError::new_at(
+                format!("Index {} out of bounds for list of length {}", i, items.len()),
+                source_ref,
+              ));
             }
-            // Exec the function block
-            self.exec_block_on_scope(&mut function_scope, block)
+            items[i as usize].clone()
+          }
+          (l, i) => {
+            return Err(RuntimeError::new_at(
+              format!("Cannot index {:?} with {:?}", l, i),
+              source_ref,
+            ))
           }
-          Value::BuiltInFunction(ref function) => function(
-            args
-              .iter()
-              .map(|arg| self.eval_expression_on_scope(scope, arg))
-              .collect(),
-          ),
-          _ => panic!("Function is not declared {}", identifier.name),
-        }
-      }
-      Expression::IfElseExpr(ref condition, ref then_block, ref opt_else_block) => {
-        if let Value::Bool(c) = self.eval_expression_on_scope(scope, condition) {
-          let mut child_scope = push_scope(scope);
-          if c {
-            return self.exec_block_on_scope(&mut child_scope, then_block);
-          } else {
-            if let Some(else_block) = opt_else_block {
-              return self.exec_block_on_scope(&mut child_scope, else_block);
+        }
+      }
+      Expression::FunctionExpr(ref params, _, ref block) => {
+        Value::Function(Rc::clone(scope), param_names(params), *block.clone())
+      }
+      Expression::IfElseExpr(ref condition, ref then_block, ref opt_else_block, ref source_ref) => {
+        match self.eval_expression_on_scope(scope, condition)? {
+          Value::Bool(c) => {
+            let mut child_scope = push_scope(scope);
+            if c {
+              self.exec_block_on_scope(&mut child_scope, then_block)?
+            } else if let Some(else_block) = opt_else_block {
+              self.exec_block_on_scope(&mut child_scope, else_block)?
             } else {
-              return Value::Unit;
+              Value::Unit
             }
           }
+          v => {
+            return Err(RuntimeError::new_at(
+              format!("Condition is not a boolean, found {:?}", v),
+              source_ref,
+            ))
+          }
+        }
+      }
+      Expression::PathExpr(ref identifier, ref name) => {
+        match (**scope).borrow().get_variable(identifier)? {
+          Value::Enum(_, ref variants) => match variants.iter().find(|v| v.name == *name) {
+            Some(v) if v.fields.is_empty() => {
+              Value::EnumValue(identifier.clone(), name.clone(), vec![])
+            }
+            Some(v) => Value::EnumConstructor(identifier.clone(), name.clone(), v.fields.len()),
+            None => {
+              return Err(RuntimeError::new_at(
+                format!("Enum {} has no variant {}", identifier.name, name.name),
+                &name.source_ref,
+              ))
+            }
+          },
+          // Only the module's own globals are visible, not the builtins.
+          Value::Module(ref path, ref module_scope) => {
+            match (**module_scope).borrow().locals.get(name) {
+              Some(value) => value.clone(),
+              None => {
+                return Err(RuntimeError::new_at(
+                  format!("Module {} has no member {}", path, name.name),
+                  &name.source_ref,
+                ))
+              }
+            }
+          }
+          _ => {
+            return Err(RuntimeError::new_at(
+              format!("{} is not an enum or module", identifier.name),
+              &identifier.source_ref,
+            ))
+          }
         }
-        panic!("Condition is not a boolean expression: {:#?}", condition);
       }
+      Expression::MatchExpr(ref subject, ref arms, ref source_ref) => {
+        let value = self.eval_expression_on_scope(scope, subject)?;
+        for arm in arms {
+          let mut bindings = vec![];
+          if match_pattern(&arm.pattern, &value, &mut bindings) {
+            let mut child_scope = push_scope(scope);
+            for (identifier, bound_value) in bindings {
+              (*child_scope)
+                .borrow_mut()
+                .bind_variable(identifier, bound_value)?;
+            }
+            return self.eval_expression_on_scope(&mut child_scope, &arm.expression);
+          }
+        }
+        return Err(RuntimeError::new_at(
+          format!("No match arm matched value {:?}", value),
+          source_ref,
+        ));
+      }
+    })
+  }
+}
+
+/**
+ * An identifier for a name given by the host, rather than parsed from source.
+ */
+fn host_identifier(name: &str) -> Identifier {
+  Identifier {
+    name: name.to_owned(),
+    source_ref: SourceRef::new(0, 0, 0),
+  }
+}
+
+/**
+ * The names functions bind their arguments to. Annotations don't exist at
+ * runtime.
+ */
+fn param_names(params: &[Param]) -> Vec<Identifier> {
+  params.iter().map(|param| param.name.clone()).collect()
+}
+
+/**
+ * Assigns (or compound assigns) to the element of the list at the path of
+ * indices, copying each list on the path that is shared.
+ */
+fn assign_index(
+  target: &mut Value,
+  indices: &[Value],
+  op: Option<&BinOp>,
+  value: Value,
+  source_ref: &SourceRef,
+) -> Result<(), RuntimeError> {
+  let (index, rest) = indices.split_first().unwrap();
+  let element = match (target, index) {
+    (Value::List(ref mut items), Value::Int64(i)) if *i < 0 || *i as usize >= items.len() => {
+      return Err(RuntimeError::new_at(
+        format!("Index {} out of bounds for list of length {}", i, items.len()),
+        source_ref,
+      ))
     }
+    (Value::List(ref mut items), Value::Int64(i)) => &mut Rc::make_mut(items)[*i as usize],
+    (l, i) => {
+      return Err(RuntimeError::new_at(
+        format!("Cannot index {:?} with {:?}", l, i),
+        source_ref,
+      ))
+    }
+  };
+  if !rest.is_empty() {
+    return assign_index(element, rest, op, value, source_ref);
+  }
+  *element = match op {
+    Some(op) => eval_bin_op(op, element.clone(), value, source_ref)?,
+    None => value,
+  };
+  Ok(())
+}
+
+pub(crate) fn syntax_error<T: std::fmt::Debug>(
+  file: usize,
+  error: ParseError<usize, T, RuntimeError>,
+) -> RuntimeError {
+  let (message, l, r) = match error {
+    ParseError::InvalidToken { location } => ("Invalid token".to_owned(), location, location),
+    ParseError::UnrecognizedEOF { location, expected } => (
+      format!("Unexpected end of input, expected one of {}", expected.join(", ")),
+      location,
+      location,
+    ),
+    ParseError::UnrecognizedToken {
+      token: (l, token, r),
+      expected,
+    } => (
+      format!("Unexpected {:?}, expected one of {}", token, expected.join(", ")),
+      l,
+      r,
+    ),
+    ParseError::ExtraToken { token: (l, token, r) } => {
+      (format!("Unexpected extra {:?}", token), l, r)
+    }
+    ParseError::User { error } => return error,
+  };
+  RuntimeError::SyntaxError(message, Some(SourceRef::new(file, l, r)))
+}
+
+/**
+ * Where the debugger stops for the statement. Function declarations don't run
+ * (they are bound as the block starts), and literal statements have no
+ * location.
+ */
+fn statement_source_ref(statement: &Statement) -> Option<&SourceRef> {
+  match statement {
+    Statement::LetStmt(ref identifier, _, _)
+    | Statement::AssignmentStmt(ref identifier, _)
+    | Statement::EnumDeclarationStmt(ref identifier, _) => Some(&identifier.source_ref),
+    Statement::IndexAssignmentStmt(_, _, _, _, ref source_ref)
+    | Statement::ImportStmt(_, _, ref source_ref) => Some(source_ref),
+    Statement::UnusedExprEvalStmt(ref expression) => expression.source_ref(),
+    Statement::FunctionDeclarationStmt(_, _, _, _) => None,
+  }
+}
+
+fn bin_op_error(op: &BinOp, l: &Value, r: &Value, source_ref: &SourceRef) -> RuntimeError {
+  RuntimeError::new_at(
+    format!("Cannot apply {:?} to {:?} and {:?}", op, l, r),
+    source_ref,
+  )
+}
+
+pub(crate) fn eval_bin_op(
+  op: &BinOp,
+  l: Value,
+  r: Value,
+  source_ref: &SourceRef,
+) -> Result<Value, RuntimeError> {
+  let result = match (op, &l, &r) {
+    // Integer Operations (checked, promoting to BigInt on overflow)
+    (op, Value::Int64(li), Value::Int64(ri)) => eval_int_bin_op(op, *li, *ri),
+    (op, Value::BigInt(_), Value::Int64(_) | Value::BigInt(_))
+    | (op, Value::Int64(_), Value::BigInt(_)) => {
+      eval_big_int_bin_op(op, &to_big_int(&l).unwrap(), &to_big_int(&r).unwrap())
+    }
+
+    // Boolean Operations
+    (BinOp::Eql, Value::Bool(l), Value::Bool(r)) => Some(Ok(Value::Bool(l == r))),
+    (BinOp::Neq, Value::Bool(l), Value::Bool(r)) => Some(Ok(Value::Bool(l != r))),
+
+    // List Operations
+    (BinOp::Plus, Value::List(l), Value::List(r)) => {
+      Some(Ok(Value::List(Rc::new(l.iter().chain(r.iter()).cloned().collect()))))
+    }
+
+    // String Operations
+    (BinOp::Plus, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Str(l.to_owned() + r))),
+    (BinOp::Ge, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Bool(l >= r))),
+    (BinOp::Gt, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Bool(l > r))),
+    (BinOp::Le, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Bool(l <= r))),
+    (BinOp::Lt, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Bool(l < r))),
+    (BinOp::Eql, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Bool(l == r))),
+    (BinOp::Neq, Value::Str(l), Value::Str(r)) => Some(Ok(Value::Bool(l != r))),
+
+    // Float Operations (ints are promoted when mixed with floats)
+    (op, Value::Float64(_), _) | (op, _, Value::Float64(_)) => {
+      match (to_float(&l), to_float(&r)) {
+        (Some(l), Some(r)) => eval_float_bin_op(op, l, r).map(Ok),
+        _ => None,
+      }
+    }
+
+    // Unsupported operations
+    _ => None,
+  };
+  match result {
+    Some(result) => result.map_err(|message| RuntimeError::new_at(message, source_ref)),
+    None => Err(bin_op_error(op, &l, &r, source_ref)),
+  }
+}
+
+fn to_float(value: &Value) -> Option<f64> {
+  match value {
+    Value::Int64(i) => Some(*i as f64),
+    Value::BigInt(i) => i.to_f64(),
+    Value::Float64(f) => Some(*f),
+    _ => None,
+  }
+}
+
+fn eval_float_bin_op(op: &BinOp, l: f64, r: f64) -> Option<Value> {
+  Some(match op {
+    BinOp::Plus => Value::Float64(l + r),
+    BinOp::Minus => Value::Float64(l - r),
+    BinOp::Star => Value::Float64(l * r),
+    BinOp::Slash => Value::Float64(l / r),
+    BinOp::Mod => Value::Float64(l % r),
+    BinOp::Ge => Value::Bool(l >= r),
+    BinOp::Gt => Value::Bool(l > r),
+    BinOp::Le => Value::Bool(l <= r),
+    BinOp::Lt => Value::Bool(l < r),
+    BinOp::Eql => Value::Bool(l == r),
+    BinOp::Neq => Value::Bool(l != r),
+    _ => return None,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::convert::FromValue;
+
+  fn eval(source: &str) -> String {
+    format!("{:?}", VM::new().exec(source).unwrap())
+  }
+
+  /**
+   * Evaluates source that must fail, returning the error message and the
+   * source text the error points at.
+   */
+  fn eval_err(source: &str) -> (String, String) {
+    let error = VM::new().exec(source).unwrap_err();
+    let source_ref = error.source_ref().expect("error has no location");
+    let at = &source[source_ref.left as usize..source_ref.right as usize];
+    (error.message().to_owned(), at.to_owned())
+  }
+
+  #[test]
+  fn enums_are_constructed_and_destructured() {
+    let source = "enum Shape { Circle(r), Rect(w, h), Empty }
+      fn area(shape) {
+        match shape { Shape::Circle(r) => 3 * r * r, Shape::Rect(w, h) => w * h, Shape::Empty => 0 }
+      }
+      [area(Shape::Circle(2)), area(Shape::Rect(2, 3)), area(Shape::Empty)]";
+    assert_eq!(eval(source), "[i12, i6, i0]");
+    assert_eq!(eval("enum Pair { Of(a, b) } Pair::Of(1, \"x\")"), "Pair::Of(1, x)");
+    let source = "enum Opt { Some(v), None }
+      match Opt::Some(Opt::Some(5)) { Opt::Some(Opt::Some(x)) => x, _ => 0 }";
+    assert_eq!(eval(source), "i5");
+  }
+
+  #[test]
+  fn match_literals_bindings_and_wildcards() {
+    assert_eq!(eval("match 2 { 1 => \"one\", 2 => \"two\", _ => \"many\" }"), "\"two\"");
+    assert_eq!(eval("match 7 { 1 => 0, n => n * 2 }"), "i14");
+    assert_eq!(eval("match \"b\" { \"a\" => 1, _ => 2 }"), "i2");
+    // Bindings are scoped to their arm.
+    assert_eq!(eval("let n = 1; let m = match 5 { n => n }; [n, m]"), "[i1, i5]");
+  }
+
+  #[test]
+  fn non_exhaustive_enum_match_is_rejected_before_running() {
+    let (message, at) = eval_err(
+      "enum Color { Red, Green, Blue } println(\"ran\");
+       match Color::Red { Color::Red => 1, Color::Green => 2 }",
+    );
+    assert_eq!(message, "Non-exhaustive match, missing: Color::Blue");
+    assert!(at.starts_with("match Color::Red"), "{}", at);
+  }
+
+  #[test]
+  fn unmatched_value_is_a_runtime_error() {
+    let (message, at) = eval_err("let x = match 3 { 1 => 1, 2 => 2 };");
+    assert_eq!(message, "No match arm matched value i3");
+    assert_eq!(at, "match 3 { 1 => 1, 2 => 2 }");
+  }
+
+  #[test]
+  fn variant_arity_is_checked() {
+    let (message, at) = eval_err(
+      "enum Shape { Circle(r) } match Shape::Circle(1) { Shape::Circle(a, b) => a, _ => 0 }",
+    );
+    assert_eq!(message, "Pattern Shape::Circle expects 1 fields, found 2");
+    assert_eq!(at, "Circle");
+    let (message, _) = eval_err("enum Shape { Circle(r) } Shape::Circle(1, 2)");
+    assert_eq!(message, "Variant Shape::Circle takes 1 fields but 2 were given");
+  }
+
+  #[test]
+  fn function_expressions() {
+    assert_eq!(eval("let double = fn (x) { x * 2 }; double(21)"), "i42");
+    assert_eq!(eval("(fn (a, b) { a - b })(5, 3)"), "i2");
+    assert_eq!(eval("let k = fn () { 7 }; k()"), "i7");
+  }
+
+  #[test]
+  fn closures_capture_outer_variables_by_reference() {
+    let source = "fn outer() {
+        let counter = 42;
+        fn () { counter += 1; counter }
+      }
+      let one = outer();
+      let two = outer();
+      [one(), one(), one(), two()]";
+    assert_eq!(eval(source), "[i43, i44, i45, i43]");
+    // Assignments made after the closure is created are seen by it.
+    assert_eq!(eval("let x = 1; let get = fn () { x }; x = 2; get()"), "i2");
+  }
+
+  #[test]
+  fn returned_functions_can_be_called_directly() {
+    assert_eq!(eval("fn outer() { fn inner() { 5 } inner } outer()()"), "i5");
+    assert_eq!(eval("fn adder(n) { fn (x) { x + n } } adder(2)(3)"), "i5");
+  }
+
+  #[test]
+  fn closures_can_be_passed_to_builtins() {
+    assert_eq!(eval("let step = 10; map([1, 2], fn (x) { x + step })"), "[i11, i12]");
+  }
+
+  #[test]
+  fn map_builtin() {
+    assert_eq!(eval("map([1, 2, 3], fn (x) { x * x })"), "[i1, i4, i9]");
+    assert_eq!(eval("map([], fn (x) { x })"), "[]");
+  }
+
+  #[test]
+  fn filter_builtin() {
+    assert_eq!(eval("filter([1, 2, 3, 4], fn (x) { x % 2 == 0 })"), "[i2, i4]");
+    let (message, _) = eval_err("filter([1], fn (x) { x })");
+    assert_eq!(message, "filter predicate must return a bool, found i1");
+  }
+
+  #[test]
+  fn fold_builtin() {
+    assert_eq!(eval("fold([1, 2, 3], 10, fn (sum, x) { sum + x })"), "i16");
+    assert_eq!(eval("fold([], \"empty\", fn (a, x) { x })"), "\"empty\"");
+  }
+
+  #[test]
+  fn sort_by_builtin() {
+    let source = "sort_by([\"ccc\", \"a\", \"bb\", \"d\"], fn (s) { len(s) })";
+    // The sort is stable, "a" stays before "d".
+    assert_eq!(eval(source), "[\"a\", \"d\", \"bb\", \"ccc\"]");
+    assert_eq!(eval("sort_by([3, 1, 2], fn (x) { -x })"), "[i3, i2, i1]");
+    // Ints and floats compare with each other.
+    assert_eq!(eval("sort_by([2.5, 1, 3], fn (x) { x })"), "[i1, f2.5, i3]");
+  }
+
+  #[test]
+  fn sort_by_rejects_incomparable_keys() {
+    let (message, _) = eval_err("sort_by([1, \"a\"], fn (x) { x })");
+    assert!(message.starts_with("Cannot compare sort keys"), "{}", message);
+    // Keys that are never next to each other in the input are still checked.
+    let (message, _) = eval_err("sort_by([[\"a\"], [], [1]], fn (x) { x })");
+    assert!(message.starts_with("Cannot compare sort keys"), "{}", message);
+  }
+
+  #[test]
+  fn any_builtin() {
+    assert_eq!(eval("any([1, 2, 3], fn (x) { x > 2 })"), "true");
+    assert_eq!(eval("any([], fn (x) { true })"), "false");
+    // Stops at the first true, so the bad item is never looked at.
+    assert_eq!(eval("any([1, \"a\"], fn (x) { x == 1 })"), "true");
+  }
+
+  #[test]
+  fn all_builtin() {
+    assert_eq!(eval("all([1, 2, 3], fn (x) { x > 0 })"), "true");
+    assert_eq!(eval("all([1, 2, 3], fn (x) { x > 1 })"), "false");
+    assert_eq!(eval("all([], fn (x) { false })"), "true");
+  }
+
+  #[test]
+  fn window_builtin() {
+    assert_eq!(eval("window([1, 2, 3], 2)"), "[[i1, i2], [i2, i3]]");
+    assert_eq!(eval("window([1], 2)"), "[]");
+    let (message, _) = eval_err("window([1], 0)");
+    assert_eq!(message, "window expected a positive size, found i0");
+  }
+
+  #[test]
+  fn higher_order_builtins_reject_non_functions() {
+    for source in &["map([1], 2)", "filter([], \"f\")", "fold([], 0, 1)", "sort_by([], [])"] {
+      let (message, _) = eval_err(source);
+      assert!(message.contains(" expected a function, found "), "{}", message);
+    }
+  }
+
+  #[test]
+  fn pipe_binds_looser_than_or() {
+    assert_eq!(eval("fn wrap(x) { [x] } true || false |> wrap"), "[true]");
+    assert_eq!(eval("fn wrap(x) { [x] } 1 + 2 |> wrap"), "[i3]");
+  }
+
+  #[test]
+  fn pipe_into_call_prepends_the_argument() {
+    assert_eq!(eval("fn sub(a, b) { a - b } 10 |> sub(3)"), "i7");
+    assert_eq!(eval("[1, 2] |> map(fn (x) { x * 10 })"), "[i10, i20]");
+  }
+
+  #[test]
+  fn pipe_into_bare_function() {
+    assert_eq!(eval("fn double(x) { x * 2 } 4 |> double"), "i8");
+    assert_eq!(eval("[1, 2, 3] |> len"), "i3");
+    assert_eq!(eval("-5 |> (fn (x) { x * x })"), "i25");
+  }
+
+  #[test]
+  fn pipes_chain_left_to_right() {
+    let source = "fn add(a, b) { a + b } fn double(x) { x * 2 }
+      1 |> add(2) |> double |> add(1)";
+    assert_eq!(eval(source), "i7");
+  }
+
+  #[test]
+  fn string_operators() {
+    assert_eq!(eval("\"foo\" + \"bar\""), "\"foobar\"");
+    assert_eq!(eval("[\"a\" == \"a\", \"a\" == \"b\", \"a\" != \"b\"]"), "[true, false, true]");
+    assert_eq!(eval("[\"a\" < \"b\", \"b\" <= \"a\", \"ab\" > \"a\"]"), "[true, false, true]");
+  }
+
+  #[test]
+  fn split_and_lines() {
+    assert_eq!(eval("split(\"a,b,,c\", \",\")"), "[\"a\", \"b\", \"\", \"c\"]");
+    assert_eq!(eval("lines(\"one\ntwo\r\n\")"), "[\"one\", \"two\"]");
+  }
+
+  #[test]
+  fn trim_chars_and_len() {
+    assert_eq!(eval("trim(\"  x y \n\")"), "\"x y\"");
+    assert_eq!(eval("chars(\"héy\")"), "[\"h\", \"é\", \"y\"]");
+    // Counts characters, not bytes.
+    assert_eq!(eval("[len(\"héy\"), len([1, 2])]"), "[i3, i2]");
+    let (message, _) = eval_err("len(1)");
+    assert_eq!(message, "len expected a str or list, found i1");
+  }
+
+  #[test]
+  fn contains_starts_with_and_replace() {
+    assert_eq!(eval("[contains(\"haystack\", \"st\"), contains(\"a\", \"b\")]"), "[true, false]");
+    let source = "[starts_with(\"prefix\", \"pre\"), starts_with(\"p\", \"pre\")]";
+    assert_eq!(eval(source), "[true, false]");
+    assert_eq!(eval("replace(\"a-b-c\", \"-\", \"+\")"), "\"a+b+c\"");
+    let (message, _) = eval_err("contains(\"a\", 1)");
+    assert_eq!(message, "contains expected a str, found i1");
+  }
+
+  #[test]
+  fn to_string_and_parse_int() {
+    assert_eq!(eval("to_string([1, \"a\", true])"), "\"[1, a, true]\"");
+    assert_eq!(eval("to_string(42) + \"!\""), "\"42!\"");
+    assert_eq!(eval("parse_int(\" 42\n\") + 1"), "i43");
+    assert_eq!(eval("parse_int(\"-7\")"), "i-7");
+    assert_eq!(eval("parse_int(\"99999999999999999999\")"), "i99999999999999999999");
+    let (message, _) = eval_err("parse_int(\"4x\")");
+    assert_eq!(message, "parse_int failed to parse \"4x\" as an int");
+  }
+
+  #[test]
+  fn float_literals() {
+    assert_eq!(eval("1.5"), "f1.5");
+    assert_eq!(eval("2.5e2"), "f250.0");
+    assert_eq!(eval("1e-2"), "f0.01");
+    assert_eq!(eval("3E+1"), "f30.0");
+  }
+
+  #[test]
+  fn float_arithmetic_and_comparison() {
+    assert_eq!(eval("1.5 + 2.25 * 2.0"), "f6.0");
+    assert_eq!(eval("1.0 / 4.0 - 0.5"), "f-0.25");
+    assert_eq!(eval("7.5 % 2.0"), "f1.5");
+    assert_eq!(eval("1.5 < 2.5 && 2.5 >= 2.5"), "true");
+    assert_eq!(eval("0.1 + 0.2 == 0.3"), "false");
+  }
+
+  #[test]
+  fn mixed_int_float_promotes_to_float() {
+    assert_eq!(eval("1 + 0.5"), "f1.5");
+    assert_eq!(eval("0.5 * 4"), "f2.0");
+    assert_eq!(eval("1 / 2.0"), "f0.5");
+    assert_eq!(eval("2 == 2.0"), "true");
+    assert_eq!(eval("3 > 2.5"), "true");
+    // Int only arithmetic stays integral.
+    assert_eq!(eval("1 / 2"), "i0");
+  }
+
+  #[test]
+  fn int_float_conversions() {
+    assert_eq!(eval("to_float(3)"), "f3.0");
+    assert_eq!(eval("to_int(3.9)"), "i3");
+    assert_eq!(eval("to_int(0.0 - 3.9)"), "i-3");
+    assert_eq!(eval("to_int(to_float(42))"), "i42");
+  }
+
+  #[test]
+  fn unary_operators() {
+    assert_eq!(eval("-42"), "i-42");
+    assert_eq!(eval("-1.5"), "f-1.5");
+    assert_eq!(eval("!true"), "false");
+    assert_eq!(eval("let x = 3; -x * 2"), "i-6");
+    assert_eq!(eval("let x = 3; - -x"), "i3");
+    assert_eq!(eval("!(1 > 2) && !false"), "true");
+    assert_eq!(eval("match -3 { -3 => 1, _ => 2 }"), "i1");
+  }
+
+  #[test]
+  fn not_rejects_non_bools() {
+    let (message, at) = eval_err("let x = !42;");
+    assert!(message.starts_with("Cannot apply Not"), "{}", message);
+    assert_eq!(at, "!42");
+  }
+
+  thread_local! {
+    static SIDE_EFFECTS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+  }
+
+  /**
+   * Evaluates with a `side_effect(result)` builtin that counts its calls.
+   * Returns the value and the number of calls.
+   */
+  fn eval_counting_side_effects(source: &str) -> (String, usize) {
+    SIDE_EFFECTS.with(|c| c.set(0));
+    let mut vm = VM::new();
+    vm.add_builtin_function("side_effect", |_, args| {
+      SIDE_EFFECTS.with(|c| c.set(c.get() + 1));
+      Ok(args[0].clone())
+    });
+    let value = format!("{:?}", vm.exec(source).unwrap());
+    (value, SIDE_EFFECTS.with(|c| c.get()))
+  }
+
+  #[test]
+  fn and_short_circuits() {
+    let (value, calls) = eval_counting_side_effects("false && side_effect(true)");
+    assert_eq!((value.as_str(), calls), ("false", 0));
+    let (value, calls) = eval_counting_side_effects("true && side_effect(false)");
+    assert_eq!((value.as_str(), calls), ("false", 1));
+    assert_eq!(eval("let x = 0; x != 0 && 10 / x > 1"), "false");
+  }
+
+  #[test]
+  fn or_short_circuits() {
+    let (value, calls) = eval_counting_side_effects("true || side_effect(false)");
+    assert_eq!((value.as_str(), calls), ("true", 0));
+    let (value, calls) = eval_counting_side_effects("false || side_effect(true)");
+    assert_eq!((value.as_str(), calls), ("true", 1));
+    let (value, calls) =
+      eval_counting_side_effects("side_effect(false) || side_effect(true) || side_effect(false)");
+    assert_eq!((value.as_str(), calls), ("true", 2));
+  }
+
+  #[test]
+  fn to_int_rejects_non_finite() {
+    let (message, at) = eval_err("to_int(1.0 / 0.0)");
+    assert!(message.starts_with("to_int cannot represent"), "{}", message);
+    assert_eq!(at, "to_int(1.0 / 0.0)");
+  }
+
+  #[test]
+  fn int_overflow_promotes_to_big_int() {
+    assert_eq!(eval("9223372036854775807 + 1"), "i9223372036854775808");
+    assert_eq!(eval("-9223372036854775807 - 2"), "i-9223372036854775809");
+    assert_eq!(eval("4294967296 * 4294967296"), "i18446744073709551616");
+    assert_eq!(eval("-(-9223372036854775807 - 1)"), "i9223372036854775808");
+    assert_eq!(
+      eval("let x = 9223372036854775807; x * x * x / (x * x)"),
+      "i9223372036854775807"
+    );
+  }
+
+  #[test]
+  fn int_literals_beyond_int64() {
+    assert_eq!(eval("99999999999999999999"), "i99999999999999999999");
+    assert_eq!(eval("99999999999999999999 - 99999999999999999998"), "i1");
+    assert_eq!(eval("9223372036854775808 - 1"), "i9223372036854775807");
+    // i64::MIN is the negation of a literal that doesn't fit an Int64.
+    assert_eq!(eval("-9223372036854775808"), "i-9223372036854775808");
+    assert_eq!(eval("-9223372036854775808 == -9223372036854775807 - 1"), "true");
+    assert_eq!(
+      eval("match 9223372036854775807 + 1 { 9223372036854775808 => true, _ => false }"),
+      "true"
+    );
+    assert_eq!(eval("match -9223372036854775807 - 1 { -9223372036854775808 => 1, _ => 0 }"), "i1");
+  }
+
+  #[test]
+  fn big_int_results_demote_to_int64() {
+    // Back in range, so ordinary Int64 arithmetic and patterns apply again.
+    assert_eq!(eval("let big = 9223372036854775807 + 10; big - 20"), "i9223372036854775797");
+    assert_eq!(
+      eval("match 9223372036854775807 + 1 - 9223372036854775807 { 1 => true, _ => false }"),
+      "true"
+    );
+    assert_eq!(eval("9223372036854775807 + 1 > 9223372036854775807"), "true");
+    assert_eq!(eval("to_float(9223372036854775807 * 2)"), "f1.8446744073709552e19");
+    assert_eq!(eval("parse_int(\"100000000000000000000\") / 10"), "i10000000000000000000");
+  }
+
+  #[test]
+  fn division_by_zero_is_a_located_error() {
+    let (message, at) = eval_err("let x = 0;\nlet y = 10 / x;");
+    assert_eq!((message.as_str(), at.as_str()), ("Division by zero", "10 / x"));
+    let (message, at) = eval_err("let y = (9223372036854775807 * 2) % 0;");
+    assert_eq!(
+      (message.as_str(), at.as_str()),
+      ("Modulo by zero", "(9223372036854775807 * 2) % 0")
+    );
+  }
+
+  #[test]
+  fn runtime_errors_carry_locations() {
+    let (message, at) = eval_err("let xs = [1, 2];\nxs[2]");
+    assert!(message.starts_with("Index 2 out of bounds"), "{}", message);
+    assert_eq!(at, "xs[2]");
+    let (message, at) = eval_err("1 + true");
+    assert!(message.starts_with("Cannot apply Plus"), "{}", message);
+    assert_eq!(at, "1 + true");
+    let (message, at) = eval_err("len(1, 2)");
+    assert_eq!(message, "len takes 1 arguments but 2 were given");
+    assert_eq!(at, "len(1, 2)");
+    let (message, at) = eval_err("let a = 1;\nnope + a");
+    assert_eq!(message, "Failed to get-bind Ident [nope]");
+    assert_eq!(at, "nope");
+  }
+
+  #[test]
+  fn bitwise_operators() {
+    assert_eq!(eval("12 & 10"), "i8");
+    assert_eq!(eval("12 | 10"), "i14");
+    assert_eq!(eval("12 ^ 10"), "i6");
+    assert_eq!(eval("~0"), "i-1");
+    assert_eq!(eval("1 << 4"), "i16");
+    assert_eq!(eval("-16 >> 2"), "i-4");
+    // Int64 semantics, bits shifted out are lost rather than promoted.
+    assert_eq!(eval("1 << 63"), "i-9223372036854775808");
+    assert_eq!(eval("3 << 63 >> 63"), "i-1");
+  }
+
+  #[test]
+  fn bitwise_operators_use_c_precedence() {
+    assert_eq!(eval("1 | 2 ^ 3 & 6"), "i1");
+    assert_eq!(eval("1 << 2 + 1"), "i8");
+    assert_eq!(eval("1 << 3 > 4 && (6 & 3) == 2"), "true");
+    assert_eq!(eval("let mask = 0; mask = mask | 1 << 5 | 1; mask & ~(1 << 5)"), "i1");
+  }
+
+  #[test]
+  fn shift_amount_must_be_in_range() {
+    let (message, at) = eval_err("let s = 64; 1 << s");
+    assert_eq!(message, "Shift amount 64 is out of range 0..64");
+    assert_eq!(at, "1 << s");
+    let (message, _) = eval_err("1 >> -1");
+    assert_eq!(message, "Shift amount -1 is out of range 0..64");
+  }
+
+  #[test]
+  fn compound_assignment() {
+    assert_eq!(eval("let x = 10; x += 5; x -= 3; x *= 4; x /= 6; x %= 5; x"), "i3");
+    assert_eq!(eval("let s = \"a\"; s += \"b\"; s"), "\"ab\"");
+    assert_eq!(eval("let f = 1.5; f *= 2; f"), "f3.0");
+    assert_eq!(eval("let x = 9223372036854775807; x += 1; x"), "i9223372036854775808");
+  }
+
+  #[test]
+  fn index_assignment() {
+    assert_eq!(eval("let xs = [1, 2, 3]; xs[1] = 5; xs[2] += 10; xs"), "[i1, i5, i13]");
+    assert_eq!(eval("let g = [[0, 0], [0, 0]]; g[1][0] -= 7; g"), "[[i0, i0], [i-7, i0]]");
+    // Lists are values, copies are unaffected.
+    assert_eq!(eval("let xs = [1]; let ys = xs; ys[0] = 2; [xs, ys]"), "[[i1], [i2]]");
+  }
+
+  #[test]
+  fn index_assignment_evaluates_target_once() {
+    let (value, calls) =
+      eval_counting_side_effects("let xs = [1, 2, 3]; xs[side_effect(1)] += 1; xs");
+    assert_eq!((value.as_str(), calls), ("[i1, i3, i3]", 1));
+    let (value, calls) =
+      eval_counting_side_effects("let g = [[1, 2]]; g[side_effect(0)][side_effect(1)] *= 3; g");
+    assert_eq!((value.as_str(), calls), ("[[i1, i6]]", 2));
+  }
+
+  #[test]
+  fn assignment_errors() {
+    let (message, at) = eval_err("let xs = [1, 2];\nxs[2] += 1;");
+    assert!(message.starts_with("Index 2 out of bounds"), "{}", message);
+    assert_eq!(at, "xs[2] += 1");
+    // The list is left untouched when the assignment fails.
+    let mut vm = VM::new();
+    assert!(vm.exec("let xs = [1, [2]]; xs[0][0] = 3;").is_err());
+    assert_eq!(format!("{:?}", vm.exec("xs").unwrap()), "[i1, [i2]]");
+    let (message, at) = eval_err("let xs = [1];\nlen(xs) = 2;");
+    assert_eq!(message, "Invalid assignment target");
+    assert_eq!(at, "len(xs) = 2");
+  }
+
+  #[test]
+  fn functions_are_hoisted() {
+    let source = "
+      let answer = [is_even(10), is_odd(7), is_even(3)];
+      fn is_even(n) { if n == 0 { true } else { is_odd(n - 1) } }
+      fn is_odd(n) { if n == 0 { false } else { is_even(n - 1) } }
+      answer
+    ";
+    assert_eq!(eval(source), "[true, true, false]");
+    // Hoisting is per block, nested functions see their own siblings.
+    let source = "
+      fn outer(n) {
+        let result = ping(n);
+        fn ping(n) { if n == 0 { \"ping\" } else { pong(n - 1) } }
+        fn pong(n) { if n == 0 { \"pong\" } else { ping(n - 1) } }
+        result
+      }
+      [outer(4), outer(5)]
+    ";
+    assert_eq!(eval(source), "[\"ping\", \"pong\"]");
+  }
+
+  fn run_main(source: &str, args: Vec<String>) -> String {
+    let mut vm = VM::new();
+    let value = vm.exec(source).unwrap();
+    format!("{:?}", vm.call_main(value, args).unwrap())
+  }
+
+  #[test]
+  fn main_is_called_with_args() {
+    let source = "
+      let greeting = \"hello\";
+      fn main(args) { [greeting, args[0], args[1]] }
+      fn unused() { 1 }
+    ";
+    let args = vec!["input.txt".to_owned(), "part2".to_owned()];
+    assert_eq!(run_main(source, args), "[\"hello\", \"input.txt\", \"part2\"]");
+    assert_eq!(run_main("fn main() { 3 }", vec!["x".to_owned()]), "i3");
+    // Without a main, the program's value is used.
+    assert_eq!(run_main("1 + 1", vec![]), "i2");
+  }
+
+  /**
+   * Writes the files into a fresh directory for the test, returning the path
+   * of the first one.
+   */
+  fn write_files(test: &str, files: &[(&str, &str)]) -> String {
+    let directory = std::env::temp_dir().join(format!("atc_{}_{}", test, std::process::id()));
+    for (name, source) in files {
+      let path = directory.join(name);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, source).unwrap();
+    }
+    directory.join(files[0].0).display().to_string()
+  }
+
+  #[test]
+  fn modules_are_imported() {
+    let main = write_files(
+      "import",
+      &[
+        (
+          "main.at",
+          "import \"util.at\" as util;\nimport \"lib/grid.at\" as grid;\n\
+           [util::double(21), util::circle(2), grid::size]",
+        ),
+        (
+          "util.at",
+          "enum Shape { Circle(r) }\nfn double(x) { x * 2 }\nfn circle(r) { Shape::Circle(r) }",
+        ),
+        // Imports are relative to the importing file.
+        ("lib/grid.at", "import \"../util.at\" as util;\nlet size = util::double(5);"),
+      ],
+    );
+    let mut vm = VM::new();
+    assert_eq!(
+      format!("{:?}", vm.exec_file(&main).unwrap()),
+      "[i42, Shape::Circle(2), i10]"
+    );
+  }
+
+  #[test]
+  fn modules_execute_once() {
+    let main = write_files(
+      "import_once",
+      &[
+        ("main.at", "import \"a.at\" as a;\nimport \"b.at\" as b;\na::count + b::count"),
+        ("a.at", "import \"counted.at\" as counted;\nlet count = counted::count;"),
+        ("b.at", "import \"counted.at\" as counted;\nlet count = counted::count;"),
+        ("counted.at", "let count = side_effect(1);"),
+      ],
+    );
+    SIDE_EFFECTS.with(|c| c.set(0));
+    let mut vm = VM::new();
+    vm.add_builtin_function("side_effect", |_, args| {
+      SIDE_EFFECTS.with(|c| c.set(c.get() + 1));
+      Ok(args[0].clone())
+    });
+    assert_eq!(format!("{:?}", vm.exec_file(&main).unwrap()), "i2");
+    assert_eq!(SIDE_EFFECTS.with(|c| c.get()), 1);
+  }
+
+  #[test]
+  fn cyclic_imports_are_detected() {
+    let main = write_files(
+      "import_cycle",
+      &[
+        ("main.at", "import \"a.at\" as a;"),
+        ("a.at", "import \"b.at\" as b;"),
+        ("b.at", "\nimport \"a.at\" as a;"),
+      ],
+    );
+    let mut vm = VM::new();
+    let error = vm.exec_file(&main).unwrap_err();
+    assert!(error.message().starts_with("Cyclic import of"), "{}", error);
+    let files = vm.files.borrow();
+    let source_ref = error.source_ref().unwrap();
+    let file = &files[source_ref.file];
+    assert!(file.path.ends_with("b.at"));
+    assert_eq!(source_ref.left, 1);
+  }
+
+  #[test]
+  fn module_errors_name_their_file() {
+    let main = write_files(
+      "import_error",
+      &[
+        ("main.at", "import \"util.at\" as util;\nutil::explode(0)"),
+        ("util.at", "fn explode(x) { 1 / x }"),
+      ],
+    );
+    let mut vm = VM::new();
+    let error = vm.exec_file(&main).unwrap_err();
+    assert_eq!(error.message(), "Division by zero");
+    let files = vm.files.borrow();
+    let source_ref = error.source_ref().unwrap();
+    let file = &files[source_ref.file];
+    assert!(file.path.ends_with("util.at"));
+    assert_eq!(&file.source[source_ref.left as usize..source_ref.right as usize], "1 / x");
+    let (message, _) = eval_err("import \"does_not_exist.at\" as nope;");
+    assert!(message.starts_with("Cannot read"), "{}", message);
+  }
+
+  #[test]
+  fn prelude_helpers() {
+    assert_eq!(eval("[abs(-3), abs(4), abs(-2.5)]"), "[i3, i4, f2.5]");
+    assert_eq!(eval("[min(3, 1), max(3, 1), min(\"b\", \"a\")]"), "[i1, i3, \"a\"]");
+    assert_eq!(eval("sum([1, 2, 3, 4])"), "i10");
+    assert_eq!(eval("sum([])"), "i0");
+    assert_eq!(eval("range(2, 7)"), "[i2, i3, i4, i5, i6]");
+    assert_eq!(eval("range(3, 3)"), "[]");
+    assert_eq!(eval("len(range(0, 10000))"), "i10000");
+    assert_eq!(eval("[gcd(12, 18), gcd(-4, 6), lcm(4, 6), lcm(0, 5)]"), "[i6, i2, i12, i0]");
+    assert_eq!(eval("repeat(\"x\", 3)"), "[\"x\", \"x\", \"x\"]");
+    assert_eq!(eval("len(repeat(0, 1001))"), "i1001");
+    assert_eq!(eval("[1, 2] + [3]"), "[i1, i2, i3]");
+  }
+
+  #[test]
+  fn prelude_can_be_shadowed_or_left_out() {
+    assert_eq!(eval("fn abs(x) { 42 } abs(-1)"), "i42");
+    let without_prelude = || {
+      VM::with_config(VmConfig {
+        prelude: false,
+        ..VmConfig::trusted()
+      })
+    };
+    let error = without_prelude().exec("abs(-1)").unwrap_err();
+    assert_eq!(error.message(), "Failed to get-bind Ident [abs]");
+    // Builtins are still bound without the prelude.
+    let value = without_prelude().exec("len([1])").unwrap();
+    assert_eq!(format!("{:?}", value), "i1");
+  }
+
+  #[test]
+  fn host_calls_and_globals() {
+    let mut vm = VM::new();
+    vm.set_global("offset", 100);
+    vm.exec("fn shift(xs) { map(xs, fn (x) { x + offset }) }").unwrap();
+    let shifted = vm.call("shift", vec![vec![1, 2].into_value()]).unwrap();
+    assert_eq!(Vec::<i64>::from_value(shifted).unwrap(), vec![101, 102]);
+    // Globals can be re-bound by the host between calls.
+    vm.set_global("offset", -1);
+    let shifted = vm.call("shift", vec![vec![1].into_value()]).unwrap();
+    assert_eq!(Vec::<i64>::from_value(shifted).unwrap(), vec![0]);
+    vm.exec("let answer = [\"part1\", 42];").unwrap();
+    let answer = <(String, i64)>::from_value(vm.get_global("answer").unwrap()).unwrap();
+    assert_eq!(answer, ("part1".to_owned(), 42));
+    assert!(vm.get_global("missing").is_none());
+    assert_eq!(vm.call("missing", vec![]).unwrap_err().message(), "No global named missing");
+    // Builtins and the prelude are callable too.
+    let gcd = vm.call("gcd", vec![12.into_value(), 8.into_value()]).unwrap();
+    assert_eq!(i64::from_value(gcd).unwrap(), 4);
+  }
+
+  #[test]
+  fn builtin_closures_capture_host_state() {
+    let seen = Rc::new(RefCell::new(vec![]));
+    let mut vm = VM::new();
+    let captured = Rc::clone(&seen);
+    vm.add_builtin_function("record", move |_, args| {
+      captured.borrow_mut().extend(args);
+      Ok(Value::Unit)
+    });
+    let boxed: Box<BuiltInFn> =
+      Box::new(|vm, args| vm.call_function(args[0].clone(), vec![true.into_value()]));
+    vm.add_builtin_function("with_true", boxed);
+    vm.exec("record(1, \"two\"); with_true(fn (b) { record(b) });").unwrap();
+    assert_eq!(format!("{:?}", seen.borrow()), "[i1, \"two\", true]");
+  }
+
+  /**
+   * Evaluates source that must exhaust one of the limits, returning the error
+   * message and the source text the error points at.
+   */
+  fn exhaust(config: VmConfig, source: &str) -> (String, String) {
+    let error = VM::with_config(config).exec(source).unwrap_err();
+    assert!(matches!(error, RuntimeError::ResourceExhausted(..)), "{:?}", error);
+    let source_ref = error.source_ref().expect("error has no location");
+    let at = &source[source_ref.left as usize..source_ref.right as usize];
+    (error.message().to_owned(), at.to_owned())
+  }
+
+  #[test]
+  fn step_limit() {
+    let config = VmConfig {
+      max_steps: Some(200),
+      ..VmConfig::trusted()
+    };
+    assert_eq!(
+      exhaust(config.clone(), "fn spin(n) { spin(n + 1) } spin(0)").0,
+      "Step limit of 200 exceeded"
+    );
+    // Evaluation within the limit is unaffected, and loading the prelude is free.
+    let max = VM::with_config(config).exec("max(1 + 2 * 3, 4)").unwrap();
+    assert_eq!(format!("{:?}", max), "i7");
+  }
+
+  #[test]
+  fn call_depth_limit() {
+    let config = VmConfig {
+      max_call_depth: Some(10),
+      ..VmConfig::trusted()
+    };
+    assert_eq!(
+      exhaust(config.clone(), "fn down(n) { down(n - 1) }\ndown(0)"),
+      ("Call depth limit of 10 exceeded".to_owned(), "down(n - 1)".to_owned())
+    );
+    // Depth is released as calls return, so many shallow calls are fine.
+    let mut vm = VM::with_config(config);
+    let source = "fn id(x) { x } fn twice(x) { id(id(x)) } len(map(range(0, 100), twice))";
+    assert_eq!(format!("{:?}", vm.exec(source).unwrap()), "i100");
+  }
+
+  #[test]
+  fn allocation_limit() {
+    let config = VmConfig {
+      max_alloc_bytes: Some(1 << 16),
+      ..VmConfig::trusted()
+    };
+    // Lists built by the prelude count too, the error points into the prelude.
+    let error = VM::with_config(config.clone()).exec("len(repeat(0, 100000))").unwrap_err();
+    assert!(matches!(error, RuntimeError::ResourceExhausted(..)));
+    assert_eq!(error.message(), "Allocation limit of 65536 bytes exceeded");
+    let (_, at) = exhaust(config, "fn grow(s) { grow(s + s) } grow(\"x\")");
+    assert_eq!(at, "s + s");
+  }
+
+  #[test]
+  fn allocation_limit_counts_freed_values() {
+    let config = VmConfig {
+      max_alloc_bytes: Some(1 << 16),
+      ..VmConfig::trusted()
+    };
+    // Each iteration makes a 2000 byte str that is dropped before the next, so
+    // no more than one is ever alive.
+    let source = |iterations| {
+      format!(
+        "let chunk = \"{}\";
+         fold(range(0, {}), 0, fn (total, i) {{ total + len(chunk + chunk) }})",
+        "x".repeat(1000),
+        iterations
+      )
+    };
+    let mut vm = VM::with_config(config.clone());
+    assert_eq!(format!("{:?}", vm.exec(&source(10)).unwrap()), "i20000");
+    let (message, at) = exhaust(config, &source(50));
+    assert_eq!(message, "Allocation limit of 65536 bytes exceeded");
+    assert_eq!(at, "chunk + chunk");
+  }
+
+  #[test]
+  fn type_annotations_are_ignored_at_runtime() {
+    assert_eq!(
+      eval("fn add(a: int, b: int) -> int { a + b } let x: str = add(1, 2); x"),
+      "i3"
+    );
+    let source = "let f = fn (xs: [int]) -> fn(int) -> int { fn (i) { xs[i] } }; f([7])(0)";
+    assert_eq!(eval(source), "i7");
   }
 }
@@ -0,0 +1,141 @@
+extern crate serde_json;
+
+use serde_json::{json, Value as Json};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const SOURCE: &str = "fn add(a: int, b: int) -> int {
+  a + b
+}
+
+fn main() {
+  let total = add(1, 2);
+  println(total + missing);
+}
+";
+
+const URI: &str = "file:///sample.at";
+
+fn frame(message: Json) -> String {
+  let body = message.to_string();
+  format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+fn request(id: u64, method: &str, params: Json) -> String {
+  frame(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+}
+
+fn notification(method: &str, params: Json) -> String {
+  frame(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+}
+
+fn at(line: u64, character: u64) -> Json {
+  json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+}
+
+fn range(line: u64, start: u64, end: u64) -> Json {
+  json!({
+    "start": { "line": line, "character": start },
+    "end": { "line": line, "character": end },
+  })
+}
+
+/**
+ * Splits the server's output back into the messages it framed.
+ */
+fn messages(mut output: &str) -> Vec<Json> {
+  let mut messages = vec![];
+  while !output.is_empty() {
+    let (header, rest) = output.split_once("\r\n\r\n").unwrap();
+    let length: usize = header.trim_start_matches("Content-Length: ").parse().unwrap();
+    messages.push(serde_json::from_str(&rest[..length]).unwrap());
+    output = &rest[length..];
+  }
+  messages
+}
+
+/**
+ * Drives `atc lsp` through a whole session: opening a document, querying it,
+ * breaking it, and shutting the server down.
+ */
+#[test]
+fn scripted_session() {
+  let script = [
+    request(1, "initialize", json!({ "capabilities": {} })),
+    notification("initialized", json!({})),
+    notification(
+      "textDocument/didOpen",
+      json!({ "textDocument": { "uri": URI, "languageId": "at", "version": 1, "text": SOURCE } }),
+    ),
+    request(2, "textDocument/definition", at(5, 15)),
+    request(3, "textDocument/references", {
+      let mut params = at(6, 12);
+      params["context"] = json!({ "includeDeclaration": true });
+      params
+    }),
+    request(4, "textDocument/hover", at(5, 14)),
+    request(5, "textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } })),
+    request(6, "textDocument/formatting", json!({ "textDocument": { "uri": URI } })),
+    notification(
+      "textDocument/didChange",
+      json!({
+        "textDocument": { "uri": URI, "version": 2 },
+        "contentChanges": [{ "text": "fn main( {" }],
+      }),
+    ),
+    request(7, "shutdown", json!(null)),
+    notification("exit", json!(null)),
+  ]
+  .concat();
+
+  let mut server = Command::new(env!("CARGO_BIN_EXE_language_lalrpop"))
+    .arg("lsp")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  server.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+  let output = server.wait_with_output().unwrap();
+  assert!(output.status.success());
+  let messages = messages(&String::from_utf8(output.stdout).unwrap());
+  assert_eq!(messages.len(), 9);
+
+  assert_eq!(messages[0]["id"], 1);
+  assert_eq!(messages[0]["result"]["capabilities"]["definitionProvider"], true);
+
+  assert_eq!(messages[1]["method"], "textDocument/publishDiagnostics");
+  assert_eq!(
+    messages[1]["params"]["diagnostics"],
+    json!([{
+      "range": range(6, 18, 25),
+      "severity": 1,
+      "source": "atc",
+      "message": "Unknown name missing",
+    }])
+  );
+
+  assert_eq!(messages[2]["result"], json!({ "uri": URI, "range": range(0, 3, 6) }));
+
+  assert_eq!(
+    messages[3]["result"],
+    json!([{ "uri": URI, "range": range(5, 6, 11) }, { "uri": URI, "range": range(6, 10, 15) }])
+  );
+
+  assert_eq!(
+    messages[4]["result"]["contents"]["value"],
+    "```\nfn add(a: int, b: int) -> int\n```"
+  );
+
+  let symbols = messages[5]["result"].as_array().unwrap();
+  let names: Vec<&Json> = symbols.iter().map(|symbol| &symbol["name"]).collect();
+  assert_eq!(names, vec!["add", "main"]);
+  assert_eq!(symbols[1]["selectionRange"], range(4, 3, 7));
+
+  assert_eq!(messages[6]["error"]["code"], -32601);
+
+  let diagnostics = messages[7]["params"]["diagnostics"].as_array().unwrap();
+  assert_eq!(diagnostics.len(), 1);
+  assert!(diagnostics[0]["message"].as_str().unwrap().starts_with("Unexpected"));
+
+  assert_eq!(messages[8], json!({ "jsonrpc": "2.0", "id": 7, "result": null }));
+}